@@ -0,0 +1,30 @@
+//! Records the commit `cis-gh-reactor` is built from, which its `version` command reports. The
+//! `GIT_SHA` environment variable takes precedence, for builds outside of a git checkout (like
+//! the Nix package).
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    if Path::new(".git").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+    let sha = std::env::var("GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        });
+    if let Some(sha) = sha {
+        println!("cargo:rustc-env=CI_SCRIPT_GIT_SHA={sha}");
+    }
+}
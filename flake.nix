@@ -24,6 +24,8 @@
           name = "ci-script";
           src = ./.;
           cargoLock.lockFile = ./Cargo.lock;
+          # Reported by the bot's `version` command, there's no git checkout to read it from
+          GIT_SHA = self.rev or "";
         };
       in
       with pkgs;
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+use super::retry;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Error talking to Github: {0}")]
+    Github(#[from] octocrab::Error),
+    #[error("Failed to create Github App token: {0}")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("Given name is not a valid Github repo name (`owner/repo`): {0}")]
+    InvalidRepoName(String),
+    #[error("Github App installation for {0} has no access tokens URL")]
+    NoAccessTokensUrl(String),
+    #[error("Github App has no installations; is it installed on the target org?")]
+    NoInstallations,
+    #[error("Failed to start runtime for Github requests: {0}")]
+    Runtime(#[from] std::io::Error),
+}
+
+/// User agent of the Github API clients, unless configured otherwise
+pub const DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Source of the tokens used to talk to Github (through the API and over git), so the bot can run
+/// as a Github App or with a personal access token, or get its tokens from elsewhere.
+pub trait AuthProvider: std::fmt::Debug + Send + Sync {
+    /// Token with access to the Github repository `repo` (`owner/name`)
+    fn token(&self, repo: &str) -> Result<String, Error>;
+
+    /// URL of the Github API the tokens are for, e.g. `https://api.github.com/`
+    fn api_url(&self) -> &url::Url;
+
+    /// User agent the API clients identify themselves to Github with
+    fn user_agent(&self) -> &str {
+        DEFAULT_USER_AGENT
+    }
+
+    /// Github API client authenticated with a token for `repo` (`owner/name`)
+    fn client(&self, repo: &str) -> Result<octocrab::Octocrab, Error> {
+        Ok(octocrab::OctocrabBuilder::new()
+            .base_url(self.api_url().clone())?
+            .add_header(reqwest::header::USER_AGENT, self.user_agent().into())
+            .personal_token(self.token(repo)?)
+            .build()?)
+    }
+
+    /// Github API client without credentials, which can only read public repositories
+    fn anonymous_client(&self) -> Result<octocrab::Octocrab, Error> {
+        Ok(octocrab::OctocrabBuilder::new()
+            .base_url(self.api_url().clone())?
+            .add_header(reqwest::header::USER_AGENT, self.user_agent().into())
+            .build()?)
+    }
+}
+
+/// How long before they expire cached installation tokens are replaced, so the tokens `AppAuth`
+/// hands out stay valid for the git operation or API request they're used for
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(10 * 60);
+
+/// Authenticates as the installation of a Github App on the repository, with tokens that only
+/// have access to that repository. They're cached per repository until shortly before they
+/// expire.
+#[derive(Clone)]
+pub struct AppAuth {
+    app_id: octocrab::models::AppId,
+    key: jsonwebtoken::EncodingKey,
+    api_url: url::Url,
+    user_agent: String,
+    /// Installation tokens and when they expire by repository (`owner/name`), shared by clones
+    tokens: Arc<Mutex<HashMap<String, (String, SystemTime)>>>,
+}
+
+impl std::fmt::Debug for AppAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AppAuth")
+            .field("app_id", &self.app_id)
+            .field("api_url", &self.api_url)
+            .field("user_agent", &self.user_agent)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AppAuth {
+    /// `key` is the PEM encoded private key of the app. The user agent defaults to
+    /// `DEFAULT_USER_AGENT` with the app ID, so the app's requests can be told apart.
+    pub fn new(app_id: u64, key: &str, api_url: url::Url) -> Result<Self, Error> {
+        Ok(AppAuth {
+            app_id: app_id.into(),
+            key: jsonwebtoken::EncodingKey::from_rsa_pem(key.as_bytes())?,
+            api_url,
+            user_agent: format!("{DEFAULT_USER_AGENT} (Github App {app_id})"),
+            tokens: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn with_user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Github API client authenticated as the app itself, e.g. to list its installations. A new
+    /// one is needed every few minutes, since the JWT it uses expires after 10 minutes.
+    pub fn app_client(&self) -> Result<octocrab::Octocrab, Error> {
+        Ok(octocrab::OctocrabBuilder::new()
+            .base_url(self.api_url.clone())?
+            .add_header(reqwest::header::USER_AGENT, self.user_agent.clone())
+            .personal_token(octocrab::auth::create_jwt(self.app_id, &self.key)?)
+            .build()?)
+    }
+
+    /// The installations of the app (the first page of them), failing if there are none, like for
+    /// a new app that isn't installed anywhere yet, since it can't get any tokens then. (A key of
+    /// another app fails earlier, Github rejects its requests with a 401.)
+    pub fn installations(&self) -> Result<Vec<octocrab::models::Installation>, Error> {
+        let client = self.app_client()?;
+        let url = client.absolute_url("app/installations")?;
+        let installations: octocrab::Page<octocrab::models::Installation> =
+            block_on(retry::send(true, || client._get(url.clone(), None::<&()>)))??;
+        if installations.items.is_empty() {
+            return Err(Error::NoInstallations);
+        }
+        Ok(installations.items)
+    }
+}
+
+impl AuthProvider for AppAuth {
+    fn token(&self, repo: &str) -> Result<String, Error> {
+        let (owner, name) = repo
+            .split_once('/')
+            .ok_or_else(|| Error::InvalidRepoName(repo.into()))?;
+        let cached = self
+            .tokens
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(repo)
+            .filter(|(_, expires_at)| {
+                expires_at
+                    .duration_since(SystemTime::now())
+                    .is_ok_and(|left| left > TOKEN_EXPIRY_MARGIN)
+            })
+            .map(|(token, _)| token.clone());
+        if let Some(token) = cached {
+            return Ok(token);
+        }
+        let client = self.app_client()?;
+        let installation_url = client.absolute_url(format!("repos/{owner}/{name}/installation"))?;
+        block_on(async {
+            let installation: octocrab::models::Installation =
+                retry::send(true, || client._get(installation_url.clone(), None::<&()>)).await?;
+            let access_tokens_url = installation
+                .access_tokens_url
+                .ok_or_else(|| Error::NoAccessTokensUrl(repo.into()))?;
+            let access_tokens_url = client.absolute_url(access_tokens_url)?;
+            // Scripts working with other repositories of the installation (like cloning them)
+            // get tokens for those
+            let params = serde_json::json!({ "repositories": [name] });
+            // Safe to retry, an extra token just expires unused
+            let access: octocrab::models::InstallationToken = retry::send(true, || {
+                client._post(access_tokens_url.clone(), Some(&params))
+            })
+            .await?;
+            // Tokens without a (valid) expiry are used once
+            let expires_at = access
+                .expires_at
+                .as_deref()
+                .and_then(|expires_at| humantime::parse_rfc3339(expires_at).ok());
+            if let Some(expires_at) = expires_at {
+                self.tokens
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .insert(repo.into(), (access.token.clone(), expires_at));
+            }
+            Ok(access.token)
+        })?
+    }
+
+    fn api_url(&self) -> &url::Url {
+        &self.api_url
+    }
+
+    fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+}
+
+/// Authenticates with a fixed (personal access) token
+#[derive(Clone)]
+pub struct PatAuth {
+    token: String,
+    api_url: url::Url,
+    user_agent: String,
+}
+
+impl std::fmt::Debug for PatAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PatAuth")
+            .field("api_url", &self.api_url)
+            .field("user_agent", &self.user_agent)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PatAuth {
+    pub fn new<T: Into<String>>(token: T, api_url: url::Url) -> Self {
+        PatAuth {
+            token: token.into(),
+            api_url,
+            user_agent: DEFAULT_USER_AGENT.into(),
+        }
+    }
+
+    pub fn with_user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+}
+
+impl AuthProvider for PatAuth {
+    fn token(&self, _repo: &str) -> Result<String, Error> {
+        Ok(self.token.clone())
+    }
+
+    fn api_url(&self) -> &url::Url {
+        &self.api_url
+    }
+
+    fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+}
+
+/// Run a Github API request to completion from synchronous code.
+///
+/// Octocrab needs a tokio runtime, but scripts run both on tokio threads (in `cis`), where a
+/// nested runtime can't be started, and outside of any runtime (in the webhook reactor). So the
+/// request runs on a thread of its own with a fresh runtime.
+pub(crate) fn block_on<F>(future: F) -> Result<F::Output, Error>
+where
+    F: std::future::Future + Send,
+    F::Output: Send,
+{
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?;
+                Ok(rt.block_on(future))
+            })
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
@@ -1,30 +1,239 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Default for `Options::max_output`
+pub const DEFAULT_MAX_OUTPUT: usize = 1024 * 1024;
+
+/// Replaces the middle of output that exceeds `Options::max_output`
+const TRUNCATED_MARKER: &str = "\n...(truncated)...\n";
+
+/// How often a running cargo checks whether it was cancelled
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Environment variables of the bot that cargo keeps, all others are cleared so the bot's secrets
+/// don't reach build scripts. They're what's needed to find cargo, and for rustup's proxy to find
+/// its toolchains.
+const KEPT_ENV: &[&str] = &["PATH", "HOME", "CARGO_HOME", "RUSTUP_HOME"];
+
+/// Which cargo to run, the same for all of a job's cargo commands
+#[derive(Clone, Debug, Default)]
+pub struct Cargo {
+    /// Path of the cargo binary, `cargo` is looked up in `PATH` without it
+    pub path: Option<PathBuf>,
+    /// Rustup toolchain to run, passed as `+<toolchain>` before the arguments. Needs `path` (or
+    /// the `cargo` in `PATH`) to be rustup's proxy.
+    pub toolchain: Option<String>,
+}
 
 pub struct Run {
     args: Vec<String>,
     dir: PathBuf,
+    options: Options,
+    cargo: Cargo,
+    /// See `Run::cancel`
+    cancelled: Vec<Arc<AtomicBool>>,
+    /// `CARGO_TARGET_DIR`, cargo's default (`target` of the workspace) without it
+    target_dir: Option<PathBuf>,
+}
+
+/// Common cargo flags, given to the `cargo` syntax as an object map like
+/// `#{ features: ["a", "b"], profile: "bench" }`
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// `--features`, from an array or a comma separated string
+    pub features: Vec<String>,
+    /// `--all-features`
+    pub all_features: bool,
+    /// `--no-default-features`
+    pub no_default_features: bool,
+    /// `--release`
+    pub release: bool,
+    /// `--profile`
+    pub profile: Option<String>,
+    /// `--package`
+    pub package: Option<String>,
+    /// Not a cargo flag: maximum number of bytes of stdout and stderr (each) to keep, the middle
+    /// of longer output is dropped. `None` means `DEFAULT_MAX_OUTPUT`.
+    pub max_output: Option<usize>,
+}
+
+impl Options {
+    pub fn from_map(map: rhai::Map) -> Result<Self, Box<rhai::EvalAltResult>> {
+        fn invalid(key: &str, expected: &str, value: &rhai::Dynamic) -> Box<rhai::EvalAltResult> {
+            format!(
+                "`cargo` option `{key}` must be {expected}, not {}",
+                value.type_name()
+            )
+            .into()
+        }
+        fn string(key: &str, value: rhai::Dynamic) -> Result<String, Box<rhai::EvalAltResult>> {
+            value
+                .clone()
+                .into_string()
+                .map_err(|_| invalid(key, "a string", &value))
+        }
+        fn bool(key: &str, value: rhai::Dynamic) -> Result<bool, Box<rhai::EvalAltResult>> {
+            value
+                .as_bool()
+                .map_err(|_| invalid(key, "a bool", &value))
+        }
+
+        let mut options = Options::default();
+        for (key, value) in map {
+            match key.as_str() {
+                "features" if value.is::<rhai::Array>() => {
+                    for feature in value.cast::<rhai::Array>() {
+                        options.features.push(string("features", feature)?);
+                    }
+                }
+                "features" => {
+                    let features = string("features", value)?;
+                    options.features.extend(
+                        features
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|feature| !feature.is_empty())
+                            .map(String::from),
+                    );
+                }
+                "all_features" => options.all_features = bool("all_features", value)?,
+                "no_default_features" => {
+                    options.no_default_features = bool("no_default_features", value)?
+                }
+                "release" => options.release = bool("release", value)?,
+                "profile" => options.profile = Some(string("profile", value)?),
+                "package" => options.package = Some(string("package", value)?),
+                "max_output" => {
+                    let max_output = value
+                        .as_int()
+                        .ok()
+                        .and_then(|max_output| usize::try_from(max_output).ok())
+                        .ok_or_else(|| invalid("max_output", "a positive integer", &value))?;
+                    options.max_output = Some(max_output);
+                }
+                key => return Err(format!("Unknown `cargo` option `{key}`").into()),
+            }
+        }
+        Ok(options)
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if !self.features.is_empty() {
+            args.push("--features".into());
+            args.push(self.features.join(","));
+        }
+        if self.all_features {
+            args.push("--all-features".into());
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".into());
+        }
+        if self.release {
+            args.push("--release".into());
+        }
+        if let Some(profile) = &self.profile {
+            args.push("--profile".into());
+            args.push(profile.clone());
+        }
+        if let Some(package) = &self.package {
+            args.push("--package".into());
+            args.push(package.clone());
+        }
+        args
+    }
 }
 
 impl Run {
     pub fn new<S: ToString, A: AsRef<[S]>, P: AsRef<Path>>(args: A, dir: P) -> Self {
         let args = args.as_ref().iter().map(|arg| arg.to_string()).collect();
         let dir = dir.as_ref().into();
-        Run { args, dir }
+        Run {
+            args,
+            dir,
+            options: Options::default(),
+            cargo: Cargo::default(),
+            cancelled: vec![],
+            target_dir: None,
+        }
+    }
+
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn cargo(mut self, cargo: Cargo) -> Self {
+        self.cargo = cargo;
+        self
+    }
+
+    /// Kill cargo, and everything it started, as soon as `cancelled` is set. Cargo is killed once
+    /// any of the flags given this way is set.
+    pub fn cancel(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.cancelled.push(cancelled);
+        self
     }
 
     pub fn run(self) -> CargoResult {
-        log::info!("Running cargo in {:?} with args {:?}", self.dir, self.args);
-        match std::process::Command::new("cargo")
-            .env_clear()
+        // The flags go right after the subcommand, so they come before any `--` separating the
+        // arguments for the compiled binary
+        let mut args = self.args;
+        let flags_at = args.len().min(1);
+        args.splice(flags_at..flags_at, self.options.args());
+        if let Some(toolchain) = &self.cargo.toolchain {
+            args.insert(0, format!("+{toolchain}"));
+        }
+        let program = self.cargo.path.unwrap_or_else(|| "cargo".into());
+        log::info!("Running {:?} in {:?} with args {:?}", program, self.dir, args);
+        let max_output = self.options.max_output.unwrap_or(DEFAULT_MAX_OUTPUT);
+        let mut command = std::process::Command::new(program);
+        command.env_clear();
+        for var in KEPT_ENV {
+            if let Some(value) = std::env::var_os(var) {
+                command.env(var, value);
+            }
+        }
+        if let Some(target_dir) = &self.target_dir {
+            command.env("CARGO_TARGET_DIR", target_dir);
+        }
+        command
+            .current_dir(&self.dir)
             .stdin(std::process::Stdio::null())
-            .args(self.args)
-            .output()
-        {
-            Ok(output) => CargoResult {
-                exit_code: output.status.code(),
-                stderr: String::from_utf8_lossy(&output.stderr).to_string(),
-                stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-            },
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .args(args);
+        // In a process group of its own, so cancelling also kills the build scripts and
+        // benchmarks cargo runs
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let cancelled = self.cancelled;
+        let result = command.spawn().and_then(|child| {
+            let finished = Arc::new(AtomicBool::new(false));
+            if !cancelled.is_empty() {
+                let (finished, pid) = (finished.clone(), child.id());
+                std::thread::spawn(move || {
+                    while !finished.load(Ordering::SeqCst) {
+                        if cancelled.iter().any(|cancelled| cancelled.load(Ordering::SeqCst)) {
+                            log::info!("Cancelling cargo");
+                            kill_process_group(pid);
+                            return;
+                        }
+                        std::thread::sleep(CANCEL_POLL_INTERVAL);
+                    }
+                });
+            }
+            let result = Self::capture(child, max_output);
+            finished.store(true, Ordering::SeqCst);
+            result
+        });
+        match result {
+            Ok(result) => result,
             Err(e) => CargoResult {
                 exit_code: Some(-1),
                 stdout: "".into(),
@@ -32,9 +241,129 @@ impl Run {
             },
         }
     }
+
+    // Reads stdout and stderr while cargo runs, so only `max_output` bytes of each are kept in
+    // memory however much cargo prints
+    fn capture(mut child: std::process::Child, max_output: usize) -> std::io::Result<CargoResult> {
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let stderr = std::thread::spawn(move || match stderr {
+            Some(stderr) => capture(stderr, max_output),
+            None => Ok(String::new()),
+        });
+        let stdout = match stdout {
+            Some(stdout) => capture(stdout, max_output)?,
+            None => String::new(),
+        };
+        let stderr = stderr
+            .join()
+            .map_err(|_| std::io::Error::other("stderr reader panicked"))??;
+        Ok(CargoResult {
+            exit_code: child.wait()?.code(),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// Run cargo for each of the `runs`, at most `max` at the same time, and return the results with
+/// how long each took, in the order of `runs`. Every run captures its own output.
+///
+/// Cargo runs sharing a target directory wait for each other's lock on it, so when more than one
+/// run at a time, each of the `max` slots builds in `target/parallel-<slot>` of the run's
+/// directory instead. The runs of a slot reuse its builds.
+pub fn run_parallel(runs: Vec<Run>, max: usize) -> Vec<(Duration, CargoResult)> {
+    let count = runs.len();
+    let slots = max.min(count);
+    let queue = Mutex::new(runs.into_iter().enumerate());
+    let results = Mutex::new(vec![None; count]);
+    std::thread::scope(|scope| {
+        for slot in 0..slots {
+            let (queue, results) = (&queue, &results);
+            scope.spawn(move || loop {
+                let next = queue.lock().unwrap_or_else(PoisonError::into_inner).next();
+                let (index, mut run) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                if slots > 1 {
+                    run.target_dir = Some(run.dir.join("target").join(format!("parallel-{slot}")));
+                }
+                let started = Instant::now();
+                let result = run.run();
+                results.lock().unwrap_or_else(PoisonError::into_inner)[index] =
+                    Some((started.elapsed(), result));
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner)
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Kill the process group led by `pid` (see `Run::run`)
+#[cfg(unix)]
+fn kill_process_group(pid: u32) {
+    match libc::pid_t::try_from(pid) {
+        // SAFETY: `kill` doesn't touch any memory
+        Ok(pid) if unsafe { libc::kill(-pid, libc::SIGKILL) } == 0 => {}
+        _ => log::warn!("Failed to kill cargo: {}", std::io::Error::last_os_error()),
+    }
 }
 
-#[derive(Clone, Debug)]
+#[cfg(not(unix))]
+fn kill_process_group(_pid: u32) {
+    log::warn!("Cancelling cargo is not supported on this platform");
+}
+
+/// Read `reader` to the end, keeping the first and last `max / 2` bytes
+fn capture<R: Read>(mut reader: R, max: usize) -> std::io::Result<String> {
+    let tail_max = max / 2;
+    let head_max = max - tail_max;
+    let mut head = Vec::new();
+    let mut tail = VecDeque::new();
+    let mut truncated = false;
+    let mut buf = [0; 8192];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let (to_head, to_tail) = buf[..n].split_at((head_max - head.len()).min(n));
+        head.extend_from_slice(to_head);
+        tail.extend(to_tail);
+        if tail.len() > tail_max {
+            tail.drain(..tail.len() - tail_max);
+            truncated = true;
+        }
+    }
+    let mut output = String::from_utf8_lossy(&head).into_owned();
+    if truncated {
+        output.push_str(TRUNCATED_MARKER);
+    }
+    output.push_str(&String::from_utf8_lossy(tail.make_contiguous()));
+    Ok(output)
+}
+
+/// The last `lines` lines of `output`, e.g. to only comment the end of a long build log
+pub fn tail(output: &str, lines: rhai::INT) -> String {
+    let lines = usize::try_from(lines).unwrap_or(0);
+    let mut start = output.len();
+    for _ in 0..lines {
+        match output[..start].trim_end_matches('\n').rfind('\n') {
+            Some(newline) => start = newline,
+            None => return output.to_string(),
+        }
+    }
+    output[start..].trim_start_matches('\n').to_string()
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
 pub struct CargoResult {
     pub exit_code: Option<i32>, // remove `pub` after mocking
     pub stdout: String,
@@ -57,3 +386,44 @@ impl CargoResult {
         self.stdout.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn parallel_runs_use_a_target_dir_per_slot() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        // Stands in for cargo, printing the target directory it was given
+        let fake_cargo = dir.path().join("cargo");
+        std::fs::write(&fake_cargo, "#!/bin/sh\nprintf %s \"$CARGO_TARGET_DIR\"\n").unwrap();
+        std::fs::set_permissions(&fake_cargo, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let cargo = Cargo {
+            path: Some(fake_cargo),
+            toolchain: None,
+        };
+        let runs = |count| -> Vec<Run> {
+            (0..count)
+                .map(|_| Run::new(["build"], dir.path()).cargo(cargo.clone()))
+                .collect()
+        };
+
+        // Which slot takes which run depends on timing
+        let slot_dirs: Vec<_> = ["parallel-0", "parallel-1"]
+            .iter()
+            .map(|slot| dir.path().join("target").join(slot).display().to_string())
+            .collect();
+        let results = run_parallel(runs(4), 2);
+        assert_eq!(results.len(), 4);
+        for (_, result) in results {
+            assert!(slot_dirs.contains(&result.stdout), "{:?}", result);
+        }
+
+        // One at a time, the runs keep cargo's default
+        let results = run_parallel(runs(2), 1);
+        assert!(results.iter().all(|(_, r)| r.stdout.is_empty()), "{:?}", results);
+    }
+}
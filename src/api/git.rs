@@ -1,11 +1,14 @@
 use git2::build::{CheckoutBuilder, RepoBuilder};
 use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use thiserror::Error;
 
+use super::github::{self, Github};
+use crate::semaphore::Semaphore;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Failed to checkout repository because path {0} exists but is not a directory")]
@@ -19,6 +22,8 @@ pub enum Error {
     ExclusiveLock,
     #[error("File or directory not found")]
     NotFound,
+    #[error("Path {0} leads outside the repository")]
+    PathOutsideRepository(PathBuf),
     #[error("Failed to read or write file")]
     FileIO {
         #[from]
@@ -26,32 +31,460 @@ pub enum Error {
     },
     #[error("Unexpected status entry encountered for path {0}")]
     UnexpectedStatusEntry(PathBuf),
-    #[error("Failed to retrieve Github access token: {0}")]
-    NoAccessToken(String),
-    #[error("Failed to receive access token through channel: {source}")]
-    ChannelRecvFailure {
-        #[from]
-        source: std::sync::mpsc::RecvError,
-    },
-    #[error("Error talking to Github: {source}")]
-    GithubApiError {
-        #[from]
-        source: octocrab::Error,
-    },
-    #[error("Given name is not a valid Github repo name (`owner/repo`)")]
-    InvalidGithubRepoName,
+    #[error("{0}")]
+    Github(#[from] github::Error),
     #[error("Current branch name contains invalid UTF-8")]
     CurrentBranchInvalidUTF8,
     #[error("Remote URL contains invalid UTF-8")]
     RemoteInvalidUTF8,
+    #[error("No Github user triggered this job")]
+    NoTriggerUser,
+    #[error("Local branch `{0}` does not exist")]
+    BranchNotFound(String),
+    #[error("Git only reads `{0}` from the global config, it can't be set for the repository")]
+    GlobalConfigKey(String),
+    #[error("Failed to sign commit: {0}")]
+    Sign(String),
+    #[error("Failed to list the cargo workspace members: {0}")]
+    CargoMetadata(String),
+    #[error("Invalid branch name `{0}`")]
+    InvalidBranchName(String),
+    #[error("Invalid commit message template: {0}")]
+    CommitTemplate(String),
+    #[error("The branch of the pull request is unknown")]
+    NoPullRequestHead,
+    #[error("The author of the pull request doesn't allow maintainers to push to its branch")]
+    MaintainerCantModify,
+    #[error(
+        "There's no store for this repository, only the job's own repository has one (if the \
+         bot has a store directory)"
+    )]
+    NoStore,
+    #[error("{0}")]
+    Store(#[from] crate::store::Error),
+    #[error(
+        "There's no data directory, only the job's own repository can read it (if the bot has \
+         a data directory)"
+    )]
+    NoDataDir,
+    #[error("{0}")]
+    Data(#[from] crate::data::Error),
+    #[error("`{0}` isn't allowed, the script is read-only (see `--read-only-scripts`)")]
+    ReadOnly(&'static str),
+    #[error("{operation} failed: {}", .source.message())]
+    Git {
+        /// What was being done, like `Push of refs/heads/x to origin`
+        operation: String,
+        source: git2::Error,
+    },
+    #[error("Push of {refname} to {remote} was rejected: {message}")]
+    PushRejected {
+        refname: String,
+        remote: String,
+        /// Why the remote rejected it, like `non-fast-forward`
+        message: String,
+    },
+    #[error(
+        "Push of {refspec} to {remote} failed ({kind} error, {attempts} attempts): {}",
+        .source.message()
+    )]
+    PushFailed {
+        refspec: String,
+        remote: String,
+        kind: PushFailure,
+        attempts: u32,
+        source: git2::Error,
+    },
+    #[error(
+        "Push of {refname} to {remote} reported success, but the remote has it at {} instead \
+         of {expected}",
+        .actual.as_deref().unwrap_or("nothing")
+    )]
+    PushNotVerified {
+        refname: String,
+        remote: String,
+        expected: git2::Oid,
+        actual: Option<String>,
+    },
+}
+
+/// Why a push failed, see `Error::PushFailed`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushFailure {
+    /// The remote didn't accept the access token
+    Authentication,
+    /// The remote couldn't be reached, or the connection broke
+    Network,
+    /// Anything else, like a missing local ref
+    Git,
+}
+
+impl PushFailure {
+    fn of(e: &git2::Error) -> Self {
+        match (e.code(), e.class()) {
+            (git2::ErrorCode::Auth, _) => PushFailure::Authentication,
+            (
+                _,
+                git2::ErrorClass::Net
+                | git2::ErrorClass::Http
+                | git2::ErrorClass::Ssl
+                | git2::ErrorClass::Os,
+            ) => PushFailure::Network,
+            _ => PushFailure::Git,
+        }
+    }
+
+    /// Whether trying again (with a new token) could help
+    fn is_transient(self) -> bool {
+        self != PushFailure::Git
+    }
+}
+
+impl std::fmt::Display for PushFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PushFailure::Authentication => "authentication",
+            PushFailure::Network => "network",
+            PushFailure::Git => "git",
+        })
+    }
+}
+
+/// How often a push is attempted before its `PushFailure` is reported
+const PUSH_ATTEMPTS: u32 = 3;
+
+/// Wait before the second attempt of a push, doubled for every later one
+const PUSH_RETRY_WAIT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Adds the operation that failed to git errors, which by themselves don't say what was being
+/// done, see `Error::Git`
+trait Context<T> {
+    fn context<F: FnOnce() -> String>(self, operation: F) -> Result<T, Error>;
+}
+
+impl<T> Context<T> for Result<T, git2::Error> {
+    fn context<F: FnOnce() -> String>(self, operation: F) -> Result<T, Error> {
+        self.map_err(|source| Error::Git {
+            operation: operation(),
+            source,
+        })
+    }
+}
+
+/// Push `refspec` of `repo` to the remote `remote` returns (called `remote_name` in errors) with
+/// a Github access token from `access_token`. Unlike `git2::Remote::push`, this fails when the
+/// remote rejects the update (e.g. when it's not a fast-forward) as well. Authentication and
+/// network failures are tried again, with a new token, up to `PUSH_ATTEMPTS` times in all. `repo`
+/// is only locked during attempts, not while waiting between them.
+fn push_refspec<R, T>(
+    repo: &Mutex<git2::Repository>,
+    remote: R,
+    remote_name: &str,
+    refspec: &str,
+    access_token: T,
+) -> Result<(), Error>
+where
+    R: for<'r> Fn(&'r git2::Repository) -> Result<git2::Remote<'r>, Error>,
+    T: Fn() -> Result<String, Error>,
+{
+    let mut wait = PUSH_RETRY_WAIT;
+    for attempt in 1.. {
+        let res = {
+            let repo = repo.lock()?;
+            let mut remote = remote(&repo)?;
+            push_refspec_once(&mut remote, remote_name, refspec, access_token()?)
+        };
+        match res {
+            Err(Error::PushFailed {
+                kind, source, ..
+            }) if kind.is_transient() && attempt < PUSH_ATTEMPTS => {
+                log::warn!(
+                    "Push of {refspec} to {remote_name} failed ({kind} error), trying again in \
+                     {wait:?}: {}",
+                    source.message()
+                );
+                std::thread::sleep(wait);
+                wait *= 2;
+            }
+            Err(Error::PushFailed {
+                refspec,
+                remote,
+                kind,
+                source,
+                ..
+            }) => {
+                return Err(Error::PushFailed {
+                    refspec,
+                    remote,
+                    kind,
+                    attempts: attempt,
+                    source,
+                })
+            }
+            res => return res,
+        }
+    }
+    unreachable!("pushes are attempted until they don't fail with a transient error")
+}
+
+/// Check that `remote` (called `remote_name` in errors) has `refname` at `expected`, e.g. after
+/// pushing it. Pushes can report success without the remote ref being updated, e.g. when a
+/// server-side hook or a proxy swallows the update.
+fn verify_push(
+    remote: &mut git2::Remote,
+    remote_name: &str,
+    refname: &str,
+    expected: git2::Oid,
+    access_token: String,
+) -> Result<(), Error> {
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |_url, _username_from_url, _allowed_types| {
+        git2::Cred::userpass_plaintext("x-access-token", &access_token)
+    });
+    let context = || format!("Verifying the push of {refname} to {remote_name}");
+    let connection = remote
+        .connect_auth(git2::Direction::Fetch, Some(callbacks), None)
+        .context(context)?;
+    let actual = connection
+        .list()
+        .context(context)?
+        .iter()
+        .find(|head| head.name() == refname)
+        .map(|head| head.oid());
+    if actual == Some(expected) {
+        return Ok(());
+    }
+    Err(Error::PushNotVerified {
+        refname: refname.into(),
+        remote: remote_name.into(),
+        expected,
+        actual: actual.map(|oid| oid.to_string()),
+    })
+}
+
+fn push_refspec_once(
+    remote: &mut git2::Remote,
+    remote_name: &str,
+    refspec: &str,
+    access_token: String,
+) -> Result<(), Error> {
+    let rejected = std::cell::RefCell::new(None);
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |_url, _username_from_url, _allowed_types| {
+        git2::Cred::userpass_plaintext("x-access-token", &access_token)
+    });
+    callbacks.push_update_reference(|refname, status| {
+        if let Some(message) = status {
+            *rejected.borrow_mut() = Some((refname.to_string(), message.to_string()));
+        }
+        Ok(())
+    });
+    let mut push_options = git2::PushOptions::new();
+    push_options.remote_callbacks(callbacks);
+    if let Err(source) = remote.push(&[refspec], Some(&mut push_options)) {
+        return Err(Error::PushFailed {
+            refspec: refspec.to_string(),
+            remote: remote_name.to_string(),
+            kind: PushFailure::of(&source),
+            attempts: 1,
+            source,
+        });
+    }
+    drop(push_options);
+    match rejected.into_inner() {
+        Some((refname, message)) => Err(Error::PushRejected {
+            refname,
+            remote: remote_name.to_string(),
+            message,
+        }),
+        None => Ok(()),
+    }
 }
 
+/// Maximum number of bytes of `cargo metadata` output that `LocalRepo::workspace_members` reads
+const CARGO_METADATA_MAX_OUTPUT: usize = 64 * 1024 * 1024;
+
+/// Config keys git ignores in a repository's own config, since a cloned repository could use them
+/// to attack whoever works in it
+const GLOBAL_CONFIG_KEYS: &[&str] = &[
+    "safe.directory",
+    "safe.bareRepository",
+    "uploadpack.packObjectsHook",
+];
+
 impl From<std::sync::PoisonError<std::sync::MutexGuard<'_, git2::Repository>>> for Error {
     fn from(_: std::sync::PoisonError<std::sync::MutexGuard<'_, git2::Repository>>) -> Self {
         Self::ExclusiveLock
     }
 }
 
+/// Initialize and update all submodules of `repo` to the commits recorded in its tree, optionally
+/// also descending into submodules of submodules.
+pub(crate) fn update_submodules(repo: &git2::Repository, recursive: bool) -> Result<(), git2::Error> {
+    for mut submodule in repo.submodules()? {
+        log::info!(
+            "Updating submodule {}",
+            submodule.name().unwrap_or("<non UTF-8 name>")
+        );
+        submodule.sync()?;
+        submodule.update(true, None)?;
+        if recursive {
+            update_submodules(&submodule.open()?, true)?;
+        }
+    }
+    Ok(())
+}
+
+/// Percentage steps at which `fetch_options` logs the transfer progress
+const PROGRESS_STEP: usize = 10;
+
+/// Fetch options that log the transfer progress of a clone or fetch, prefixed with `what`, at
+/// debug level, so large clones don't look stuck
+pub(crate) fn fetch_options(what: String) -> git2::FetchOptions<'static> {
+    let mut next_percent = 0;
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.transfer_progress(move |progress| {
+        let (received, total) = (progress.received_objects(), progress.total_objects());
+        match (received * 100).checked_div(total) {
+            Some(percent) if percent >= next_percent => {
+                log::debug!(
+                    "{what}: received {received}/{total} objects ({percent}%, {} KiB)",
+                    progress.received_bytes() / 1024
+                );
+                next_percent = percent - percent % PROGRESS_STEP + PROGRESS_STEP;
+            }
+            _ => {}
+        }
+        true
+    });
+    let mut fetch_options = git2::FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks);
+    fetch_options
+}
+
+/// A remote ref to fetch and the local ref to fetch it into, for the ways refs are named:
+/// - fully qualified refs (`refs/heads/main`, `refs/tags/v1.0`, `refs/pull/1/head`)
+/// - branches (`heads/main`), tags (`tags/v1.0`) and pull request refs (`pull/1/head`,
+///   `pull/1/merge`) without `refs/`
+/// - anything else is a branch name (`main`, `feature/x`)
+///
+/// Branches and tags are fetched into the same ref locally, other refs (like those of pull
+/// requests) into a local branch of the same name, e.g. `refs/heads/pull/1/head`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FetchRef {
+    pub remote: String,
+    pub local: String,
+}
+
+impl FetchRef {
+    pub fn new(name: &str) -> Self {
+        let remote = if name.starts_with("refs/") {
+            name.to_string()
+        } else if ["heads/", "tags/", "pull/"].iter().any(|prefix| name.starts_with(prefix)) {
+            format!("refs/{name}")
+        } else {
+            format!("refs/heads/{name}")
+        };
+        let local = if remote.starts_with("refs/heads/") || remote.starts_with("refs/tags/") {
+            remote.clone()
+        } else {
+            format!("refs/heads/{}", &remote["refs/".len()..])
+        };
+        FetchRef { remote, local }
+    }
+
+    pub fn refspec(&self) -> String {
+        format!("{}:{}", self.remote, self.local)
+    }
+}
+
+/// Template of the messages of `LocalRepo::commit_templated`, configured on the host so the bot's
+/// commits follow the same conventions (like prefixes or trailers) in every repository. `{name}`
+/// is replaced by the value of the placeholder `name`, `{{` and `}}` are literal braces.
+#[derive(Clone, Debug)]
+pub struct CommitTemplate {
+    pub template: String,
+    /// The job's values of placeholders, like `command` and `issue`, which scripts can't override
+    pub values: std::collections::BTreeMap<String, String>,
+}
+
+/// Template of `commit_templated` without a configured one
+const DEFAULT_COMMIT_TEMPLATE: &str = "{message}";
+
+/// `template` with its placeholders replaced by their `values`, see `CommitTemplate`
+fn render_template(
+    template: &str,
+    values: &std::collections::BTreeMap<String, String>,
+) -> Result<String, Error> {
+    let mut message = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        message.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+            message.push_str(&rest[..1]);
+            rest = escaped;
+            continue;
+        }
+        let end = rest
+            .find('}')
+            .filter(|_| rest.starts_with('{'))
+            .ok_or_else(|| Error::CommitTemplate("unmatched brace, use `{{` or `}}`".into()))?;
+        let name = &rest[1..end];
+        let value = values
+            .get(name)
+            .ok_or_else(|| Error::CommitTemplate(format!("no value for `{{{name}}}`")))?;
+        message.push_str(value);
+        rest = &rest[end + 1..];
+    }
+    message.push_str(rest);
+    Ok(message)
+}
+
+/// Signs the commits scripts create with a GPG key, so they pass branch protections requiring
+/// verified commits. Like git, this runs `gpg`, which finds the key in the keyring of its
+/// `GNUPGHOME`.
+#[derive(Clone, Debug)]
+pub struct GpgSigner {
+    /// Key id, fingerprint or user id of the secret key, as understood by `gpg --local-user`
+    pub key: String,
+    /// File holding the passphrase of the key, if it's protected by one
+    pub passphrase_file: Option<PathBuf>,
+}
+
+impl GpgSigner {
+    /// Armored detached signature of the commit buffer `commit`
+    fn sign(&self, commit: &str) -> Result<String, Error> {
+        let mut gpg = std::process::Command::new("gpg");
+        gpg.args(["--batch", "--no-tty", "--armor", "--detach-sign", "--local-user"])
+            .arg(&self.key);
+        if let Some(passphrase_file) = &self.passphrase_file {
+            gpg.args(["--pinentry-mode", "loopback", "--passphrase-file"])
+                .arg(passphrase_file);
+        }
+        let mut child = gpg
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::Sign(format!("failed to run gpg: {e}")))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(commit.as_bytes())
+                .map_err(|e| Error::Sign(format!("failed to write to gpg: {e}")))?;
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|e| Error::Sign(format!("failed to run gpg: {e}")))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(Error::Sign(format!("gpg {}: {}", output.status, stderr.trim())));
+        }
+        String::from_utf8(output.stdout)
+            .map_err(|_| Error::Sign("gpg returned an invalid signature".into()))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Git {
     /// Path to the repository owning the script
@@ -60,7 +493,22 @@ pub struct Git {
     pub(crate) path: std::path::PathBuf,
     /// Root containing the repositories
     pub(crate) root: std::path::PathBuf,
-    pub(crate) github_client: Arc<Mutex<octocrab::Octocrab>>,
+    /// Limits concurrent clones, see `job::CheckoutOptions::clone_limit`
+    pub(crate) clone_limit: Option<Arc<Semaphore>>,
+    pub(crate) github: Arc<dyn Github>,
+    /// Github user that triggered the job, passed on to the repositories we clone
+    pub(crate) trigger_user: Option<octocrab::models::User>,
+    /// Signs commits in the repositories we clone, if set
+    pub(crate) signer: Option<Arc<GpgSigner>>,
+    /// Cargo to run in the repositories we clone
+    pub(crate) cargo: super::cargo::Cargo,
+    /// Makes the repositories we clone read-only too, see `LocalRepo::read_only`
+    pub(crate) read_only: bool,
+    /// Used by `commit_templated` in the repositories we clone as well
+    pub(crate) commit_template: Option<CommitTemplate>,
+    /// The job's own repository (`owner/name`), the only one `fetch_file` reads with the bot's
+    /// credentials
+    pub(crate) repo: String,
     //pub(crate) tokio_handle: tokio::runtime::Handle,
 }
 
@@ -72,7 +520,7 @@ impl Git {
         repo: String,
         head: S,
     ) -> Result<LocalRepo, Box<rhai::EvalAltResult>> {
-        let url = format!("https://github.com/{}", repo);
+        let url = format!("{}{}", self.github_url(), repo);
         let (repo_owner, repo_name) = repo.split_at(
             repo.find('/')
                 .ok_or(format!("Invalid Github Repository name (`owner/repo`)"))?,
@@ -80,6 +528,9 @@ impl Git {
         let mut repo_name = String::from(repo_name);
         repo_name.remove(0); // Remove the '/'
         let dir = self.repo_dir(&url);
+        // Held until the requested head is fetched and checked out as well
+        let _permit = self.clone_limit.as_ref().map(|limit| limit.acquire());
+        let started = std::time::Instant::now();
         let repo = match std::fs::metadata(&dir) {
             Ok(metadata) if metadata.is_dir() => {
                 git2::Repository::open(&dir).map_err(|e| format!("{e}"))?
@@ -90,6 +541,7 @@ impl Git {
                 checkout.remove_untracked(true).remove_ignored(true).force();
                 log::info!("Cloning {} to {:?}", &url, &dir);
                 RepoBuilder::new()
+                    .fetch_options(fetch_options(format!("Cloning {url}")))
                     .with_checkout(checkout)
                     .clone(url.as_ref(), &dir)
                     .map_err(|e| format!("{e}"))?
@@ -101,18 +553,38 @@ impl Git {
             }
         };
 
-        let repo = LocalRepo::with_repo(
+        let mut repo = LocalRepo::with_repo(
             dir,
             repo_owner,
             repo_name,
             head.as_ref(),
             repo,
-            self.github_client.clone(),
+            self.github.clone(),
         )?;
-        log::info!("Constructed local repo {:?}", repo.dir);
+        repo.trigger_user = self.trigger_user.clone();
+        repo.signer = self.signer.clone();
+        repo.cargo = self.cargo.clone();
+        repo.read_only = self.read_only;
+        repo.commit_template = self.commit_template.clone();
+        log::info!("Constructed local repo {:?} in {:?}", repo.dir, started.elapsed());
         Ok(repo)
     }
 
+    /// Read the file at `path` in `repo` (`owner/name`) at `git_ref` through the Github API.
+    /// Other repositories than the job's own are read without credentials, so scripts can't read
+    /// private files of every repository the bot has access to.
+    pub fn fetch_file(
+        &mut self,
+        repo: String,
+        git_ref: String,
+        path: String,
+    ) -> Result<rhai::Blob, Box<rhai::EvalAltResult>> {
+        let authenticated = repo.eq_ignore_ascii_case(&self.repo);
+        self.github
+            .file_contents(&repo, &git_ref, &path, authenticated)
+            .map_err(|e| format!("{e}").into())
+    }
+
     fn repo_dir<U: std::fmt::Display>(&self, url: U) -> PathBuf {
         log::info!("repos_root: {:?}", &self.root);
         let full_path = PathBuf::from(&self.root);
@@ -122,6 +594,10 @@ impl Git {
         log::debug!("full_path: {:?}", full_path);
         full_path
     }
+
+    fn github_url(&self) -> url::Url {
+        self.github.web_url()
+    }
 }
 
 #[derive(Clone)]
@@ -129,9 +605,33 @@ pub struct LocalRepo {
     dir: PathBuf,
     repo: Arc<Mutex<git2::Repository>>,
     config: Option<Config>,
-    github_client: Arc<Mutex<octocrab::Octocrab>>,
+    github: Arc<dyn Github>,
     github_owner: String,
     github_name: String,
+    /// Cached result of the Github API lookup in `default_branch`
+    default_branch: Arc<Mutex<Option<String>>>,
+    /// Github user that triggered the job (e.g. by commenting on an issue), if any
+    trigger_user: Option<octocrab::models::User>,
+    /// Web URL of the Github instance hosting the repository (e.g. `https://github.com/`)
+    github_url: url::Url,
+    /// Signs the commits made by `commit`, which are unsigned if this isn't set
+    signer: Option<Arc<GpgSigner>>,
+    /// Branch of the pull request the job runs for, see `push_to_pr`
+    pr_head: Option<crate::job::PullRequestHead>,
+    /// Values kept between jobs, only set for the job's own repository
+    store: Option<crate::store::Store>,
+    /// Files of the host the script can read with `data::read`
+    data_dir: Option<crate::data::DataDir>,
+    /// Cargo to run in the repository, by the script's `cargo` commands as well
+    cargo: super::cargo::Cargo,
+    /// Whether the script may only look at the repository, see
+    /// `job::CheckoutOptions::read_only_scripts`
+    read_only: bool,
+    /// Number of the issue or pull request the job runs for, only set for the job's own
+    /// repository. Part of the names of `unique_branch`.
+    issue_number: Option<i64>,
+    /// Template of `commit_templated`, `DEFAULT_COMMIT_TEMPLATE` if not set
+    commit_template: Option<CommitTemplate>,
     //tokio_handle: tokio::runtime::Handle,
 }
 
@@ -154,19 +654,105 @@ impl LocalRepo {
         repo_owner: O,
         repo_name: N,
         repo: git2::Repository,
-        github: Arc<Mutex<octocrab::Octocrab>>,
+        github: Arc<dyn Github>,
     ) -> LocalRepo {
+        let github_url = github.web_url();
         LocalRepo {
             dir: PathBuf::from(dir.as_ref()),
             repo: Arc::new(Mutex::new(repo)),
             config: None,
             github_owner: String::from(repo_owner.as_ref()),
             github_name: String::from(repo_name.as_ref()),
-            github_client: github,
+            github,
+            default_branch: Arc::new(Mutex::new(None)),
+            trigger_user: None,
+            github_url,
+            signer: None,
+            pr_head: None,
+            store: None,
+            data_dir: None,
+            cargo: Default::default(),
+            read_only: false,
+            issue_number: None,
+            commit_template: None,
             //tokio_handle,
         }
     }
 
+    pub(crate) fn set_trigger_user(&mut self, user: Option<octocrab::models::User>) {
+        self.trigger_user = user;
+    }
+
+    pub(crate) fn set_signer(&mut self, signer: Option<Arc<GpgSigner>>) {
+        self.signer = signer;
+    }
+
+    pub(crate) fn set_pr_head(&mut self, pr_head: Option<crate::job::PullRequestHead>) {
+        self.pr_head = pr_head;
+    }
+
+    pub(crate) fn set_store(&mut self, store: Option<crate::store::Store>) {
+        self.store = store;
+    }
+
+    pub(crate) fn set_data_dir(&mut self, data_dir: Option<crate::data::DataDir>) {
+        self.data_dir = data_dir;
+    }
+
+    pub(crate) fn set_cargo(&mut self, cargo: super::cargo::Cargo) {
+        self.cargo = cargo;
+    }
+
+    pub(crate) fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub(crate) fn set_issue_number(&mut self, issue_number: Option<i64>) {
+        self.issue_number = issue_number;
+    }
+
+    pub(crate) fn set_commit_template(&mut self, commit_template: Option<CommitTemplate>) {
+        self.commit_template = commit_template;
+    }
+
+    /// Fails for read-only scripts, called first by everything that changes the repository (its
+    /// files, index, refs or config) or its remote
+    fn check_writable(&self, operation: &'static str) -> Result<(), Box<rhai::EvalAltResult>> {
+        if self.read_only {
+            return Err(format!("{}", Error::ReadOnly(operation)).into());
+        }
+        Ok(())
+    }
+
+    fn store(&self) -> Result<&crate::store::Store, Error> {
+        self.store.as_ref().ok_or(Error::NoStore)
+    }
+
+    /// The value stored under `key` by an earlier job of the repository, `()` if there is none
+    pub fn store_get(&mut self, key: &str) -> Result<rhai::Dynamic, Box<rhai::EvalAltResult>> {
+        let value = self
+            .store()
+            .and_then(|store| Ok(store.get(key)?))
+            .map_err(|e| format!("{e}"))?;
+        Ok(value.map_or(rhai::Dynamic::UNIT, Into::into))
+    }
+
+    /// Store `value` under `key` for later jobs of the repository
+    pub fn store_put(&mut self, key: &str, value: &str) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.store()
+            .and_then(|store| Ok(store.put(key, value)?))
+            .map_err(|e| format!("{e}").into())
+    }
+
+    /// Contents of the file at `path` in the host's data directory
+    pub fn data_read(&mut self, path: &str) -> Result<rhai::Blob, Box<rhai::EvalAltResult>> {
+        self.data_dir
+            .as_ref()
+            .ok_or(Error::NoDataDir)
+            .and_then(|data_dir| Ok(data_dir.read(path)?))
+            .map_err(|e| format!("{e}").into())
+    }
+
     //fn with_repo<P: AsRef<Path>, S: AsRef<str>, R: AsRef<str>>(dir: P, repo_name: R, head: S, repo: git2::Repository, github_client: Arc<Mutex<octocrab::Octocrab>>, tokio_handle: tokio::runtime::Handle) -> Result<LocalRepo, Box<rhai::EvalAltResult>>
     fn with_repo<P: AsRef<Path>, S: AsRef<str>, O: AsRef<str>, N: AsRef<str>>(
         dir: P,
@@ -174,15 +760,27 @@ impl LocalRepo {
         repo_name: N,
         head: S,
         repo: git2::Repository,
-        github_client: Arc<Mutex<octocrab::Octocrab>>,
+        github: Arc<dyn Github>,
     ) -> Result<LocalRepo, Box<rhai::EvalAltResult>> {
+        let github_url = github.web_url();
         let mut s = LocalRepo {
             dir: PathBuf::from(dir.as_ref()),
             repo: Arc::new(Mutex::new(repo)),
             config: None,
-            github_client,
+            github,
             github_owner: String::from(repo_owner.as_ref()),
             github_name: String::from(repo_name.as_ref()),
+            default_branch: Arc::new(Mutex::new(None)),
+            trigger_user: None,
+            github_url,
+            signer: None,
+            pr_head: None,
+            store: None,
+            data_dir: None,
+            cargo: Default::default(),
+            read_only: false,
+            issue_number: None,
+            commit_template: None,
             //tokio_handle,
         };
         s.checkout_remote_head(head.as_ref())
@@ -198,21 +796,13 @@ impl LocalRepo {
         head: impl Into<String>,
         base: impl Into<String>,
     ) -> Result<(), Error> {
-        let token = self.get_access_token()?;
-        let gh_client = octocrab::OctocrabBuilder::new()
-            .personal_token(token)
-            .build()?;
-        futures_lite::future::block_on(async {
-            let owner = self.github_owner.clone();
-            let name = self.github_name.clone();
-            gh_client
-                .pulls(owner, name)
-                .create(title, head, base)
-                .body(body)
-                .send()
-                .await
-        })?;
-        Ok(())
+        let pr = github::PullRequest {
+            title: title.into(),
+            body: body.into(),
+            head: head.into(),
+            base: base.into(),
+        };
+        Ok(self.github.create_pr(&self.full_name(), &pr)?)
     }
 
     pub fn pub_create_pr(
@@ -222,20 +812,51 @@ impl LocalRepo {
         head: String,
         base: String,
     ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("create_pr")?;
         self.create_pr(title, body, head, base)
             .map_err(|e| format!("{e}").into())
     }
 
+    /// The default branch of the Github repository (e.g. `main`), which is usually what
+    /// `create_pr` should use as `base`. The result is cached for the lifetime of the `LocalRepo`.
+    fn default_branch(&self) -> Result<String, Error> {
+        let mut cached = self
+            .default_branch
+            .lock()
+            .map_err(|_| Error::ExclusiveLock)?;
+        if let Some(branch) = cached.as_ref() {
+            return Ok(branch.clone());
+        }
+
+        let branch = self.github.default_branch(&self.full_name())?;
+        *cached = Some(branch.clone());
+        Ok(branch)
+    }
+
+    pub fn pub_default_branch(&mut self) -> Result<String, Box<rhai::EvalAltResult>> {
+        self.default_branch().map_err(|e| format!("{e}").into())
+    }
+
     // fetch and checkout/reset remote head (branch)
     fn checkout_remote_head<S: AsRef<str>>(&mut self, head: S) -> Result<(), Error> {
         let head = head.as_ref();
         let repo = self.repo.lock()?;
         log::info!("Fetching {} in {:?}", head, self.dir);
         //self.repo.lock()?.find_remote("origin")?.fetch(
-        let mut remote = repo.find_remote("origin")?;
-        remote.fetch(&[&format!("refs/{}:refs/heads/{}", head, head)], None, None)?;
-
-        let rev = repo.revparse_single(head)?;
+        let fetch_ref = FetchRef::new(head);
+        let refspec = fetch_ref.refspec();
+        let fetch = || format!("Fetch of {refspec} from origin");
+        let mut remote = repo.find_remote("origin").context(fetch)?;
+        remote
+            .fetch(
+                &[&refspec],
+                Some(&mut fetch_options(format!("Fetching {head}"))),
+                None,
+            )
+            .context(fetch)?;
+
+        let reset = || format!("Reset to {}", fetch_ref.local);
+        let rev = repo.revparse_single(&fetch_ref.local).context(reset)?;
         repo.reset(
             &rev,
             git2::ResetType::Hard,
@@ -245,12 +866,35 @@ impl LocalRepo {
                     .remove_ignored(true)
                     .force(),
             ),
-        )?;
+        )
+        .context(reset)?;
+
+        Ok(())
+    }
 
+    fn update_submodules(&self, recursive: bool) -> Result<(), Error> {
+        let repo = self.repo.lock()?;
+        update_submodules(&repo, recursive)?;
         Ok(())
     }
 
-    // Checkout a possibly new local branch
+    pub fn pub_update_submodules(&mut self) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("update_submodules")?;
+        self.update_submodules(false)
+            .map_err(|e| format!("{e}").into())
+    }
+
+    pub fn pub_update_submodules_recursive(
+        &mut self,
+        recursive: bool,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("update_submodules")?;
+        self.update_submodules(recursive)
+            .map_err(|e| format!("{e}").into())
+    }
+
+    /// Create the local branch `name` at HEAD, without checking it out. Fails if the branch
+    /// already exists, see `ensure_branch` for a variant that doesn't.
     pub fn checkout_new_branch<S: AsRef<str>>(&mut self, name: S) -> Result<(), Error> {
         self.checkout_new_branch_target(name, "HEAD")
     }
@@ -305,6 +949,7 @@ impl LocalRepo {
         path: P,
         contents: rhai::Blob,
     ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("write")?;
         let path = path.as_ref();
         if path
             .components()
@@ -326,6 +971,33 @@ impl LocalRepo {
         Ok(std::fs::write(path, contents).map_err(|e| format!("{e}"))?)
     }
 
+    /// Like `read_file`, but for text files: the contents as a string, or an error saying where
+    /// the file isn't valid UTF-8
+    pub fn read_text<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<String, Box<rhai::EvalAltResult>> {
+        let path = path.as_ref();
+        let bytes = self.read_file(path)?;
+        String::from_utf8(bytes).map_err(|e| {
+            let offset = e.utf8_error().valid_up_to();
+            format!(
+                "File {} is not valid UTF-8 (at byte {offset}), use `read` for binary files",
+                path.display()
+            )
+            .into()
+        })
+    }
+
+    /// Like `write_file`, but for text files
+    pub fn write_text<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        contents: &str,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.write_file(path, contents.as_bytes().to_vec())
+    }
+
     fn get_full_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf, Box<rhai::EvalAltResult>> {
         match self
             .normalize_path(self.dir.join(&path))
@@ -347,12 +1019,12 @@ impl LocalRepo {
     pub fn ls_files_in_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<rhai::Dynamic, Box<rhai::EvalAltResult>> {
         let dir = dir.as_ref();
         let path = self.get_full_path(dir)?;
+        let root_relative = self.root_relative();
         Ok(walkdir::WalkDir::new(path).into_iter().filter_map(|e| e.ok())
             .filter_map(|e| {
                 let metadata = e.metadata().ok()?;
-                let path = e.into_path().to_path_buf();
-                let path = path.strip_prefix(self.get_full_path("./").ok()?).ok()?;
-                Some(DirEntry { metadata, path:  DirEntryPath(path.to_path_buf())})
+                let path = root_relative(e.path())?;
+                Some(DirEntry { metadata, path })
             })
             .filter(|e| e.metadata.is_file()).collect::<Vec<_>>().into())
     }
@@ -371,17 +1043,15 @@ impl LocalRepo {
     ) -> Result<rhai::Dynamic, Box<rhai::EvalAltResult>> {
         let path = self.get_full_path(dir)?;
         log::debug!("More specifically, listing files in {:?}", path);
+        let root_relative = self.root_relative();
         Ok(std::fs::read_dir(path)
             .map_err(|e| format!("{e}"))?
             .filter_map(|entry| {
                 match entry {
                     Ok(entry) => {
                         let metadata = entry.metadata().ok()?;
-                        let path = entry.path().strip_prefix(&self.dir).ok()?.to_path_buf();
-                        Some(DirEntry {
-                            metadata,
-                            path: DirEntryPath(path),
-                        })
+                        let path = root_relative(&entry.path())?;
+                        Some(DirEntry { metadata, path })
                         //DirEntry::try_from(entry).ok()
                     }
                     Err(_) => None,
@@ -391,8 +1061,93 @@ impl LocalRepo {
             .into())
     }
 
+    /// Turns paths inside the repository into `DirEntryPath`s relative to its root, whether they
+    /// start with the repository's directory as given or canonicalized (like the paths
+    /// `get_full_path` returns)
+    fn root_relative(&self) -> impl Fn(&Path) -> Option<DirEntryPath> {
+        let dir = self.dir.clone();
+        let canonical_dir = self.dir.canonicalize().ok();
+        move |path| {
+            let relative = path
+                .strip_prefix(&dir)
+                .ok()
+                .or_else(|| path.strip_prefix(canonical_dir.as_ref()?).ok())?;
+            Some(DirEntryPath(relative.to_path_buf()))
+        }
+    }
+
+    /// Every member of the Cargo workspace that `dir` is in, as `#{ name, path, manifest_path }`
+    /// maps with the paths relative to the repository root, like `cargo metadata` lists them.
+    /// Members outside the repository are skipped.
+    fn workspace_members<P: AsRef<Path>>(&self, dir: P) -> Result<rhai::Array, Error> {
+        #[derive(serde::Deserialize)]
+        struct Metadata {
+            packages: Vec<Package>,
+        }
+        #[derive(serde::Deserialize)]
+        struct Package {
+            name: String,
+            manifest_path: PathBuf,
+        }
+
+        let dir = self.normalize_path(dir)?;
+        let options = super::cargo::Options {
+            // Truncated JSON can't be parsed, and the metadata of large workspaces is big
+            max_output: Some(CARGO_METADATA_MAX_OUTPUT),
+            ..Default::default()
+        };
+        let mut result = super::cargo::Run::new(
+            ["metadata", "--no-deps", "--format-version", "1"],
+            &dir,
+        )
+        .options(options)
+        .cargo(self.cargo.clone())
+        .run();
+        if !result.is_ok() {
+            return Err(Error::CargoMetadata(result.stderr.trim().into()));
+        }
+        let metadata: Metadata = serde_json::from_str(&result.stdout)
+            .map_err(|e| Error::CargoMetadata(e.to_string()))?;
+        let root_relative = self.root_relative();
+        let mut members = vec![];
+        for package in metadata.packages {
+            let manifest_path = match root_relative(&package.manifest_path) {
+                Some(manifest_path) => manifest_path,
+                None => continue,
+            };
+            let path = DirEntryPath(
+                manifest_path
+                    .0
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default(),
+            );
+            let mut member = rhai::Map::new();
+            member.insert("name".into(), package.name.into());
+            member.insert("path".into(), rhai::Dynamic::from(path));
+            member.insert("manifest_path".into(), rhai::Dynamic::from(manifest_path));
+            members.push(member.into());
+        }
+        Ok(members)
+    }
+
+    pub fn pub_workspace_members<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+    ) -> Result<rhai::Array, Box<rhai::EvalAltResult>> {
+        self.workspace_members(dir)
+            .map_err(|e| format!("{e}").into())
+    }
+
+    /// `workspace_members` of the workspace at the repository root
+    pub fn pub_workspace_members_root(&mut self) -> Result<rhai::Array, Box<rhai::EvalAltResult>> {
+        self.pub_workspace_members(".")
+    }
+
     pub fn add<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<rhai::EvalAltResult>> {
-        let path = path.as_ref();
+        self.check_writable("add")?;
+        let path = self.relative_path(path).map_err(|e| format!("{e}"))?;
+        let path = path.as_path();
         log::debug!("Adding file {:?}", path);
         let repo = self.repo.lock().map_err(|e| format!("{e}"))?;
         let mut index = repo.index().map_err(|e| format!("{e}"))?;
@@ -418,137 +1173,424 @@ impl LocalRepo {
         }
     }
 
-    fn commit<S: AsRef<str>>(&mut self, message: S) -> Result<(), Error> {
+    /// Unstage `path` (a file or directory) like `git reset HEAD -- <path>`: its index entries go
+    /// back to how they are in `HEAD`, or are removed if it's not in `HEAD`. The working tree is
+    /// left alone.
+    pub fn reset_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("reset_path")?;
+        let path = self.relative_path(path).map_err(|e| format!("{e}"))?;
+        log::debug!("Unstaging {:?}", path);
+        let unstage = || format!("Unstaging {}", path.display());
+        let repo = self.repo.lock().map_err(|e| format!("{e}"))?;
+        let head = match repo.head().and_then(|head| head.peel(git2::ObjectType::Commit)) {
+            Ok(head) => Some(head),
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => None,
+            Err(source) => {
+                let operation = unstage();
+                return Err(format!("{}", Error::Git { operation, source }).into());
+            }
+        };
+        repo.reset_default(head.as_ref(), std::iter::once(path.as_path()))
+            .context(unstage)
+            .map_err(|e| format!("{e}"))?;
+        Ok(())
+    }
+
+    /// Set `key` to `value` in the repository's own config (`.git/config`), like
+    /// `git config --local <key> <value>`. The global and system config are left alone.
+    fn set_config_value(&self, key: &str, value: &str) -> Result<(), Error> {
+        if GLOBAL_CONFIG_KEYS
+            .iter()
+            .any(|global| global.eq_ignore_ascii_case(key))
+        {
+            return Err(Error::GlobalConfigKey(key.into()));
+        }
+        log::debug!("Setting git config {} to {:?}", key, value);
         let repo = self.repo.lock()?;
+        repo.config()?
+            .open_level(git2::ConfigLevel::Local)?
+            .set_str(key, value)?;
+        Ok(())
+    }
+
+    pub fn set_config<K: AsRef<str>, V: AsRef<str>>(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("set_config")?;
+        self.set_config_value(key.as_ref(), value.as_ref())
+            .map_err(|e| format!("{e}").into())
+    }
+
+    /// `path` relative to the repository root, for paths that don't have to exist (yet). Like
+    /// `write_file`, no `..` is allowed, and existing paths must not lead outside the repository.
+    fn relative_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf, Error> {
+        let path = path.as_ref();
+        let full_path = self.dir.join(path);
+        if full_path.exists() {
+            self.normalize_path(&full_path)?;
+        }
+        let mut relative = PathBuf::new();
+        for component in full_path.strip_prefix(&self.dir).unwrap_or(path).components() {
+            match component {
+                std::path::Component::Normal(component) => relative.push(component),
+                std::path::Component::CurDir => {}
+                _ => return Err(Error::PathOutsideRepository(path.into())),
+            }
+        }
+        Ok(relative)
+    }
+
+    /// Whether git ignores `path`, i.e. `add` would skip it
+    fn ignored<P: AsRef<Path>>(&self, path: P) -> Result<bool, Error> {
+        let path = self.relative_path(path)?;
+        Ok(self.repo.lock()?.status_should_ignore(&path)?)
+    }
+
+    pub fn is_ignored<P: AsRef<Path>>(&mut self, path: P) -> Result<bool, Box<rhai::EvalAltResult>> {
+        self.ignored(path).map_err(|e| format!("{e}").into())
+    }
+
+    /// Whether `path` is in the index, or for a directory, whether any file in it is
+    fn tracked<P: AsRef<Path>>(&self, path: P) -> Result<bool, Error> {
+        let path = self.relative_path(path)?;
+        let index = self.repo.lock()?.index()?;
+        Ok(index.get_path(&path, 0).is_some()
+            || index.iter().any(|entry| {
+                std::str::from_utf8(&entry.path)
+                    .map(|entry_path| Path::new(entry_path).starts_with(&path))
+                    .unwrap_or(false)
+            }))
+    }
+
+    pub fn is_tracked<P: AsRef<Path>>(&mut self, path: P) -> Result<bool, Box<rhai::EvalAltResult>> {
+        self.tracked(path).map_err(|e| format!("{e}").into())
+    }
+
+    fn signature(&self) -> Result<git2::Signature<'static>, Error> {
         let signature = match &self.config {
             Some(Config { name, email }) => git2::Signature::now(name, email)?,
             None => git2::Signature::now("ci-script (TODO: Changeme)", "changeme@parity.io")?,
         };
-        let rev = repo.revparse_single("HEAD")?;
-        let commit = rev.peel_to_commit()?;
-        let mut index = repo.index()?;
-        let oid = index.write_tree()?;
-        let tree = repo.find_tree(oid)?;
-        repo.commit(
-            Some("HEAD"),
-            &signature,
-            &signature,
-            message.as_ref(),
-            &tree,
-            &[&commit],
-        )?;
+        Ok(signature)
+    }
+
+    fn commit<S: AsRef<str>>(&mut self, message: S) -> Result<(), Error> {
+        let repo = self.repo.lock()?;
+        let signature = self.signature()?;
+        let operation = || "Commit".to_string();
+        let commit = repo
+            .revparse_single("HEAD")
+            .and_then(|rev| rev.peel_to_commit())
+            .context(|| "Commit (resolving HEAD)".to_string())?;
+        let tree = repo
+            .index()
+            .and_then(|mut index| index.write_tree())
+            .and_then(|oid| repo.find_tree(oid))
+            .context(|| "Commit (writing the index)".to_string())?;
+        let signer = match &self.signer {
+            Some(signer) => signer,
+            None => {
+                repo.commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    message.as_ref(),
+                    &tree,
+                    &[&commit],
+                )
+                .context(operation)?;
+                return Ok(());
+            }
+        };
+        let buffer = repo
+            .commit_create_buffer(&signature, &signature, message.as_ref(), &tree, &[&commit])
+            .context(operation)?;
+        let buffer = buffer
+            .as_str()
+            .ok_or_else(|| Error::Sign("commit contains invalid UTF-8".into()))?;
+        let gpg_signature = signer.sign(buffer)?;
+        let oid = repo
+            .commit_signed(buffer, &gpg_signature, None)
+            .context(operation)?;
+        // Unlike `commit`, `commit_signed` doesn't move the branch, which `head` resolves to (or
+        // `HEAD` itself when it's detached)
+        let summary = message.as_ref().lines().next().unwrap_or_default();
+        repo.head()
+            .and_then(|mut head| head.set_target(oid, &format!("commit: {summary}")))
+            .context(|| "Commit (updating HEAD)".to_string())?;
+        Ok(())
+    }
+
+    /// Name and (Github noreply) email address of the user that triggered the job
+    fn trigger_identity(&self) -> Result<Config, Error> {
+        let user = self.trigger_user.as_ref().ok_or(Error::NoTriggerUser)?;
+        Ok(Config {
+            name: user.login.clone(),
+            email: format!(
+                "{}+{}@users.noreply.{}",
+                user.id,
+                user.login,
+                self.github_url.host_str().unwrap_or("github.com")
+            ),
+        })
+    }
+
+    /// Attribute subsequent commits to the user that triggered the job instead of the bot
+    pub fn set_author_from_issue(&mut self) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.config = Some(self.trigger_identity().map_err(|e| format!("{e}"))?);
         Ok(())
     }
 
+    /// A `Co-authored-by:` trailer crediting the user that triggered the job, to be appended to
+    /// a commit message
+    pub fn co_authored_by(&mut self) -> Result<String, Box<rhai::EvalAltResult>> {
+        let Config { name, email } = self.trigger_identity().map_err(|e| format!("{e}"))?;
+        Ok(format!("Co-authored-by: {name} <{email}>"))
+    }
+
     pub fn pub_commit<S: AsRef<str>>(
         &mut self,
         message: S,
     ) -> Result<(), Box<rhai::EvalAltResult>> {
-        self.commit(message).map_err(|e| format!("{e}").into())
+        self.check_writable("commit")?;
+        let started = std::time::Instant::now();
+        self.commit(message).map_err(|e| format!("{e}"))?;
+        log::info!("Committed in {:?} in {:?}", self.dir, started.elapsed());
+        Ok(())
+    }
+
+    /// Commit with a message from the host's `CommitTemplate`, filled in with `fields` (like
+    /// `message`), the job's values and `sha`, the commit the changes were made on
+    pub fn commit_templated(&mut self, fields: rhai::Map) -> Result<(), Error> {
+        let mut values: std::collections::BTreeMap<_, _> = fields
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let template = match &self.commit_template {
+            Some(CommitTemplate {
+                template,
+                values: job_values,
+            }) => {
+                values.extend(job_values.clone());
+                template.clone()
+            }
+            None => DEFAULT_COMMIT_TEMPLATE.to_string(),
+        };
+        let (sha, _) = self.head_commit_info()?;
+        values.insert("sha".into(), sha);
+        let message = render_template(&template, &values)?;
+        self.commit(message)
     }
 
-    pub fn list_modified(&self) -> Result<Vec<PathBuf>, Box<rhai::EvalAltResult>> {
+    pub fn pub_commit_templated(
+        &mut self,
+        fields: rhai::Map,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("commit_templated")?;
+        self.commit_templated(fields).map_err(|e| format!("{e}").into())
+    }
+
+    /// Paths of all files that differ from `HEAD`, staged or not, including untracked files
+    pub fn list_modified(&mut self) -> Result<rhai::Dynamic, Box<rhai::EvalAltResult>> {
         let repo = self.repo.lock().map_err(|e| format!("{e}"))?;
         let list = repo
-            .statuses(Some(
-                git2::StatusOptions::default().include_unmodified(false),
-            ))
+            .statuses(Some(&mut status_options()))
             .map_err(|e| format!("{e}"))?
             .iter()
-            .filter_map(|entry| entry.path().map(PathBuf::from))
-            .collect();
-        Ok(list)
-    }
-
-    fn get_access_token(&self) -> Result<String, Error> {
-        let github_client = self.github_client.clone();
-        futures_lite::future::block_on(async {
-            let github_client = github_client.lock().map_err(|_| Error::ExclusiveLock)?;
-            let installations = github_client
-                .apps()
-                .installations()
-                .send()
-                .await?
-                .take_items();
-            let mut access_token_req =
-                octocrab::params::apps::CreateInstallationAccessToken::default();
-            access_token_req.repositories = vec![];
-            // TODO: Properly fill-in installation
-            log::info!("still doing stuff");
-            let access: octocrab::models::InstallationToken = github_client
-                .post(
-                    installations[0].access_tokens_url.as_ref().unwrap(),
-                    Some(&access_token_req),
-                )
-                .await
-                .map_err(|e| Error::NoAccessToken(format!("{e}")))?;
-            Ok(access.token)
-        })
+            .filter_map(|entry| entry.path().map(|path| DirEntryPath(path.into())))
+            .collect::<Vec<_>>();
+        Ok(list.into())
+    }
+
+    /// Number of files changed and lines inserted and deleted between `from` and `to` (commits,
+    /// or anything else that resolves to one, like branches and tags), like
+    /// `git diff --shortstat <from> <to>`
+    fn diff_stats(&self, from: &str, to: &str) -> Result<git2::DiffStats, Error> {
+        let repo = self.repo.lock()?;
+        let operation = || format!("Diff of {from} and {to}");
+        let tree = |rev: &str| repo.revparse_single(rev).and_then(|object| object.peel_to_tree());
+        let from_tree = tree(from).context(operation)?;
+        let to_tree = tree(to).context(operation)?;
+        repo.diff_tree_to_tree(Some(&from_tree), Some(&to_tree), None)
+            .and_then(|diff| diff.stats())
+            .context(operation)
+    }
+
+    /// `diff_stats` as a map of `files_changed`, `insertions` and `deletions`
+    pub fn pub_diff_stats(
+        &mut self,
+        from: &str,
+        to: &str,
+    ) -> Result<rhai::Map, Box<rhai::EvalAltResult>> {
+        let stats = self.diff_stats(from, to).map_err(|e| format!("{e}"))?;
+        let count =
+            |n: usize| rhai::Dynamic::from(rhai::INT::try_from(n).unwrap_or(rhai::INT::MAX));
+        let mut map = rhai::Map::new();
+        map.insert("files_changed".into(), count(stats.files_changed()));
+        map.insert("insertions".into(), count(stats.insertions()));
+        map.insert("deletions".into(), count(stats.deletions()));
+        Ok(map)
     }
 
+    /// Push the local branch `localref` to origin. With `verify`, check afterwards that origin
+    /// has the branch at the commit that was pushed.
     fn push<L: AsRef<str>>(
         &mut self,
         localref: L,
+        verify: bool,
     ) -> Result<(), Error> {
         log::debug!("pushing!");
-        let repo = self.repo.lock()?;
-        let mut remote = repo.find_remote("origin")?;
-        //let github_client = self.github_client.lock().map_err(|_| Error::ExclusiveLock)?.clone();
-        let github_client = self.github_client.clone();
-        // TODO: Fix block_on
-        //let access_token_res: Result<String, Error> = self.tokio_handle.block_on(async {
-        let (tx, rx) = channel();
-        let handle = tokio::runtime::Handle::current();
-        std::thread::spawn(move || {
-            let res: Result<String, Error> = handle.block_on(async {
-                let github_client = github_client.lock().map_err(|_| Error::ExclusiveLock)?;
-                let installations = github_client
-                    .apps()
-                    .installations()
-                    .send()
-                    .await?
-                    .take_items();
-                let mut access_token_req =
-                    octocrab::params::apps::CreateInstallationAccessToken::default();
-                access_token_req.repositories = vec![];
-                // TODO: Properly fill-in installation
-                log::info!("still doing stuff");
-                let access: octocrab::models::InstallationToken = github_client
-                    .post(
-                        installations[0].access_tokens_url.as_ref().unwrap(),
-                        Some(&access_token_req),
-                    )
-                    .await
-                    .map_err(|e| Error::NoAccessToken(format!("{e}")))?;
-                Ok(access.token)
+        let refspec = format!("refs/heads/{}", localref.as_ref());
+        let context = || format!("Push of {refspec} to origin");
+        let pushed = self.repo.lock()?.refname_to_id(&refspec).context(context)?;
+        let access_token = || Ok(self.github.token(&self.full_name())?);
+        let mut res = push_refspec(
+            &self.repo,
+            |repo| repo.find_remote("origin").context(context),
+            "origin",
+            &refspec,
+            access_token,
+        );
+        if res.is_ok() && verify {
+            let repo = self.repo.lock()?;
+            res = repo.find_remote("origin").context(context).and_then(|mut remote| {
+                verify_push(&mut remote, "origin", &refspec, pushed, access_token()?)
             });
-            tx.send(res)
-                .unwrap_or_else(|e| log::warn!("Failed to send access token through channel: {e}"));
-        });
-
-        let access_token_res: Result<String, Error> = rx.recv()?;
-        let access_token = access_token_res?;
-        log::debug!("Got an access token!");
-        let mut callbacks = git2::RemoteCallbacks::new();
-        callbacks.credentials(move |_url, _username_from_url, _allowed_types| {
-            git2::Cred::userpass_plaintext("x-access-token", &access_token)
-        });
-        let mut push_options = git2::PushOptions::new();
-        push_options.remote_callbacks(callbacks);
-        log::debug!("push options including creds callback ready!");
-        // TODO: Check if this error handling is sufficient
-        if let Err(err) = remote.push::<String>(
-            &[format!("refs/heads/{}", localref.as_ref())],
-            Some(&mut push_options),
-        ) {
+        }
+        if let Err(err) = &res {
             log::debug!("Failed to push: {err}");
-            Err(err)?
-        } else {
-            Ok(())
         }
+        res
     }
 
-    /// Make the given branch point to HEAD and perform a clean checkout
+    /// Push the local branch `localref` to the branch of the pull request the job runs for, also
+    /// when that's in a fork. Pushing to a fork needs the author to allow edits by maintainers.
+    /// With `verify`, check afterwards that the branch is at the commit that was pushed.
+    fn push_to_pr<L: AsRef<str>>(&mut self, localref: L, verify: bool) -> Result<(), Error> {
+        let head = self.pr_head.as_ref().ok_or(Error::NoPullRequestHead)?;
+        let fork = head.repository.owner.login != self.github_owner
+            || head.repository.name != self.github_name;
+        if fork && !head.maintainer_can_modify {
+            return Err(Error::MaintainerCantModify);
+        }
+        let remote_name = format!("{}/{}", head.repository.owner.login, head.repository.name);
+        let remote_ref = format!("refs/heads/{}", head.branch);
+        let refspec = format!("refs/heads/{}:{remote_ref}", localref.as_ref());
+        let context = || format!("Push of {refspec} to {remote_name}");
+        let pushed = self
+            .repo
+            .lock()?
+            .refname_to_id(&format!("refs/heads/{}", localref.as_ref()))
+            .context(context)?;
+        let url = head.repository.clone_url();
+        // The installation token of the base repository, which Github lets push to forks that
+        // allow edits by maintainers
+        let access_token = || Ok(self.github.token(&self.full_name())?);
+        log::info!(
+            "Pushing {} to {} of {}",
+            localref.as_ref(),
+            head.branch,
+            remote_name
+        );
+        push_refspec(
+            &self.repo,
+            |repo| repo.remote_anonymous(url.as_str()).context(context),
+            &remote_name,
+            &refspec,
+            access_token,
+        )?;
+        if verify {
+            let repo = self.repo.lock()?;
+            let mut remote = repo.remote_anonymous(url.as_str()).context(context)?;
+            verify_push(&mut remote, &remote_name, &remote_ref, pushed, access_token()?)?;
+        }
+        Ok(())
+    }
+
+    pub fn pub_push_to_pr<L: AsRef<str>>(
+        &mut self,
+        localref: L,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("push_to_pr")?;
+        let started = std::time::Instant::now();
+        self.push_to_pr(&localref, false).map_err(|e| format!("{e}"))?;
+        log::info!("Pushed {} to the pull request in {:?}", localref.as_ref(), started.elapsed());
+        Ok(())
+    }
+
+    /// `push_to_pr`, then check that the pull request's branch is at the commit that was pushed
+    pub fn pub_push_to_pr_verified<L: AsRef<str>>(
+        &mut self,
+        localref: L,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("push_to_pr")?;
+        let started = std::time::Instant::now();
+        self.push_to_pr(&localref, true).map_err(|e| format!("{e}"))?;
+        log::info!(
+            "Pushed and verified {} to the pull request in {:?}",
+            localref.as_ref(),
+            started.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Reset the index and working tree to HEAD, removing untracked and ignored files
+    fn reset_hard(&mut self) -> Result<(), Error> {
+        let repo = self.repo.lock()?;
+        let operation = || "Reset to HEAD".to_string();
+        let head = repo.revparse_single("HEAD").context(operation)?;
+        repo.reset(
+            &head,
+            git2::ResetType::Hard,
+            Some(
+                CheckoutBuilder::new()
+                    .remove_untracked(true)
+                    .remove_ignored(true)
+                    .force(),
+            ),
+        )
+        .context(operation)?;
+        Ok(())
+    }
+
+    pub fn pub_reset_hard(&mut self) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("reset_hard")?;
+        self.reset_hard().map_err(|e| format!("{e}").into())
+    }
+
+    /// Stash all changes in the working tree (including untracked files)
+    fn stash(&mut self) -> Result<(), Error> {
+        let signature = self.signature()?;
+        let mut repo = self.repo.lock()?;
+        repo.stash_save(
+            &signature,
+            "ci-script stash",
+            Some(git2::StashFlags::INCLUDE_UNTRACKED),
+        )?;
+        Ok(())
+    }
+
+    pub fn pub_stash(&mut self) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("stash")?;
+        self.stash().map_err(|e| format!("{e}").into())
+    }
+
+    /// Apply the most recent stash and remove it from the stash list
+    fn stash_pop(&mut self) -> Result<(), Error> {
+        let mut repo = self.repo.lock()?;
+        repo.stash_pop(0, None)?;
+        Ok(())
+    }
+
+    pub fn pub_stash_pop(&mut self) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("stash_pop")?;
+        self.stash_pop().map_err(|e| format!("{e}").into())
+    }
+
+    /// Make the given branch point to HEAD (creating or resetting it) and perform a clean checkout,
+    /// which throws away uncommitted changes and untracked files. See `ensure_branch` to keep them.
     fn branch<B: AsRef<str>>(&mut self, branch: B) -> Result<(), Error> {
         let repo = self.repo.lock()?;
         let branch = branch.as_ref();
@@ -566,6 +1608,183 @@ impl LocalRepo {
         Ok(())
     }
 
+    /// Switch to the branch `name`, pointing it at HEAD's commit: it's created if it doesn't exist
+    /// and reset if it does. Since HEAD's commit doesn't change, the working directory (including
+    /// uncommitted changes) is left as is, unlike with `branch`. Unlike `checkout_new_branch`, it
+    /// doesn't fail when a script re-runs on the same pull request and the branch already exists.
+    pub fn ensure_branch<B: AsRef<str>>(&mut self, name: B) -> Result<(), Error> {
+        let repo = self.repo.lock()?;
+        let refname = format!("refs/heads/{}", name.as_ref());
+        let head = repo.head()?;
+        // The checked out branch is at HEAD already, and git refuses to reset it anyway
+        if head.name() == Some(refname.as_str()) {
+            return Ok(());
+        }
+        let commit = head.peel_to_commit()?;
+        repo.branch(name.as_ref(), &commit, true)?;
+        repo.set_head(&refname)?;
+        Ok(())
+    }
+
+    pub fn pub_ensure_branch<B: AsRef<str>>(
+        &mut self,
+        name: B,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("ensure_branch")?;
+        self.ensure_branch(name).map_err(|e| format!("{e}").into())
+    }
+
+    /// Switch to a new branch at HEAD named `<prefix>-<issue>-<short HEAD sha>` (without the issue
+    /// outside of jobs for an issue), like `ensure_branch`, and return its name. If a branch of
+    /// that name exists locally or on `origin` already, `-2`, `-3`, ... is appended, so jobs for
+    /// different pull requests (or re-runs) don't push over each other's branches.
+    pub fn unique_branch(&mut self, prefix: &str) -> Result<String, Error> {
+        let mut taken = self.origin_branches()?;
+        let name = {
+            let repo = self.repo.lock()?;
+            let head = repo.head()?.peel_to_commit()?;
+            let mut base = String::from(prefix);
+            if let Some(issue_number) = self.issue_number {
+                base.push_str(&format!("-{issue_number}"));
+            }
+            base.push_str(&format!("-{:.8}", head.id()));
+            for branch in repo.branches(Some(git2::BranchType::Local))? {
+                if let Some(name) = branch?.0.name()? {
+                    taken.insert(name.to_string());
+                }
+            }
+            let mut name = base.clone();
+            for n in 2.. {
+                if !taken.contains(&name) {
+                    break;
+                }
+                name = format!("{base}-{n}");
+            }
+            name
+        };
+        if !git2::Branch::name_is_valid(&name)? {
+            return Err(Error::InvalidBranchName(name));
+        }
+        self.ensure_branch(&name)?;
+        Ok(name)
+    }
+
+    pub fn pub_unique_branch(&mut self, prefix: &str) -> Result<String, Box<rhai::EvalAltResult>> {
+        self.check_writable("unique_branch")?;
+        self.unique_branch(prefix).map_err(|e| format!("{e}").into())
+    }
+
+    /// Names of the branches of the `origin` remote as it is now (not as of the last fetch), none
+    /// if the repository has no `origin`
+    fn origin_branches(&self) -> Result<std::collections::HashSet<String>, Error> {
+        let repo = self.repo.lock()?;
+        let mut remote = match repo.find_remote("origin") {
+            Ok(remote) => remote,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let access_token = self.github.token(&self.full_name())?;
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(move |_url, _username_from_url, _allowed_types| {
+            git2::Cred::userpass_plaintext("x-access-token", &access_token)
+        });
+        let connection = remote
+            .connect_auth(git2::Direction::Fetch, Some(callbacks), None)
+            .context(|| "Listing the branches of origin".into())?;
+        let branches = connection
+            .list()
+            .context(|| "Listing the branches of origin".into())?
+            .iter()
+            .filter_map(|head| head.name().strip_prefix("refs/heads/"))
+            .map(String::from)
+            .collect();
+        Ok(branches)
+    }
+
+    /// Owner of the Github repository (user or organization)
+    pub fn get_owner(&mut self) -> String {
+        self.github_owner.clone()
+    }
+
+    /// Name of the Github repository (without the owner)
+    pub fn get_name(&mut self) -> String {
+        self.github_name.clone()
+    }
+
+    /// Name of the Github repository as `owner/name`
+    fn full_name(&self) -> String {
+        format!("{}/{}", self.github_owner, self.github_name)
+    }
+
+    /// Web URL of the Github repository. Use `url()` for the URL of the `origin` remote.
+    pub fn get_url(&mut self) -> String {
+        format!("{}{}/{}", self.github_url, self.github_owner, self.github_name)
+    }
+
+    /// Switch to an existing local branch and check out its tree. Like `git checkout`, this fails
+    /// instead of overwriting uncommitted changes that conflict with the branch.
+    fn checkout<B: AsRef<str>>(&mut self, branch: B) -> Result<(), Error> {
+        let repo = self.repo.lock()?;
+        let branch = branch.as_ref();
+        let reference = match repo.find_branch(branch, git2::BranchType::Local) {
+            Ok(branch) => branch.into_reference(),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                return Err(Error::BranchNotFound(branch.into()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let tree = reference.peel_to_tree()?;
+        repo.checkout_tree(tree.as_object(), Some(CheckoutBuilder::new().safe()))?;
+        repo.set_head(&format!("refs/heads/{branch}"))?;
+        Ok(())
+    }
+
+    pub fn pub_checkout<B: AsRef<str>>(&mut self, branch: B) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("checkout")?;
+        self.checkout(branch).map_err(|e| format!("{e}").into())
+    }
+
+    fn head_commit_info(&self) -> Result<(String, String), Error> {
+        let repo = self.repo.lock()?;
+        let commit = repo.revparse_single("HEAD")?.peel_to_commit()?;
+        let message = String::from_utf8_lossy(commit.message_bytes()).into_owned();
+        Ok((commit.id().to_string(), message))
+    }
+
+    /// Full SHA of the commit HEAD points to
+    pub fn head_sha(&mut self) -> Result<String, Box<rhai::EvalAltResult>> {
+        let (sha, _) = self.head_commit_info().map_err(|e| format!("{e}"))?;
+        Ok(sha)
+    }
+
+    /// Full commit message of the commit HEAD points to
+    pub fn head_message(&mut self) -> Result<String, Box<rhai::EvalAltResult>> {
+        let (_, message) = self.head_commit_info().map_err(|e| format!("{e}"))?;
+        Ok(message)
+    }
+
+    fn log(&self, count: usize) -> Result<Vec<String>, Error> {
+        let repo = self.repo.lock()?;
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push_head()?;
+        revwalk
+            .take(count)
+            .map(|oid| {
+                let commit = repo.find_commit(oid?)?;
+                let summary = String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default());
+                Ok(format!("{} {}", commit.id(), summary))
+            })
+            .collect()
+    }
+
+    /// Similar to `git log --format='%H %s'`, return the last `count` commits reachable from HEAD
+    pub fn pub_log(&mut self, count: i64) -> Result<rhai::Dynamic, Box<rhai::EvalAltResult>> {
+        let count = usize::try_from(count).map_err(|_| "Commit count can't be negative")?;
+        self.log(count)
+            .map(|log| log.into())
+            .map_err(|e| format!("{e}").into())
+    }
+
     pub fn pub_url(&mut self) -> Result<String, Box<rhai::EvalAltResult>> {
         let url = self.url().map_err(|e| format!("{}", e))?;
         Ok(url)
@@ -593,6 +1812,7 @@ impl LocalRepo {
     }
 
     pub fn pub_branch<B: AsRef<str>>(&mut self, branch: B) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("branch")?;
         self.branch(branch).map_err(|e| format!("{e}").into())
     }
 
@@ -600,8 +1820,23 @@ impl LocalRepo {
         &mut self,
         localref: L,
     ) -> Result<(), Box<rhai::EvalAltResult>> {
-        self.push(localref)
-            .map_err(|e| format!("{e}").into())
+        self.check_writable("push")?;
+        let started = std::time::Instant::now();
+        self.push(&localref, false).map_err(|e| format!("{e}"))?;
+        log::info!("Pushed {} in {:?}", localref.as_ref(), started.elapsed());
+        Ok(())
+    }
+
+    /// `push`, then check that origin has the branch at the commit that was pushed
+    pub fn pub_push_verified<L: AsRef<str>>(
+        &mut self,
+        localref: L,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.check_writable("push")?;
+        let started = std::time::Instant::now();
+        self.push(&localref, true).map_err(|e| format!("{e}"))?;
+        log::info!("Pushed and verified {} in {:?}", localref.as_ref(), started.elapsed());
+        Ok(())
     }
 
     fn status(&self) -> Result<Status, Error> {
@@ -609,7 +1844,7 @@ impl LocalRepo {
         let statuses = {
             let repo = self.repo.lock()?;
             let x = repo
-                .statuses(None)?
+                .statuses(Some(&mut status_options()))?
                 .iter()
                 .filter_map(|entry| entry.try_into().ok())
                 .collect::<Vec<StatusEntry>>();
@@ -623,6 +1858,17 @@ impl LocalRepo {
     }
 }
 
+/// Lists untracked files one by one rather than their directories, so every path in a status can
+/// be read and added
+fn status_options() -> git2::StatusOptions {
+    let mut options = git2::StatusOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .include_unmodified(false);
+    options
+}
+
 #[derive(Clone)]
 struct StatusEntry {
     path: PathBuf,
@@ -650,6 +1896,10 @@ pub struct Status {
     statuses: Vec<StatusEntry>,
 }
 
+/// A path relative to the root of a repository, like `src/lib.rs`. All paths `LocalRepo` returns
+/// (from `ls`, `ls_files` and `ls_modified`, and the `changed`, `added` and `deleted` files of
+/// its `status`) are relative to the root of its repository, so they can be passed back to its
+/// `read`, `write` and `add`.
 #[derive(Clone, Debug, PartialEq)]
 pub struct DirEntryPath(PathBuf);
 
@@ -775,3 +2025,26 @@ impl Status {
         Ok(files)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetch_ref_names() {
+        for (name, remote, local) in [
+            ("main", "refs/heads/main", "refs/heads/main"),
+            ("feature/x", "refs/heads/feature/x", "refs/heads/feature/x"),
+            ("heads/main", "refs/heads/main", "refs/heads/main"),
+            ("tags/v1.0", "refs/tags/v1.0", "refs/tags/v1.0"),
+            ("pull/1/head", "refs/pull/1/head", "refs/heads/pull/1/head"),
+            ("pull/1/merge", "refs/pull/1/merge", "refs/heads/pull/1/merge"),
+            ("refs/heads/main", "refs/heads/main", "refs/heads/main"),
+            ("refs/tags/v1.0", "refs/tags/v1.0", "refs/tags/v1.0"),
+            ("refs/pull/1/head", "refs/pull/1/head", "refs/heads/pull/1/head"),
+        ] {
+            let fetch_ref = FetchRef::new(name);
+            assert_eq!((fetch_ref.remote.as_str(), fetch_ref.local.as_str()), (remote, local));
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+use super::auth::{self, AuthProvider};
+use super::retry;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to authenticate with Github: {0}")]
+    Auth(#[from] auth::Error),
+    #[error("Error talking to Github: {0}")]
+    Api(#[from] octocrab::Error),
+    #[error("Given name is not a valid Github repo name (`owner/repo`): {0}")]
+    InvalidRepoName(String),
+    #[error("Github did not report a default branch for the repository")]
+    NoDefaultBranch,
+    #[error("File `{path}` not found in {repo} at `{git_ref}`")]
+    FileNotFound {
+        repo: String,
+        git_ref: String,
+        path: String,
+    },
+    #[error("`{0}` is not a file")]
+    NotAFile(String),
+    #[error("Invalid file path `{0}`: expected a relative path without `.` or `..` components")]
+    InvalidPath(String),
+    #[error("File `{0}` is too large to fetch through the Github contents API")]
+    FileTooLarge(String),
+    #[error("Failed to decode file contents: {0}")]
+    ContentDecode(#[from] base64::DecodeError),
+    #[error("Mock Github directory {0:?} is not an absolute path")]
+    MockDirectory(PathBuf),
+    #[error("Error in mock Github repository: {0}")]
+    MockRepository(#[from] git2::Error),
+}
+
+/// A pull request to open, see `Github::create_pr`
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct PullRequest {
+    pub title: String,
+    pub body: String,
+    /// Branch with the changes
+    pub head: String,
+    /// Branch to merge the changes into
+    pub base: String,
+}
+
+/// The Github operations scripts perform (besides cloning and pushing over git), so they can run
+/// against something other than Github, like the `Mock` for testing scripts end-to-end. All
+/// repositories are given as `owner/name`.
+pub trait Github: std::fmt::Debug + Send + Sync {
+    /// Token to push to `repo` with
+    fn token(&self, repo: &str) -> Result<String, Error>;
+
+    /// Web URL of the Github instance, e.g. `https://github.com/`. Repositories are cloned from
+    /// `<web_url><owner>/<name>`.
+    fn web_url(&self) -> url::Url;
+
+    fn create_comment(&self, repo: &str, issue_nr: u64, body: &str) -> Result<(), Error>;
+
+    fn create_pr(&self, repo: &str, pr: &PullRequest) -> Result<(), Error>;
+
+    /// Upload `content` as a secret gist with a single file `filename`, returning its web URL.
+    /// The gist is created with the credentials for `repo`, which only works with a personal
+    /// access token since Github Apps can't create gists.
+    fn create_gist(&self, repo: &str, gist: &NewGist) -> Result<String, Error>;
+
+    /// Report a finished check on a commit of `repo`, which only works when authenticated as a
+    /// Github App
+    fn create_check_run(&self, repo: &str, check_run: &NewCheckRun) -> Result<(), Error>;
+
+    /// The default branch of `repo`, e.g. `main`
+    fn default_branch(&self, repo: &str) -> Result<String, Error>;
+
+    /// Contents of the file at `path` in `repo` at `git_ref` (a branch, tag or commit). Only
+    /// `authenticated` requests use the credentials for `repo`, others can only read public
+    /// repositories.
+    fn file_contents(
+        &self,
+        repo: &str,
+        git_ref: &str,
+        path: &str,
+        authenticated: bool,
+    ) -> Result<Vec<u8>, Error>;
+}
+
+/// Body of a request to create an issue comment
+#[derive(serde::Serialize)]
+struct NewComment<'a> {
+    body: &'a str,
+}
+
+/// A secret gist with a single file to create, see `Github::create_gist`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NewGist {
+    pub description: String,
+    pub filename: String,
+    pub content: String,
+}
+
+/// Body of a request to create a gist
+#[derive(serde::Serialize)]
+struct GistBody<'a> {
+    description: &'a str,
+    public: bool,
+    files: std::collections::HashMap<&'a str, GistFile<'a>>,
+}
+
+#[derive(serde::Serialize)]
+struct GistFile<'a> {
+    content: &'a str,
+}
+
+/// The part of a created gist we need
+#[derive(serde::Deserialize)]
+struct Gist {
+    html_url: String,
+}
+
+/// A completed check run to create, see `Github::create_check_run`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NewCheckRun {
+    pub name: String,
+    /// The commit the check ran on
+    pub head_sha: String,
+    /// One of `success`, `failure`, `neutral`, `cancelled`, `skipped`, `timed_out` or
+    /// `action_required`
+    pub conclusion: String,
+    /// Markdown shown on the check's page
+    pub summary: String,
+}
+
+/// Body of a request to create a check run
+#[derive(serde::Serialize)]
+struct CheckRunBody<'a> {
+    name: &'a str,
+    head_sha: &'a str,
+    status: &'a str,
+    conclusion: &'a str,
+    output: CheckRunOutput<'a>,
+}
+
+#[derive(serde::Serialize)]
+struct CheckRunOutput<'a> {
+    title: &'a str,
+    summary: &'a str,
+}
+
+fn split_repo(repo: &str) -> Result<(&str, &str), Error> {
+    repo.split_once('/')
+        .ok_or_else(|| Error::InvalidRepoName(repo.into()))
+}
+
+/// The components of the file `path` in a repository. Empty, `.` and `..` components are refused,
+/// they'd point the contents API URL somewhere else.
+fn path_components(path: &str) -> Result<Vec<&str>, Error> {
+    let components: Vec<&str> = path.split('/').collect();
+    if components
+        .iter()
+        .any(|component| matches!(*component, "" | "." | ".."))
+    {
+        return Err(Error::InvalidPath(path.into()));
+    }
+    Ok(components)
+}
+
+/// Talks to the Github API, authenticated by an `AuthProvider`
+#[derive(Clone, Debug)]
+pub struct Client {
+    auth: Arc<dyn AuthProvider>,
+}
+
+impl Client {
+    pub fn new(auth: Arc<dyn AuthProvider>) -> Self {
+        Client { auth }
+    }
+}
+
+impl Github for Client {
+    fn token(&self, repo: &str) -> Result<String, Error> {
+        Ok(self.auth.token(repo)?)
+    }
+
+    fn web_url(&self) -> url::Url {
+        super::web_url(self.auth.api_url())
+    }
+
+    fn create_comment(&self, repo: &str, issue_nr: u64, body: &str) -> Result<(), Error> {
+        let (owner, name) = split_repo(repo)?;
+        let client = self.auth.client(repo)?;
+        let url =
+            client.absolute_url(format!("repos/{owner}/{name}/issues/{issue_nr}/comments"))?;
+        let body = NewComment { body };
+        // Not idempotent, a retried comment could show up twice
+        let _: octocrab::models::issues::Comment = auth::block_on(retry::send(false, || {
+            client._post(url.clone(), Some(&body))
+        }))??;
+        Ok(())
+    }
+
+    fn create_pr(&self, repo: &str, pr: &PullRequest) -> Result<(), Error> {
+        let (owner, name) = split_repo(repo)?;
+        let client = self.auth.client(repo)?;
+        let url = client.absolute_url(format!("repos/{owner}/{name}/pulls"))?;
+        let _: octocrab::models::pulls::PullRequest =
+            auth::block_on(retry::send(false, || client._post(url.clone(), Some(pr))))??;
+        Ok(())
+    }
+
+    fn create_gist(&self, repo: &str, gist: &NewGist) -> Result<String, Error> {
+        let client = self.auth.client(repo)?;
+        let url = client.absolute_url("gists")?;
+        let body = GistBody {
+            description: &gist.description,
+            public: false,
+            files: std::iter::once((
+                gist.filename.as_str(),
+                GistFile {
+                    content: &gist.content,
+                },
+            ))
+            .collect(),
+        };
+        // Not idempotent, a retry could create the gist twice
+        let gist: Gist = auth::block_on(retry::send(false, || {
+            client._post(url.clone(), Some(&body))
+        }))??;
+        Ok(gist.html_url)
+    }
+
+    fn create_check_run(&self, repo: &str, check_run: &NewCheckRun) -> Result<(), Error> {
+        let (owner, name) = split_repo(repo)?;
+        let client = self.auth.client(repo)?;
+        let url = client.absolute_url(format!("repos/{owner}/{name}/check-runs"))?;
+        let body = CheckRunBody {
+            name: &check_run.name,
+            head_sha: &check_run.head_sha,
+            status: "completed",
+            conclusion: &check_run.conclusion,
+            output: CheckRunOutput {
+                title: &check_run.name,
+                summary: &check_run.summary,
+            },
+        };
+        // Not idempotent, a retry could report the check twice
+        let _: octocrab::models::CheckRun = auth::block_on(retry::send(false, || {
+            client._post(url.clone(), Some(&body))
+        }))??;
+        Ok(())
+    }
+
+    fn default_branch(&self, repo: &str) -> Result<String, Error> {
+        let (owner, name) = split_repo(repo)?;
+        let client = self.auth.client(repo)?;
+        let url = client.absolute_url(format!("repos/{owner}/{name}"))?;
+        let repo: octocrab::models::Repository =
+            auth::block_on(retry::send(true, || client._get(url.clone(), None::<&()>)))??;
+        repo.default_branch.ok_or(Error::NoDefaultBranch)
+    }
+
+    /// Fetched through the contents API, without cloning the repository
+    fn file_contents(
+        &self,
+        repo: &str,
+        git_ref: &str,
+        path: &str,
+        authenticated: bool,
+    ) -> Result<Vec<u8>, Error> {
+        let (owner, name) = split_repo(repo)?;
+        let components = path_components(path)?;
+        let client = if authenticated {
+            self.auth.client(repo)?
+        } else {
+            self.auth.anonymous_client()?
+        };
+        let mut url = client.absolute_url("repos/")?;
+        // Percent-encodes every component, so `?` or `#` in the path can't end it early
+        url.path_segments_mut()
+            .map_err(|_| Error::InvalidPath(path.into()))?
+            .pop_if_empty()
+            .extend([owner, name, "contents"])
+            .extend(components);
+        let query = [("ref", git_ref)];
+        let items = auth::block_on(retry::send(true, || client._get(url.clone(), Some(&query))))?;
+        let mut items: octocrab::models::repos::ContentItems = match items {
+            Ok(items) => items,
+            Err(octocrab::Error::GitHub { source, .. }) if source.message == "Not Found" => {
+                return Err(Error::FileNotFound {
+                    repo: repo.into(),
+                    git_ref: git_ref.into(),
+                    path: path.into(),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Directories list their entries instead
+        let file = match items.take_items().as_slice() {
+            [file] if file.r#type == "file" => file.clone(),
+            _ => return Err(Error::NotAFile(path.into())),
+        };
+        let content = file.content.unwrap_or_default();
+        // The contents API leaves out the content of files over 1MB
+        if content.is_empty() && file.size > 0 {
+            return Err(Error::FileTooLarge(path.into()));
+        }
+        // The content is base64 encoded with line breaks
+        let content: String = content.split_whitespace().collect();
+        Ok(base64::decode(content)?)
+    }
+}
+
+/// Comment recorded by the `Mock`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comment {
+    pub repo: String,
+    pub issue_nr: u64,
+    pub body: String,
+}
+
+/// Fake Github serving the (bare) git repositories in a local directory as `<dir>/<owner>/<name>`,
+/// which records the comments and pull requests scripts create instead of posting them, so tests
+/// can run scripts end-to-end and check what they did.
+#[derive(Debug)]
+pub struct Mock {
+    dir: PathBuf,
+    web_url: url::Url,
+    comments: Mutex<Vec<Comment>>,
+    pull_requests: Mutex<Vec<(String, PullRequest)>>,
+    gists: Mutex<Vec<NewGist>>,
+    check_runs: Mutex<Vec<(String, NewCheckRun)>>,
+}
+
+impl Mock {
+    /// `dir` must be an absolute path, since it's also used for the `file://` clone URLs
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.into();
+        let web_url =
+            url::Url::from_directory_path(&dir).map_err(|()| Error::MockDirectory(dir.clone()))?;
+        Ok(Mock {
+            dir,
+            web_url,
+            comments: Mutex::new(vec![]),
+            pull_requests: Mutex::new(vec![]),
+            gists: Mutex::new(vec![]),
+            check_runs: Mutex::new(vec![]),
+        })
+    }
+
+    /// The comments created so far, oldest first
+    pub fn comments(&self) -> Vec<Comment> {
+        self.comments
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The pull requests created so far with their repositories, oldest first
+    pub fn pull_requests(&self) -> Vec<(String, PullRequest)> {
+        self.pull_requests
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The gists created so far, oldest first
+    pub fn gists(&self) -> Vec<NewGist> {
+        self.gists
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The check runs created so far with their repositories, oldest first
+    pub fn check_runs(&self) -> Vec<(String, NewCheckRun)> {
+        self.check_runs
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    fn open(&self, repo: &str) -> Result<git2::Repository, Error> {
+        let (owner, name) = split_repo(repo)?;
+        Ok(git2::Repository::open(self.dir.join(owner).join(name))?)
+    }
+}
+
+impl Github for Mock {
+    fn token(&self, _repo: &str) -> Result<String, Error> {
+        // Local repositories don't ask for credentials
+        Ok(String::new())
+    }
+
+    fn web_url(&self) -> url::Url {
+        self.web_url.clone()
+    }
+
+    fn create_comment(&self, repo: &str, issue_nr: u64, body: &str) -> Result<(), Error> {
+        self.comments
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .push(Comment {
+                repo: repo.into(),
+                issue_nr,
+                body: body.into(),
+            });
+        Ok(())
+    }
+
+    fn create_pr(&self, repo: &str, pr: &PullRequest) -> Result<(), Error> {
+        self.pull_requests
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .push((repo.into(), pr.clone()));
+        Ok(())
+    }
+
+    /// Gists get made up URLs under the `web_url`, numbered in order of creation
+    fn create_gist(&self, _repo: &str, gist: &NewGist) -> Result<String, Error> {
+        let mut gists = self
+            .gists
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        gists.push(gist.clone());
+        Ok(format!("{}gists/{}", self.web_url, gists.len()))
+    }
+
+    fn create_check_run(&self, repo: &str, check_run: &NewCheckRun) -> Result<(), Error> {
+        self.check_runs
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .push((repo.into(), check_run.clone()));
+        Ok(())
+    }
+
+    /// The branch `HEAD` of the repository points to
+    fn default_branch(&self, repo: &str) -> Result<String, Error> {
+        let git_repo = self.open(repo)?;
+        let head = git_repo.find_reference("HEAD")?;
+        head.symbolic_target()
+            .and_then(|target| target.strip_prefix("refs/heads/"))
+            .map(String::from)
+            .ok_or(Error::NoDefaultBranch)
+    }
+
+    /// Serves every repository, `authenticated` or not
+    fn file_contents(
+        &self,
+        repo: &str,
+        git_ref: &str,
+        path: &str,
+        _authenticated: bool,
+    ) -> Result<Vec<u8>, Error> {
+        path_components(path)?;
+        let not_found = || Error::FileNotFound {
+            repo: repo.into(),
+            git_ref: git_ref.into(),
+            path: path.into(),
+        };
+        let git_repo = self.open(repo)?;
+        let object = match git_repo.revparse_single(&format!("{git_ref}:{path}")) {
+            Ok(object) => object,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Err(not_found()),
+            Err(e) => return Err(e.into()),
+        };
+        let blob = object
+            .into_blob()
+            .map_err(|_| Error::NotAFile(path.into()))?;
+        Ok(blob.content().to_vec())
+    }
+}
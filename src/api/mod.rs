@@ -1,4 +1,4 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -11,108 +11,257 @@ pub enum Error {
     ExclusiveLock,
 }
 
+pub mod auth;
 pub mod cargo;
 pub mod git;
+pub mod github;
+pub mod retry;
 pub mod rhai;
 
-use crate::job::Repository;
+/// Web URL of the Github instance served by the API at `api_url`, e.g. `https://github.com/` for
+/// `https://api.github.com/` and `https://github.example.com/` for a Github Enterprise server at
+/// `https://github.example.com/api/v3/`.
+pub(crate) fn web_url(api_url: &url::Url) -> url::Url {
+    let mut url = api_url.clone();
+    url.set_path("/");
+    url.set_query(None);
+    url.set_fragment(None);
+    if let Some(host) = api_url
+        .host_str()
+        .and_then(|host| host.strip_prefix("api."))
+    {
+        // Only fails for URLs that cannot have a host, which `api_url` already has
+        let _ = url.set_host(Some(host));
+    }
+    url
+}
+
+use crate::job::{PullRequestHead, Repository};
+use github::Github;
+
+/// Github rejects comments longer than this many characters
+pub const MAX_COMMENT_LEN: usize = 65536;
+
+/// Github rejects check run summaries longer than this many characters
+pub const MAX_CHECK_SUMMARY_LEN: usize = 65535;
+
+/// The conclusions a check run can have, see `Issue::create_check_run`
+const CHECK_RUN_CONCLUSIONS: &[&str] = &[
+    "success",
+    "failure",
+    "neutral",
+    "cancelled",
+    "skipped",
+    "timed_out",
+    "action_required",
+];
+
+/// Cut `body` down to `max` characters if needed, ending it with `notice`
+fn truncate_comment(body: &str, max: usize, notice: &str) -> String {
+    if body.chars().count() <= max {
+        return body.into();
+    }
+    // Leaves room to close a code block before the notice
+    let keep = max.saturating_sub(notice.chars().count() + "\n```\n\n".len());
+    let end = body.char_indices().nth(keep).map_or(body.len(), |(i, _)| i);
+    let head = &body[..end];
+    // An odd number of fences means the cut is inside a code block, which is closed so the notice
+    // isn't rendered as code
+    let fence = if head.matches("```").count() % 2 == 1 {
+        "\n```"
+    } else {
+        ""
+    };
+    format!("{head}{fence}\n\n{notice}")
+}
 #[derive(Clone, Debug)]
 pub struct Issue {
-    client: Arc<Mutex<octocrab::Octocrab>>,
+    github: Arc<dyn Github>,
     repository: Repository,
     issue: octocrab::models::issues::Issue,
+    /// Commit of the pull request the job checked out, see `Issue::create_check_run`
+    head_sha: Option<String>,
+    pr_head: Option<PullRequestHead>,
 }
 
 use std::convert::TryInto;
 
 impl Issue {
+    /// Bodies over `MAX_COMMENT_LEN` characters are truncated, with a notice at the end
     pub fn create_comment<S: AsRef<str>>(
         &mut self,
         body: S,
-    ) -> Result<octocrab::models::issues::Comment, Box<::rhai::EvalAltResult>> {
-        // Unfortunately (like I just found out) octocrab depends on reqwest which depends on
-        // tokio. Octocrab has an issue to fix that though, which I just might do :D
-        //
-        // TODO: Think about ways to re-use the tokio runtime
-        // TODO: Fix https://github.com/XAMPPRocky/octocrab/issues/99
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .map_err(|e| format!("{}", e))?;
-
-        let github_installation_client = match rt.block_on(async {
-            // TODO: Get rid of at least the first unwrap (I just introduced it, used to be a ?
-            let installations = self
-                .client
-                .lock()
-                .unwrap()
-                .apps()
-                .installations()
-                .send()
-                .await
-                .unwrap()
-                .take_items();
-            let mut access_token_req =
-                octocrab::params::apps::CreateInstallationAccessToken::default();
-            access_token_req.repository_ids = vec![self.repository.id];
-            // TODO: Properly fill-in installation
-            // TODO: Get rid of at least the first unwrap (I just introduced it, used to be a ?
-            let access: octocrab::models::InstallationToken = self
-                .client
-                .lock()
-                .unwrap()
-                .post(
-                    installations[0].access_tokens_url.as_ref().unwrap(),
-                    Some(&access_token_req),
-                )
-                .await?;
-            octocrab::OctocrabBuilder::new()
-                .personal_token(access.token)
-                .build()
-        }) {
-            Ok(github_installation_client) => github_installation_client,
-            _ => {
-                log::warn!("Failed to require octocrab Github client");
-                return Err(format!("Failed to require octocrab Github client").into());
+    ) -> Result<(), Box<::rhai::EvalAltResult>> {
+        let body = body.as_ref();
+        let notice = format!(
+            "*(truncated, the full comment was {} characters)*",
+            body.chars().count()
+        );
+        self.post_comment(&truncate_comment(body, MAX_COMMENT_LEN, &notice))
+    }
+
+    /// Like `create_comment`, but a body over `MAX_COMMENT_LEN` characters is also uploaded as a
+    /// secret gist that the truncated comment links to. Falls back to only truncating when the
+    /// gist can't be created, e.g. when authenticated as a Github App.
+    pub fn create_comment_gist<S: AsRef<str>>(
+        &mut self,
+        body: S,
+    ) -> Result<(), Box<::rhai::EvalAltResult>> {
+        let body = body.as_ref();
+        if body.chars().count() <= MAX_COMMENT_LEN {
+            return self.post_comment(body);
+        }
+        let gist = github::NewGist {
+            description: format!("Comment on {}#{}", self.full_name(), self.issue.number),
+            filename: "comment.md".into(),
+            content: body.into(),
+        };
+        match self.github.create_gist(&self.full_name(), &gist) {
+            Ok(url) => {
+                let notice = format!("*(truncated, see the [full comment]({url}))*");
+                self.post_comment(&truncate_comment(body, MAX_COMMENT_LEN, &notice))
             }
+            Err(e) => {
+                log::warn!("Failed to upload long comment as a gist: {e}");
+                self.create_comment(body)
+            }
+        }
+    }
+
+    fn post_comment(&self, body: &str) -> Result<(), Box<::rhai::EvalAltResult>> {
+        let issue_nr = self
+            .issue
+            .number
+            .try_into()
+            .map_err(|e: std::num::TryFromIntError| e.to_string())?;
+        self.github
+            .create_comment(&self.full_name(), issue_nr, body)
+            .map_err(|e| format!("Failed to create comment: {e}").into())
+    }
+
+    /// Report a finished check called `name` on the commit of the pull request the job checked
+    /// out (its head, also when the merge ref was checked out), with `summary` (Markdown)
+    /// shown on the check's page. This needs Github App credentials. Summaries over
+    /// `MAX_CHECK_SUMMARY_LEN` characters are truncated.
+    pub fn create_check_run<S: AsRef<str>>(
+        &mut self,
+        name: S,
+        conclusion: S,
+        summary: S,
+    ) -> Result<(), Box<::rhai::EvalAltResult>> {
+        let conclusion = conclusion.as_ref();
+        if !CHECK_RUN_CONCLUSIONS.contains(&conclusion) {
+            return Err(format!(
+                "Unknown check run conclusion `{conclusion}`, expected one of {}",
+                CHECK_RUN_CONCLUSIONS.join(", ")
+            )
+            .into());
+        }
+        let head_sha = self
+            .head_sha
+            .clone()
+            .ok_or("Check runs can only be created for jobs on pull requests")?;
+        let summary = summary.as_ref();
+        let notice = format!(
+            "*(truncated, the full summary was {} characters)*",
+            summary.chars().count()
+        );
+        let check_run = github::NewCheckRun {
+            name: name.as_ref().into(),
+            head_sha,
+            conclusion: conclusion.into(),
+            summary: truncate_comment(summary, MAX_CHECK_SUMMARY_LEN, &notice),
         };
+        self.github
+            .create_check_run(&self.full_name(), &check_run)
+            .map_err(|e| format!("Failed to create check run: {e}").into())
+    }
 
-        log::debug!("about to get a list of issues");
-        rt.block_on(async {
-            /*
-            let page = self.client
-                .lock()
-            let page = github_installation_client
-                .issues(&self.repository.owner.login, &self.repository.name)
-                .list()
-                .send()
-                .await
-                .map_err(|e| e.to_string())?;
-                */
-
-            github_installation_client
-                .issues(&self.repository.owner.login, &self.repository.name)
-                .create_comment(
-                    self.issue
-                        .number
-                        .try_into()
-                        .map_err(|e: std::num::TryFromIntError| e.to_string())?,
-                    body,
-                )
-                .await
-                .map_err(|e| e.to_string().into())
+    /// Name of the repository as `owner/name`
+    fn full_name(&self) -> String {
+        format!("{}/{}", self.repository.owner.login, self.repository.name)
+    }
+
+    /// Render the given headers and rows as a Markdown table and post it as a comment
+    pub fn comment_table(
+        &mut self,
+        headers: ::rhai::Array,
+        rows: ::rhai::Array,
+    ) -> Result<(), Box<::rhai::EvalAltResult>> {
+        let table = self::rhai::markdown_table(headers, rows)?;
+        self.create_comment(table)
+    }
+
+    /// Number of the issue or pull request
+    pub fn get_number(&mut self) -> ::rhai::INT {
+        self.issue.number
+    }
+
+    pub fn get_title(&mut self) -> String {
+        self.issue.title.clone()
+    }
+
+    /// Whether the command was given on a pull request rather than an issue
+    pub fn get_is_pull_request(&mut self) -> bool {
+        self.issue.pull_request.is_some()
+    }
+
+    /// Web URL of the issue or pull request
+    pub fn get_html_url(&mut self) -> String {
+        self.issue.html_url.to_string()
+    }
+
+    /// Repository of the pull request's branch as `owner/name`, `()` if unknown (e.g. for issues)
+    pub fn get_head_repo(&mut self) -> ::rhai::Dynamic {
+        self.pr_head.as_ref().map_or(().into(), |head| {
+            format!("{}/{}", head.repository.owner.login, head.repository.name).into()
         })
     }
 
+    /// Name of the pull request's branch in `head_repo`, `()` if unknown
+    pub fn get_head_branch(&mut self) -> ::rhai::Dynamic {
+        self.pr_head
+            .as_ref()
+            .map_or(().into(), |head| head.branch.clone().into())
+    }
+
+    /// URL to clone `head_repo` from, `()` if unknown
+    pub fn get_head_clone_url(&mut self) -> ::rhai::Dynamic {
+        self.pr_head
+            .as_ref()
+            .map_or(().into(), |head| head.repository.clone_url().to_string().into())
+    }
+
+    /// Whether the pull request's branch is in a fork rather than the repository itself
+    pub fn get_is_fork(&mut self) -> bool {
+        self.pr_head
+            .as_ref()
+            .is_some_and(|head| head.repository.id != self.repository.id)
+    }
+
     pub fn new(
-        client: Arc<Mutex<octocrab::Octocrab>>,
+        github: Arc<dyn Github>,
         repository: Repository,
         issue: octocrab::models::issues::Issue,
     ) -> Self {
         Issue {
-            client,
+            github,
             repository,
             issue,
+            head_sha: None,
+            pr_head: None,
         }
     }
+
+    /// Attach check runs to `head_sha`, see `create_check_run`
+    pub fn with_head_sha(mut self, head_sha: Option<String>) -> Self {
+        self.head_sha = head_sha;
+        self
+    }
+
+    /// See `get_head_repo` and friends
+    pub fn with_pr_head(mut self, pr_head: Option<PullRequestHead>) -> Self {
+        self.pr_head = pr_head;
+        self
+    }
 }
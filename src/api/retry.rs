@@ -0,0 +1,111 @@
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::{header::HeaderMap, StatusCode};
+
+/// Attempts of a request before `send` gives up
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Longest wait before a retry Github may ask for (with `Retry-After` or a rate limit reset),
+/// requests that would have to wait longer fail right away instead of stalling the job
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Send the Github API request made by `request` and parse the response, retrying transient
+/// failures up to `MAX_ATTEMPTS` times. Retries wait as long as Github asks for, or back off
+/// exponentially if it doesn't say.
+///
+/// Requests that were rate limited or couldn't connect never took effect, so they're always
+/// retried. Server errors and timeouts are only retried for `idempotent` requests, since Github
+/// may have acted on the request before failing.
+pub async fn send<R, F, Fut>(idempotent: bool, request: F) -> octocrab::Result<R>
+where
+    R: octocrab::FromResponse,
+    F: Fn() -> Fut,
+    Fut: Future<Output = octocrab::Result<reqwest::Response>>,
+{
+    let policy = backoff::ExponentialBackoff {
+        initial_interval: Duration::from_secs(1),
+        multiplier: 2.0,
+        max_elapsed_time: None,
+        ..Default::default()
+    };
+    let mut attempts = 0;
+    backoff::future::retry_notify(
+        policy,
+        || {
+            attempts += 1;
+            let last = attempts >= MAX_ATTEMPTS;
+            let response = request();
+            async move {
+                match attempt(response.await, idempotent).await {
+                    Err(backoff::Error::Transient { err, .. }) if last => {
+                        Err(backoff::Error::Permanent(err))
+                    }
+                    result => result,
+                }
+            }
+        },
+        |e, wait| log::warn!("Github request failed, retrying in {:?}: {}", wait, e),
+    )
+    .await
+}
+
+/// Parse the response to a single attempt, telling `send` whether a failure is worth retrying
+async fn attempt<R: octocrab::FromResponse>(
+    response: octocrab::Result<reqwest::Response>,
+    idempotent: bool,
+) -> Result<R, backoff::Error<octocrab::Error>> {
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            let transient = matches!(
+                &e,
+                octocrab::Error::Http { source, .. }
+                    if source.is_connect() || (idempotent && source.is_timeout())
+            );
+            return Err(if transient {
+                backoff::Error::transient(e)
+            } else {
+                backoff::Error::permanent(e)
+            });
+        }
+    };
+    let status = response.status();
+    let retry_after = retry_after(response.headers());
+    // Github answers 403 rather than 429 to most rate limited requests
+    let rate_limited = status == StatusCode::TOO_MANY_REQUESTS
+        || (status == StatusCode::FORBIDDEN && retry_after.is_some());
+    let transient = rate_limited || (idempotent && status.is_server_error());
+    let error = match octocrab::map_github_error(response).await {
+        Ok(response) => {
+            return R::from_response(response)
+                .await
+                .map_err(backoff::Error::permanent)
+        }
+        Err(e) => e,
+    };
+    match retry_after {
+        _ if !transient => Err(backoff::Error::permanent(error)),
+        Some(wait) if wait > MAX_RETRY_AFTER => Err(backoff::Error::permanent(error)),
+        retry_after => Err(backoff::Error::Transient {
+            err: error,
+            retry_after,
+        }),
+    }
+}
+
+/// How long Github asked to wait before trying again, if it did
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header =
+        |name: &str| -> Option<u64> { headers.get(name)?.to_str().ok()?.trim().parse().ok() };
+    if let Some(seconds) = header("retry-after") {
+        return Some(Duration::from_secs(seconds));
+    }
+    // Out of requests until the rate limit resets
+    if header("x-ratelimit-remaining") == Some(0) {
+        let reset = header("x-ratelimit-reset")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        return Some(Duration::from_secs(reset.saturating_sub(now)));
+    }
+    None
+}
@@ -50,4 +50,205 @@ pub mod toml {
 
         Ok(doc.to_string().into_bytes())
     }
+
+    /// The `package.version` of the given Cargo.toml
+    #[rhai_fn(return_raw)]
+    pub fn get_version(toml: Vec<u8>) -> Result<String, Box<rhai::EvalAltResult>> {
+        let doc = parse(toml)?;
+        doc.get("package")
+            .and_then(|package| package.get("version"))
+            .and_then(toml_edit::Item::as_str)
+            .map(String::from)
+            .ok_or_else(|| "No `package.version` string in the toml document".into())
+    }
+
+    /// Set the `package.version` of the given Cargo.toml to a SemVer `version`, e.g. `1.2.3`,
+    /// keeping the rest of the document (and the comments around the version) as is.
+    #[rhai_fn(return_raw)]
+    pub fn set_version(
+        toml: Vec<u8>,
+        version: String,
+    ) -> Result<rhai::Blob, Box<rhai::EvalAltResult>> {
+        semver::Version::parse(&version)
+            .map_err(|e| format!("`{version}` is not a valid SemVer version: {e}"))?;
+        let mut doc = parse(toml)?;
+        // A version inherited from the workspace (`version.workspace = true`) isn't a string
+        let current = match doc
+            .get_mut("package")
+            .and_then(|package| package.get_mut("version"))
+        {
+            Some(toml_edit::Item::Value(current)) if current.is_str() => current,
+            _ => return Err("No `package.version` string in the toml document".into()),
+        };
+        let decor = current.decor().clone();
+        *current = version.into();
+        *current.decor_mut() = decor;
+        Ok(doc.to_string().into_bytes())
+    }
+
+    fn parse(toml: Vec<u8>) -> Result<toml_edit::Document, Box<rhai::EvalAltResult>> {
+        let toml = String::from_utf8(toml).map_err(|_| "toml is invalid UTF8")?;
+        Ok(toml
+            .parse::<toml_edit::Document>()
+            .map_err(|e| format!("Not a valid toml document: {e}"))?)
+    }
+}
+
+#[export_module]
+pub mod sh {
+    /// Quote a string so a shell (or the `cargo` syntax) treats it as a single word.
+    pub fn quote(word: &str) -> String {
+        shell_words::quote(word).into_owned()
+    }
+
+    /// Quote every element of the array and join them with spaces into a single command line.
+    pub fn join(words: rhai::Array) -> String {
+        shell_words::join(words.into_iter().map(|word| word.to_string()))
+    }
+}
+
+#[export_module]
+pub mod time {
+    use std::convert::TryFrom;
+
+    pub type Stopwatch = super::Stopwatch;
+
+    /// Milliseconds since the Unix epoch (wall-clock time)
+    pub fn now_ms() -> rhai::INT {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|since| rhai::INT::try_from(since.as_millis()).unwrap_or(rhai::INT::MAX))
+            .unwrap_or(0)
+    }
+
+    /// Start measuring, read the measurement with `elapsed_ms()`
+    pub fn stopwatch() -> Stopwatch {
+        super::Stopwatch(std::time::Instant::now())
+    }
+
+    /// Milliseconds since the stopwatch was started
+    #[rhai_fn(global, pure)]
+    pub fn elapsed_ms(stopwatch: &mut Stopwatch) -> rhai::INT {
+        rhai::INT::try_from(stopwatch.0.elapsed().as_millis()).unwrap_or(rhai::INT::MAX)
+    }
+}
+
+#[export_module]
+pub mod store {
+    use crate::api::git::LocalRepo;
+
+    /// The value an earlier job of the repository stored under `key`, `()` if there is none. Only
+    /// the job's own repository (`REPO`) has a store.
+    #[rhai_fn(return_raw, pure)]
+    pub fn get(
+        repo: &mut LocalRepo,
+        key: &str,
+    ) -> Result<rhai::Dynamic, Box<rhai::EvalAltResult>> {
+        repo.store_get(key)
+    }
+
+    /// Store the string `value` under `key` for later jobs of the repository
+    #[rhai_fn(return_raw, pure)]
+    pub fn put(
+        repo: &mut LocalRepo,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        repo.store_put(key, value)
+    }
+}
+
+#[export_module]
+pub mod data {
+    use crate::api::git::LocalRepo;
+
+    /// Contents of the file at `path` (relative) in the data directory of the host. Only the
+    /// job's own repository (`REPO`) can read it.
+    #[rhai_fn(return_raw, pure)]
+    pub fn read(repo: &mut LocalRepo, path: &str) -> Result<rhai::Blob, Box<rhai::EvalAltResult>> {
+        repo.data_read(path)
+    }
+}
+
+/// Returned by `time::stopwatch()`, uses a monotonic clock so it isn't affected by changes of the
+/// system time
+#[derive(Clone, Copy, Debug)]
+pub struct Stopwatch(std::time::Instant);
+
+#[export_module]
+pub mod md {
+    /// Render the given headers and rows (an array of arrays) as a GitHub-Flavored Markdown table.
+    #[rhai_fn(return_raw)]
+    pub fn table(headers: rhai::Array, rows: rhai::Array) -> Result<String, Box<rhai::EvalAltResult>> {
+        super::markdown_table(headers, rows)
+    }
+}
+
+/// Render a GitHub-Flavored Markdown table. Columns are padded to the same width so the raw
+/// comment stays readable, `|` in cells is escaped and newlines are replaced with `<br>` (GFM
+/// tables can't span multiple lines).
+pub(crate) fn markdown_table(
+    headers: rhai::Array,
+    rows: rhai::Array,
+) -> Result<String, Box<rhai::EvalAltResult>> {
+    fn cell(value: rhai::Dynamic) -> String {
+        value
+            .to_string()
+            .replace('|', "\\|")
+            .replace("\r\n", "<br>")
+            .replace('\n', "<br>")
+    }
+
+    if headers.is_empty() {
+        return Err("A markdown table needs at least one header".into());
+    }
+    let headers: Vec<String> = headers.into_iter().map(cell).collect();
+    let rows = rows
+        .into_iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let row = row
+                .try_cast::<rhai::Array>()
+                .ok_or(format!("Row {i} of the markdown table is not an array"))?;
+            if row.len() > headers.len() {
+                return Err(format!(
+                    "Row {i} of the markdown table has {} cells but there are only {} headers",
+                    row.len(),
+                    headers.len()
+                ));
+            }
+            let mut row: Vec<String> = row.into_iter().map(cell).collect();
+            row.resize(headers.len(), String::new());
+            Ok(row)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // GFM requires at least three dashes in the delimiter row
+    let widths: Vec<usize> = (0..headers.len())
+        .map(|col| {
+            rows.iter()
+                .map(|row| row[col].chars().count())
+                .chain(std::iter::once(headers[col].chars().count()))
+                .chain(std::iter::once(3))
+                .max()
+                .unwrap_or(3)
+        })
+        .collect();
+
+    let line = |cells: &[String]| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        format!("| {} |\n", cells.join(" | "))
+    };
+
+    let mut table = line(&headers);
+    let delimiters: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    table.push_str(&line(&delimiters));
+    for row in &rows {
+        table.push_str(&line(row));
+    }
+    Ok(table)
 }
@@ -1,11 +1,25 @@
 use anyhow::Result;
-use octocrab::Octocrab;
+use ci_script::api::{
+    auth::{AppAuth, AuthProvider, PatAuth},
+    cargo::Cargo,
+    git::GpgSigner,
+    github,
+};
+use ci_script::job::{CargoLog, CargoRecord, QueuedJob};
+use ci_script::repo_config::RepoConfig;
+use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
+use std::path::Path;
+use std::sync::Arc;
 use structopt::StructOpt;
 use thiserror::Error;
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "ci-scripts", about = "Run CI scripts, like from a CI/CD job")]
+#[structopt(
+    name = "ci-scripts",
+    about = "Run CI scripts, like from a CI/CD job",
+    setting = structopt::clap::AppSettings::SubcommandsNegateReqs
+)]
 struct Opt {
     /// Path to the repository
     #[structopt(long, env, default_value = "./")]
@@ -14,93 +28,430 @@ struct Opt {
     #[structopt(long, env, default_value = "/tmp")]
     clone_dir: std::path::PathBuf,
     /// Github App ID
-    #[structopt(long, env)]
-    github_app_id: u64,
+    #[structopt(long, env, required_unless_one = &["list", "check", "github-token"])]
+    github_app_id: Option<u64>,
     /// Github App key
-    #[structopt(long, env, hide_env_values = true)]
-    github_app_key: String,
+    #[structopt(long, env, hide_env_values = true, required_unless_one = &["list", "check", "github-token"])]
+    github_app_key: Option<String>,
+    /// Github personal access token, to use instead of authenticating as a Github App
+    #[structopt(long, env, hide_env_values = true, conflicts_with_all = &["github-app-id", "github-app-key"])]
+    github_token: Option<String>,
     /// Owner of the upstream Github repository
-    #[structopt(long, env)]
-    github_owner: String,
+    #[structopt(long, env, required_unless_one = &["list", "check"])]
+    github_owner: Option<String>,
     /// Name of the upstream Github repository
+    #[structopt(long, env, required_unless_one = &["list", "check"])]
+    github_name: Option<String>,
+    /// Github API URL, e.g. `https://github.example.com/api/v3/` for Github Enterprise
+    #[structopt(long, env, default_value = "https://api.github.com/")]
+    github_api_url: url::Url,
+    /// User agent for Github API requests, defaults to `ci-script/<version>` (with the app ID when
+    /// authenticating as a Github App)
+    #[structopt(long, env)]
+    github_user_agent: Option<String>,
+    /// GPG key (id, fingerprint or user id in `gpg`'s keyring) to sign the commits of the script
+    /// with, commits are unsigned without it
+    #[structopt(long, env)]
+    signing_key: Option<String>,
+    /// File holding the passphrase of `--signing-key`
+    #[structopt(long, env, requires = "signing-key")]
+    signing_key_passphrase_file: Option<std::path::PathBuf>,
+    /// Directory to keep the values scripts `store` between runs in
+    #[structopt(long, env)]
+    store_dir: Option<std::path::PathBuf>,
+    /// Directory of files on the host scripts can read with `data::read`
+    #[structopt(long, env)]
+    data_dir: Option<std::path::PathBuf>,
+    /// Scripts (comma separated, as the directory below `.github` and the script name without
+    /// `.rhai`, like `bench/lint`) that may only look at the repository: writing, committing,
+    /// pushing and `cargo` fail
+    #[structopt(long, env, use_delimiter = true)]
+    read_only_scripts: Vec<String>,
+    /// Template of the messages of commits scripts make with `commit_templated`, with
+    /// placeholders like `{message}`, `{command}`, `{issue}`, `{user}`, `{repo}` and `{sha}`
+    #[structopt(long, env)]
+    commit_template: Option<String>,
+    /// Path of the cargo binary the script runs, `cargo` from `PATH` by default
     #[structopt(long, env)]
-    github_name: String,
+    cargo_path: Option<std::path::PathBuf>,
+    /// Rustup toolchain (like `nightly`) the script runs cargo with, passed to cargo as
+    /// `+<toolchain>`
+    #[structopt(long, env)]
+    cargo_toolchain: Option<String>,
+    /// List the scripts available in the repository instead of running one
+    #[structopt(long)]
+    list: bool,
+    /// Compile every script in the repository without running any, failing if one doesn't
+    /// compile
+    #[structopt(long, conflicts_with = "list")]
+    check: bool,
     /// Path to the script to execute relative to the root of the script's repository
-    #[structopt(env)]
-    script: std::path::PathBuf,
+    #[structopt(env, required_unless_one = &["list", "check"])]
+    script: Option<std::path::PathBuf>,
     /// Arguments to pass to the script
     #[structopt(env)]
     script_args: Vec<String>,
+    /// Write a JSON summary of the run (the script, its duration, whether it succeeded and the
+    /// `cargo` commands it ran) to this file
+    #[structopt(long, env)]
+    output: Option<std::path::PathBuf>,
     /// Log level
     #[structopt(short, long, env, default_value = "info")]
     log_level: log::LevelFilter,
+    #[structopt(subcommand)]
+    command: Option<Subcommand>,
 }
 
+#[derive(Debug, StructOpt)]
+enum Subcommand {
+    /// Inspect the queue of a running `cis-gh-reactor`
+    Queue(QueueCommand),
+}
+
+#[derive(Debug, StructOpt)]
+enum QueueCommand {
+    /// List the queued jobs, next to run first
+    Ls {
+        #[structopt(flatten)]
+        server: Server,
+    },
+    /// Take a job out of the queue, or cancel it if it's already running
+    Cancel {
+        #[structopt(flatten)]
+        server: Server,
+        /// Id of the job
+        id: String,
+        /// The server's `--admin-token`
+        #[structopt(long, env = "CIS_ADMIN_TOKEN", hide_env_values = true)]
+        admin_token: String,
+    },
+    /// Drop all queued jobs (running jobs keep running)
+    Clear {
+        #[structopt(flatten)]
+        server: Server,
+        /// The server's `--admin-token`
+        #[structopt(long, env = "CIS_ADMIN_TOKEN", hide_env_values = true)]
+        admin_token: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+struct Server {
+    /// URL of the `cis-gh-reactor` server
+    #[structopt(
+        long = "server",
+        env = "CIS_SERVER",
+        default_value = "http://localhost:3000"
+    )]
+    url: url::Url,
+}
+
+// Exit codes, so CI jobs can tell the different kinds of failures apart
+const EXIT_FAILURE: i32 = 1;
+const EXIT_SCRIPT_NOT_FOUND: i32 = 2;
+const EXIT_SCRIPT_ERROR: i32 = 3;
+const EXIT_AUTHENTICATION_FAILURE: i32 = 4;
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     let opt = Opt::from_args();
     pretty_env_logger::formatted_timed_builder()
         .filter(None, opt.log_level)
         .init();
 
-    let master_client = get_github_client(opt.github_app_id, &opt.github_app_key)?;
-    let gh_client =
-        get_github_repo_client(&master_client, &opt.github_owner, &opt.github_name).await?;
-    let gh_repo = get_github_repo(&gh_client, &opt.github_owner, &opt.github_name).await?;
-    let command: Vec<String> = {
-        let mut x = vec![opt.script.to_string_lossy().into_owned()];
-        x.extend(opt.script_args);
-        x
+    if let Err(err) = run(opt).await {
+        eprintln!("Error: {err:?}");
+        std::process::exit(exit_code(&err));
+    }
+}
+
+fn exit_code(err: &anyhow::Error) -> i32 {
+    use ci_script::job::Error as JobError;
+    match (err.downcast_ref::<JobError>(), err.downcast_ref::<Error>()) {
+        (Some(JobError::ScriptNotFound(_)), _) => EXIT_SCRIPT_NOT_FOUND,
+        (Some(JobError::ScriptExecution(_)), _) | (Some(JobError::ScriptParse(_)), _) => {
+            EXIT_SCRIPT_ERROR
+        }
+        (_, Some(Error::Check(_))) => EXIT_SCRIPT_ERROR,
+        (_, Some(Error::Authentication(_))) => EXIT_AUTHENTICATION_FAILURE,
+        _ => EXIT_FAILURE,
+    }
+}
+
+async fn run(mut opt: Opt) -> Result<()> {
+    if let Some(Subcommand::Queue(command)) = opt.command.take() {
+        return queue(command).await;
+    }
+
+    if opt.list {
+        for (dir, name) in ci_script::job::list_commands(&opt.repo)? {
+            let script = Path::new(".github").join(dir).join(name).with_extension("rhai");
+            println!("{}", script.display());
+        }
+        return Ok(());
+    }
+
+    if opt.check {
+        return check(&opt.repo);
+    }
+
+    // These are all required unless `--list` or `--check` is given
+    let (github_owner, github_name, script) = match opt {
+        Opt {
+            github_owner: Some(github_owner),
+            github_name: Some(github_name),
+            script: Some(script),
+            ..
+        } => (github_owner, github_name, script),
+        _ => return Err(Error::MissingArguments.into()),
     };
+
+    let command = ci_script::Command::from_script_path(script, opt.script_args)?;
+    let script_path = command.script_path();
+
+    let auth: Arc<dyn AuthProvider> =
+        match (opt.github_token, opt.github_app_id, opt.github_app_key) {
+            (Some(token), _, _) => {
+                let auth = PatAuth::new(token, opt.github_api_url);
+                match opt.github_user_agent {
+                    Some(user_agent) => Arc::new(auth.with_user_agent(user_agent)),
+                    None => Arc::new(auth),
+                }
+            }
+            (None, Some(app_id), Some(app_key)) => {
+                let auth = AppAuth::new(app_id, &app_key, opt.github_api_url)
+                    .map_err(|e| Error::Authentication(e.into()))?;
+                match opt.github_user_agent {
+                    Some(user_agent) => Arc::new(auth.with_user_agent(user_agent)),
+                    None => Arc::new(auth),
+                }
+            }
+            _ => return Err(Error::MissingArguments.into()),
+        };
+    let gh_client = auth
+        .client(&format!("{github_owner}/{github_name}"))
+        .map_err(|e| Error::Authentication(e.into()))?;
+    let gh_repo = get_github_repo(&gh_client, &github_owner, &github_name).await?;
     let dir = std::fs::canonicalize(&opt.repo)?;
+    let passphrase_file = opt.signing_key_passphrase_file;
+    let read_only = opt.read_only_scripts.contains(&command.qualified_name());
+    // Run locally, the checkout is trusted like the rest of the command
+    let repo_config = RepoConfig::load(&dir)?;
     let job = ci_script::job::CheckedoutJob {
         command,
         dir,
+        base_dir: None,
         clone_dir: opt.clone_dir,
+        clone_limit: None,
         gh_repo,
         gh_issue: None,
+        gh_user: None,
+        head_sha: None,
+        pr_head: None,
+        signer: opt.signing_key.map(|key| Arc::new(GpgSigner { key, passphrase_file })),
+        store_dir: opt.store_dir,
+        data_dir: opt.data_dir,
+        cargo: Cargo {
+            path: opt.cargo_path,
+            toolchain: opt.cargo_toolchain,
+        },
+        commit_template: opt.commit_template,
+        checkout_duration: None,
+        read_only,
+        repo_config,
+    };
+    let engine = Arc::new(ci_script::job::build_engine()?);
+    let script = job.prepare_script(Arc::new(github::Client::new(auth)), engine)?;
+    let output = match opt.output {
+        Some(output) => output,
+        None => {
+            if let Some(message) = script.run()? {
+                println!("{message}");
+            }
+            return Ok(());
+        }
     };
-    job.prepare_script(master_client)?.run()?;
+
+    let cargo_log = CargoLog::default();
+    let started = std::time::Instant::now();
+    let result = script.record_cargo(cargo_log.clone()).run();
+    let summary = RunSummary {
+        script: script_path,
+        duration_ms: started.elapsed().as_millis().try_into().unwrap_or(u64::MAX),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+        message: result.as_ref().ok().cloned().flatten(),
+        cargo: cargo_log
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone(),
+    };
+    std::fs::write(&output, serde_json::to_vec_pretty(&summary)?)
+        .map_err(|e| Error::Output(output, e))?;
+    if let Some(message) = result? {
+        println!("{message}");
+    }
     Ok(())
 }
 
-fn get_github_client<K: ToString>(github_app_id: u64, github_app_key: K) -> Result<Octocrab> {
-    let github_app_key = github_app_key.to_string();
-    let token = {
-        let app_id = octocrab::models::AppId::from(github_app_id);
-        let app_key = jsonwebtoken::EncodingKey::from_rsa_pem(github_app_key.as_bytes())?;
-        octocrab::auth::create_jwt(app_id, &app_key)?
-    };
-    Ok(Octocrab::builder().personal_token(token).build()?)
+/// Summary of a run, see `--output`
+#[derive(Serialize)]
+struct RunSummary {
+    /// Path of the script relative to the repository
+    script: std::path::PathBuf,
+    duration_ms: u64,
+    success: bool,
+    /// Why the script failed
+    error: Option<String>,
+    /// The message the script ended with, see `finish`
+    message: Option<String>,
+    cargo: Vec<CargoRecord>,
 }
 
 #[derive(Error, Debug)]
 enum Error {
-    #[error("Failed to acquire access token URL")]
-    NoAccessTokenURL,
+    #[error("Failed to authenticate with Github: {0}")]
+    Authentication(anyhow::Error),
+    #[error("Missing required arguments")]
+    MissingArguments,
+    #[error("{0} of the scripts failed to compile")]
+    Check(usize),
+    #[error("Failed to write the summary to {0:?}: {1}")]
+    Output(std::path::PathBuf, std::io::Error),
+    #[error("Server responded with {status}: {error}")]
+    Server { status: u16, error: String },
 }
 
-async fn get_github_repo_client<O: AsRef<str>, N: AsRef<str>>(
-    gh_client: &octocrab::Octocrab,
-    _owner: O,
-    _name: N,
-) -> Result<octocrab::Octocrab> {
-    // TODO: Consider requesting a token with more fine-grained access.
-    // TODO: Figure out what installation to use instead of hardcoding
-    use octocrab::params::apps::CreateInstallationAccessToken;
-    let installations = gh_client.apps().installations().send().await?.take_items();
-    let mut access_token_req = CreateInstallationAccessToken::default();
-    access_token_req.repositories = vec![];
-    let access_token_url = installations[0]
-        .access_tokens_url
-        .as_ref()
-        .ok_or(Error::NoAccessTokenURL)?;
-    let access: octocrab::models::InstallationToken = gh_client
-        .post(access_token_url, Some(&access_token_req))
-        .await?;
-    Ok(octocrab::OctocrabBuilder::new()
-        .personal_token(access.token)
-        .build()?)
+/// Compile the scripts of the repository at `repo` with the engine jobs use, printing the result
+/// for each
+fn check(repo: &Path) -> Result<()> {
+    let engine = ci_script::job::build_engine()?;
+    let mut failed = 0;
+    for (dir, name) in ci_script::job::list_commands(repo)? {
+        let script = Path::new(".github").join(dir).join(name).with_extension("rhai");
+        match engine.compile_file(repo.join(&script)) {
+            Ok(_) => println!("ok     {}", script.display()),
+            Err(e) => {
+                println!("FAILED {}: {e}", script.display());
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(Error::Check(failed).into());
+    }
+    Ok(())
+}
+
+async fn queue(command: QueueCommand) -> Result<()> {
+    match command {
+        QueueCommand::Ls { server } => {
+            let res = surf::get(server.endpoint("queue"))
+                .await
+                .map_err(|e| e.into_inner())?;
+            let jobs: Vec<QueuedJob> = response_json(res).await?;
+            print_jobs(&jobs);
+        }
+        QueueCommand::Cancel {
+            server,
+            id,
+            admin_token,
+        } => {
+            #[derive(Deserialize)]
+            struct Cancelled {
+                was: String,
+            }
+
+            let res = surf::post(server.endpoint(&format!("queue/{id}/cancel")))
+                .header("Authorization", format!("Bearer {admin_token}"))
+                .await
+                .map_err(|e| e.into_inner())?;
+            let Cancelled { was } = response_json(res).await?;
+            if was == "running" {
+                println!("Cancelling running job {id}");
+            } else {
+                println!("Removed job {id} from the queue");
+            }
+        }
+        QueueCommand::Clear {
+            server,
+            admin_token,
+        } => {
+            #[derive(Deserialize)]
+            struct Cleared {
+                cleared: usize,
+            }
+
+            let res = surf::post(server.endpoint("queue/clear"))
+                .header("Authorization", format!("Bearer {admin_token}"))
+                .await
+                .map_err(|e| e.into_inner())?;
+            let Cleared { cleared } = response_json(res).await?;
+            println!("Dropped {cleared} queued jobs");
+        }
+    }
+    Ok(())
+}
+
+impl Server {
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/{path}", self.url.as_str().trim_end_matches('/'))
+    }
+}
+
+/// The JSON body of a successful response, or the server's error message
+async fn response_json<T: serde::de::DeserializeOwned>(mut res: surf::Response) -> Result<T> {
+    if !res.status().is_success() {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            error: String,
+        }
+
+        let error = match res.body_json::<ErrorBody>().await {
+            Ok(ErrorBody { error }) => error,
+            Err(_) => res.status().canonical_reason().into(),
+        };
+        return Err(Error::Server {
+            status: res.status().into(),
+            error,
+        }
+        .into());
+    }
+    res.body_json().await.map_err(|e| e.into_inner())
+}
+
+/// Print the jobs as a table with aligned columns
+fn print_jobs(jobs: &[QueuedJob]) {
+    if jobs.is_empty() {
+        println!("The queue is empty");
+        return;
+    }
+    let header = ["POSITION", "ID", "REPOSITORY", "ISSUE", "COMMAND"].map(String::from);
+    let rows: Vec<[String; 5]> = std::iter::once(header)
+        .chain(jobs.iter().map(|job| {
+            [
+                job.position.to_string(),
+                job.id.clone(),
+                job.repository.clone(),
+                job.issue.to_string(),
+                job.command.clone(),
+            ]
+        }))
+        .collect();
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in &rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
 }
 
 async fn get_github_repo<O: AsRef<str>, N: AsRef<str>>(
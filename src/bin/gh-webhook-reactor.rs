@@ -1,9 +1,25 @@
 use async_std::sync::{Arc, Mutex};
-use ci_script::{job::Repository, Job, LocalQueue, Queue};
-use octocrab::params::apps::CreateInstallationAccessToken;
-use octocrab::Octocrab;
+use backoff::backoff::Backoff;
+use ci_script::{
+    api::{
+        auth::{AppAuth, AuthProvider},
+        cargo::Cargo,
+        git::GpgSigner,
+        github::{self, Github},
+    },
+    cache::{Limits, RepoCache},
+    job::{CheckoutOptions, Hooks, JobResult, PrRef, PullRequestHead, QueuedJob, Repository},
+    log_buffer::{BufferedLogger, LogBuffer},
+    semaphore::Semaphore,
+    Broadcast, Command, Job, LocalQueue, Queue,
+};
+use indexmap::IndexMap;
+use octocrab::models::{issues::Issue, RepositoryId, User};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use thiserror::Error;
 use tide::prelude::*;
@@ -15,38 +31,1156 @@ use tide_github::Event;
     about = "Simply automate your CI needs with the powers of the CI Scripting Language"
 )]
 struct Config {
+    /// `.env` file to set environment variables from before reading the other options. Variables
+    /// that are already set are kept.
+    #[structopt(long, env)]
+    env_file: Option<PathBuf>,
     /// Github Webhook secret
-    #[structopt(short, long, env, hide_env_values = true)]
-    webhook_secret: String,
+    #[structopt(
+        short,
+        long,
+        env,
+        hide_env_values = true,
+        required_unless = "webhook-secret-file"
+    )]
+    webhook_secret: Option<String>,
+    /// File holding the Github Webhook secret, which keeps it out of the environment and process
+    /// listings. Takes precedence over --webhook-secret.
+    #[structopt(long, env)]
+    webhook_secret_file: Option<PathBuf>,
+    /// Shared secret for admin routes like `POST /queue/clear`, which require it as
+    /// `Authorization: Bearer <token>`. They're disabled without it.
+    #[structopt(long, env, hide_env_values = true)]
+    admin_token: Option<String>,
+    /// File holding the admin token, takes precedence over --admin-token
+    #[structopt(long, env)]
+    admin_token_file: Option<PathBuf>,
     /// Github App ID
     #[structopt(long, env)]
     app_id: u64,
     /// Github App key
-    #[structopt(long, env, hide_env_values = true)]
-    app_key: String,
+    #[structopt(long, env, hide_env_values = true, required_unless = "app-key-file")]
+    app_key: Option<String>,
+    /// File holding the Github App key (PEM), which keeps it out of the environment and process
+    /// listings. Takes precedence over --app-key.
+    #[structopt(long, env)]
+    app_key_file: Option<PathBuf>,
+    /// Github API URL, e.g. `https://github.example.com/api/v3/` for Github Enterprise
+    #[structopt(long, env, default_value = "https://api.github.com/")]
+    github_api_url: url::Url,
+    /// User agent for Github API requests, defaults to `ci-script/<version> (Github App <app id>)`
+    #[structopt(long, env)]
+    github_user_agent: Option<String>,
     /// Port to listen on
     #[structopt(short, long, env, default_value = "3000")]
     port: u16,
     /// Address to listen on
     #[structopt(short, long, env, default_value = "127.0.0.1")]
     address: String,
+    /// Where to listen, as `host:port` or `unix:/path/to.sock` for a Unix socket (e.g. behind a
+    /// reverse proxy on the same host). Takes precedence over --address and --port.
+    #[structopt(long, env)]
+    listen: Option<Listen>,
     /// Log level
     #[structopt(short, long, env, default_value = "info")]
     log_level: log::LevelFilter,
-    /// Bot command prefix
-    #[structopt(short, long, env, default_value = "/benchbot")]
-    command_prefix: String,
+    /// Number of the most recent log lines to keep in memory for `/logs`
+    #[structopt(long, env, default_value = "1000")]
+    log_buffer_lines: usize,
+    /// Bot command prefixes (comma separated). The first one is canonical and determines the
+    /// script directory, the others are aliases for it.
+    #[structopt(short, long, env, default_value = "/benchbot", use_delimiter = true)]
+    command_prefix: Vec<String>,
+    /// Only act on commands in these repositories (comma separated `owner/name` patterns, where
+    /// `*` matches any characters, like `myorg/*`). Commands in all repositories the App is
+    /// installed on are handled without it.
+    #[structopt(long, env, use_delimiter = true)]
+    repos_allowlist: Vec<String>,
     /// Repositories root working directory
     #[structopt(short, long, env, default_value = "./repos")]
     repos_root: PathBuf,
+    /// Initialize and update git submodules after checking out a repository
+    #[structopt(long)]
+    submodules: bool,
+    /// Recursively update nested git submodules (implies --submodules)
+    #[structopt(long)]
+    recursive_submodules: bool,
+    /// Perform shallow clones and fetches of this many commits
+    #[structopt(long, env)]
+    clone_depth: Option<i32>,
+    /// Maximum number of clones and fetches (including those of scripts) to run at the same time
+    #[structopt(long, env)]
+    max_concurrent_clones: Option<std::num::NonZeroUsize>,
+    /// Keep ignored files (like `target/`) between checkouts to reuse build caches
+    #[structopt(long)]
+    keep_ignored: bool,
+    /// Also check out the merge base of the pull request with the default branch, so scripts can
+    /// compare against it through `BASE_REPO`
+    #[structopt(long)]
+    compare_base: bool,
+    /// Keep the working directory of a failed job for debugging, by moving it to `<dir>_failed`
+    /// (replacing the one of an earlier failure) rather than resetting it for the next job
+    #[structopt(long)]
+    keep_workdir_on_failure: bool,
+    /// Reject commands on an issue while a job of the same issue is still running (including
+    /// timed out jobs that didn't stop yet), rather than letting them share its working directory
+    #[structopt(long)]
+    one_job_per_issue: bool,
+    /// Only receive webhooks and serve the queue, without running jobs in this process. Jobs are
+    /// run by separate runners taking them from `/queue/remove`.
+    #[structopt(long)]
+    no_runner: bool,
+    /// Only run jobs given this label with a command's `--label=<label>` option in this process,
+    /// or only jobs without a label if empty. Without it, jobs of any label are run.
+    #[structopt(long, env)]
+    runner_label: Option<String>,
+    /// Check that a job's pull request is still open before running the job, and skip it if the
+    /// pull request was closed or merged since the command was given (one more API request per
+    /// job)
+    #[structopt(long)]
+    skip_closed: bool,
+    /// Pull request ref to check out: `head` for the tip of its branch, or `merge` for the result
+    /// of merging it into its base branch (jobs fail if it can't be merged)
+    #[structopt(long, env, default_value = "head", possible_values = &["head", "merge"])]
+    pr_ref: PrRef,
+    /// Script to run (without `.rhai`) when a command doesn't name one
+    #[structopt(long, env, default_value = "main")]
+    default_script: String,
+    /// Maximum number of job results to keep, older results are dropped first
+    #[structopt(long, env, default_value = "100")]
+    max_results: usize,
+    /// Maximum number of jobs waiting in the queue, commands are rejected when it's full
+    #[structopt(long, env)]
+    max_queue_len: Option<usize>,
+    /// Maximum number of jobs a single repository may enqueue per minute
+    #[structopt(long, env)]
+    rate_limit: Option<usize>,
+    /// Maximum duration of a job (checkout and script), in seconds or like `30m`
+    #[structopt(long, env, parse(try_from_str = ci_script::units::parse_duration))]
+    job_timeout: Option<Duration>,
+    /// Maximum number of repository working directories to keep, least recently used ones are
+    /// deleted first
+    #[structopt(long, env)]
+    max_repo_dirs: Option<usize>,
+    /// Maximum combined size of the repository working directories to keep (in bytes or like
+    /// `50GB`), least recently used ones are deleted first
+    #[structopt(long, env, parse(try_from_str = ci_script::units::parse_size))]
+    max_repos_size: Option<u64>,
+    /// Maximum size of a repository (as reported by Github) to run commands for, in bytes or like
+    /// `500MB`. Larger repositories are refused before they're cloned.
+    #[structopt(long, env, parse(try_from_str = ci_script::units::parse_size))]
+    max_clone_size: Option<u64>,
+    /// Script on this host to run before every job's script, with the same scope
+    #[structopt(long, env)]
+    pre_script: Option<PathBuf>,
+    /// Script on this host to run after every job's script, even if the job failed
+    #[structopt(long, env)]
+    post_script: Option<PathBuf>,
+    /// GPG key (id, fingerprint or user id in `gpg`'s keyring) to sign the commits of scripts
+    /// with, commits are unsigned without it
+    #[structopt(long, env)]
+    signing_key: Option<String>,
+    /// File holding the passphrase of `--signing-key`
+    #[structopt(long, env, requires = "signing-key")]
+    signing_key_passphrase_file: Option<PathBuf>,
+    /// Directory to keep the values scripts `store` between jobs in, with a file per repository
+    #[structopt(long, env)]
+    store_dir: Option<PathBuf>,
+    /// Directory of files on the host (like shared baselines) scripts can read with `data::read`
+    #[structopt(long, env)]
+    data_dir: Option<PathBuf>,
+    /// Scripts (comma separated, as the command directory below `.github` and the script name
+    /// without `.rhai`, like `bench/lint`) that may only look at the repository: writing,
+    /// committing, pushing and `cargo` fail. Use it for commands untrusted users can give.
+    #[structopt(long, env, use_delimiter = true)]
+    read_only_scripts: Vec<String>,
+    /// Template of the messages of commits scripts make with `commit_templated`, with
+    /// placeholders like `{message}`, `{command}`, `{issue}`, `{user}`, `{repo}` and `{sha}`
+    #[structopt(long, env)]
+    commit_template: Option<String>,
+    /// Path of the cargo binary scripts run, `cargo` from `PATH` by default
+    #[structopt(long, env)]
+    cargo_path: Option<PathBuf>,
+    /// Rustup toolchain (like `nightly`) scripts run cargo with, passed to cargo as `+<toolchain>`
+    #[structopt(long, env)]
+    cargo_toolchain: Option<String>,
+    /// URL to POST a JSON summary of every finished job to (like a Slack incoming webhook)
+    #[structopt(long, env)]
+    notify_url: Option<url::Url>,
+}
+
+/// Where the server listens, see `Config::listen`
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Listen {
+    /// `host:port`
+    Tcp(String),
+    /// Path of a Unix socket
+    Unix(PathBuf),
 }
 
-type State = Arc<Mutex<LocalQueue<String, Job>>>;
+impl std::str::FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("Missing Unix socket path after `unix:`".into()),
+            Some(path) => Ok(Listen::Unix(path.into())),
+            None => match s.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(Listen::Tcp(s.into()))
+                }
+                _ => Err(format!(
+                    "Invalid listen address `{s}`, expected `host:port` or `unix:/path/to.sock`"
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+struct State {
+    // This is `async_std`'s `Mutex`, which (unlike `std::sync::Mutex`) is not poisoned when a
+    // holder panics, so a panic while holding the lock can't wedge the queue for later requests.
+    queue: Arc<Mutex<LocalQueue<String, Job>>>,
+    /// Results of finished jobs, oldest first
+    results: Arc<Mutex<IndexMap<String, JobResult>>>,
+    max_results: usize,
+    /// Whether the background task processing jobs is still running
+    runner_alive: Arc<AtomicBool>,
+    /// Whether the server runs without that task, see `Config::no_runner`
+    no_runner: bool,
+    /// Whether the Github App credentials were accepted by Github at startup
+    github_authenticated: Arc<AtomicBool>,
+    /// Recently handled webhook delivery ids, oldest first
+    deliveries: Arc<Mutex<IndexMap<String, Instant>>>,
+    /// Lifecycle events of all jobs, streamed by `/events`
+    events: Arc<Mutex<Broadcast<JobEvent>>>,
+    /// `Started` events of jobs that didn't report a result yet, oldest first
+    running: Arc<Mutex<IndexMap<String, JobEvent>>>,
+    cancellations: Cancellations,
+    /// When the server started, for the uptime in `VersionInfo`
+    started: Instant,
+    /// See `Config::admin_token`
+    admin_token: Option<String>,
+    /// Recent log lines, served by `/logs`
+    logs: Arc<LogBuffer>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Phase {
+    Enqueued,
+    Started,
+    Finished,
+    Failed,
+    /// Taken out of the queue before it started
+    Cancelled,
+}
+
+/// A job entering a new `Phase`
+#[derive(Clone, Debug, Serialize)]
+struct JobEvent {
+    id: String,
+    /// `owner/name` of the repository
+    repository: String,
+    /// Number of the issue or pull request the command was given on
+    issue: i64,
+    command: String,
+    phase: Phase,
+}
+
+impl JobEvent {
+    fn new(job: &Job, phase: Phase) -> Self {
+        JobEvent {
+            id: job.id.clone(),
+            repository: format!("{}/{}", job.repository.owner.login, job.repository.name),
+            issue: job.issue.number,
+            command: job.command.to_string(),
+            phase,
+        }
+    }
+}
+
+/// Summary of a finished job, sent to `--notify-url`
+#[derive(Debug, Serialize)]
+struct Notification {
+    #[serde(flatten)]
+    event: JobEvent,
+    /// Link to the issue or pull request
+    url: String,
+    /// The summary as a single line, which Slack shows as the message
+    text: String,
+}
+
+impl Notification {
+    fn new(event: JobEvent, url: String, success: bool) -> Self {
+        let (phase, outcome) = if success {
+            (Phase::Finished, "succeeded")
+        } else {
+            (Phase::Failed, "failed")
+        };
+        let text = format!(
+            "`{}` {} on {}#{}: {}",
+            event.command, outcome, event.repository, event.issue, url
+        );
+        Notification {
+            event: JobEvent { phase, ..event },
+            url,
+            text,
+        }
+    }
+}
+
+impl State {
+    /// Record that `job` was handed to a runner
+    async fn job_started(&self, job: &Job) {
+        let event = JobEvent::new(job, Phase::Started);
+        let mut running = self.running.lock().await;
+        running.insert(job.id.clone(), event.clone());
+        // Runners that die don't report a result, don't keep their jobs around forever
+        while running.len() > self.max_results {
+            running.shift_remove_index(0);
+        }
+        self.events.lock().await.send(event);
+    }
+}
+
+/// What the job runner needs to run any job, cloned into the thread of every job
+#[derive(Clone)]
+struct Runner {
+    repos_root: PathBuf,
+    checkout_options: CheckoutOptions,
+    hooks: Hooks,
+    /// Prefix of the commands listed in help comments and missing script messages
+    command_prefix: String,
+    auth: Arc<dyn AuthProvider>,
+    /// The engine only contains the script API, per-job state is passed through the scope
+    engine: Arc<rhai::Engine>,
+    repo_cache: Arc<std::sync::Mutex<RepoCache>>,
+    /// Runtime to check whether pull requests were closed with, see `Config::skip_closed`
+    skip_closed: Option<tokio::runtime::Handle>,
+}
+
+impl Runner {
+    /// Run `job`, stopping once `cancelled` is set. Returns a message to comment on the issue, if
+    /// any.
+    fn run(&self, job: Job, cancelled: Arc<AtomicBool>) -> anyhow::Result<Option<String>> {
+        if let Some(rt) = self
+            .skip_closed
+            .as_ref()
+            .filter(|_| job.issue.pull_request.is_some())
+        {
+            match is_closed(rt, self.auth.as_ref(), &job) {
+                Ok(true) => {
+                    log::info!(
+                        "Skipping command {}, the pull request is closed",
+                        job.command
+                    );
+                    return Ok(Some(format!(
+                        "Skipping `{}`, the pull request is closed",
+                        job.command
+                    )));
+                }
+                Ok(false) => {}
+                // Only saves runner time, so the job runs when it can't be checked
+                Err(e) => log::warn!("Failed to check whether the pull request is open: {e}"),
+            }
+        }
+        // Keeps the working directory from being evicted while the job runs, including when
+        // it times out and is still stopping in the background
+        let _lease = RepoCache::lease(&self.repo_cache, job.repo_dir(&self.repos_root));
+        let _base_lease = self
+            .checkout_options
+            .compare_base
+            .then(|| RepoCache::lease(&self.repo_cache, job.base_repo_dir(&self.repos_root)));
+        if let Err(e) = self
+            .repo_cache
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .evict()
+        {
+            log::warn!("Failed to evict repositories: {e}");
+        }
+        let job = job.checkout(&self.repos_root, &self.checkout_options)?;
+        if job.is_help() {
+            let commands = ci_script::job::list_commands(&job.dir)?;
+            return Ok(Some(help_comment(&self.command_prefix, &commands)));
+        }
+        let dir = job.dir.clone();
+        let res = job
+            .prepare_script(
+                Arc::new(github::Client::new(self.auth.clone())),
+                self.engine.clone(),
+            )?
+            .cancel_token(cancelled)
+            .hooks(self.hooks.clone())
+            .run();
+        match res {
+            Err(ci_script::job::Error::ScriptNotFound(script)) => {
+                // Missing `.github` directories just mean there are no commands
+                let commands = ci_script::job::list_commands(&dir).unwrap_or_default();
+                let message = missing_script_message(&self.command_prefix, &script, &commands);
+                Err(Error::MissingScript(message).into())
+            }
+            // A script that called `finish` has its message posted as a comment
+            res => Ok(res?),
+        }
+    }
+}
+
+/// Whether the issue or pull request of `job` was closed (or merged) since the job was queued
+fn is_closed(
+    rt: &tokio::runtime::Handle,
+    auth: &dyn AuthProvider,
+    job: &Job,
+) -> anyhow::Result<bool> {
+    let repo = &job.repository;
+    let client = auth.client(&format!("{}/{}", repo.owner.login, repo.name))?;
+    let number: u64 = job.issue.number.try_into()?;
+    let issue = rt.block_on(client.issues(&repo.owner.login, &repo.name).get(number))?;
+    Ok(issue.state == "closed")
+}
+
+/// Marks the runner as dead when dropped, which also covers the runner task panicking.
+struct RunnerAliveGuard(Arc<AtomicBool>);
+
+impl RunnerAliveGuard {
+    fn new(alive: Arc<AtomicBool>) -> Self {
+        alive.store(true, Ordering::SeqCst);
+        Self(alive)
+    }
+}
+
+impl Drop for RunnerAliveGuard {
+    fn drop(&mut self) {
+        log::warn!("Job runner stopped");
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
 
 #[derive(Error, Debug)]
 enum Error {
-    #[error("Missing bot command")]
-    NoCmd,
+    #[error("At least one command prefix is required")]
+    NoCommandPrefix,
+    #[error("Failed to load environment file: {0}")]
+    EnvFile(#[from] dotenvy::Error),
+    #[error("Hook script {0:?} not found")]
+    HookNotFound(PathBuf),
+    #[error("Failed to read secret from {0:?}: {1}")]
+    SecretFile(PathBuf, std::io::Error),
+    #[error("{0}")]
+    MissingScript(String),
+    #[error("Job timed out after {0:?}")]
+    JobTimeout(std::time::Duration),
+    #[error("A job for this pull request is already running, try again when it's done")]
+    JobRunning,
+    #[error(
+        "The repository is too large to run commands for ({} MiB, the limit is {} MiB)",
+        .size / (1024 * 1024),
+        .max_size / (1024 * 1024)
+    )]
+    RepositoryTooLarge { size: u64, max_size: u64 },
+    #[error("Queue responded with {status} ({code}): {error}")]
+    QueueResponse {
+        status: u16,
+        code: String,
+        error: String,
+    },
+}
+
+/// The secret in `file` if there is one, or else `value` (one of them is required by `Config`).
+/// The line break that editors and `echo` add to the end of the file isn't part of it.
+fn read_secret(value: &Option<String>, file: &Option<PathBuf>) -> Result<String, Error> {
+    match file {
+        Some(file) => {
+            let secret = std::fs::read_to_string(file)
+                .map_err(|e| Error::SecretFile(file.clone(), e))?;
+            Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+        }
+        None => Ok(value.clone().unwrap_or_default()),
+    }
+}
+
+/// How long to remember webhook deliveries, see `SkipRedeliveries`
+const DELIVERY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Github redelivers a webhook (with the same `X-GitHub-Delivery` id) when it doesn't get a
+/// timely response, which would enqueue the same command twice. This skips deliveries that were
+/// already handled successfully within `DELIVERY_TTL`.
+struct SkipRedeliveries;
+
+#[tide::utils::async_trait]
+impl tide::Middleware<State> for SkipRedeliveries {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let delivery = match req.header("X-GitHub-Delivery") {
+            Some(delivery) => delivery.as_str().to_string(),
+            None => return Ok(next.run(req).await),
+        };
+        let deliveries = req.state().deliveries.clone();
+        {
+            let mut deliveries = deliveries.lock().await;
+            let now = Instant::now();
+            while matches!(
+                deliveries.get_index(0),
+                Some((_, t)) if now.duration_since(*t) >= DELIVERY_TTL
+            ) {
+                deliveries.shift_remove_index(0);
+            }
+            if deliveries.contains_key(&delivery) {
+                log::info!("Skipping redelivered webhook {delivery}");
+                return Ok(tide::Response::new(200));
+            }
+            deliveries.insert(delivery.clone(), now);
+        }
+        let res = next.run(req).await;
+        if !res.status().is_success() {
+            // Github may retry deliveries we failed to handle
+            deliveries.lock().await.shift_remove(&delivery);
+        }
+        Ok(res)
+    }
+}
+
+/// Logs why a request failed, for every 4xx and 5xx response: the error a handler returned (with
+/// `?`), or the `ErrorBody` it responded with
+struct LogErrors;
+
+#[tide::utils::async_trait]
+impl tide::Middleware<State> for LogErrors {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let (method, path) = (req.method(), req.url().path().to_string());
+        let mut res = next.run(req).await;
+        let status = res.status();
+        if !status.is_client_error() && !status.is_server_error() {
+            return Ok(res);
+        }
+        let error = match res.error() {
+            Some(error) => format!("{error:?}"),
+            None => {
+                let body = res.take_body().into_string().await?;
+                let error = match serde_json::from_str::<ErrorBody>(&body) {
+                    Ok(ErrorBody { error, code }) => format!("{error} ({code})"),
+                    Err(_) if body.is_empty() => "no details".into(),
+                    Err(_) => body.clone(),
+                };
+                res.set_body(body);
+                error
+            }
+        };
+        if status.is_server_error() {
+            log::warn!("{method} {path} failed with {status}: {error}");
+        } else {
+            log::info!("{method} {path} was rejected with {status}: {error}");
+        }
+        Ok(res)
+    }
+}
+
+/// Handles `pull_request` webhooks, which `tide_github` doesn't dispatch: closing (or merging) a
+/// pull request takes its queued jobs out of the queue and cancels its running one. Added to the
+/// `tide_github` server, so it runs after the webhook's signature was verified.
+struct ClosedPullRequests(Dispatcher);
+
+#[tide::utils::async_trait]
+impl tide::Middleware<()> for ClosedPullRequests {
+    async fn handle(&self, mut req: tide::Request<()>, next: tide::Next<'_, ()>) -> tide::Result {
+        #[derive(Deserialize)]
+        struct Payload {
+            action: String,
+            number: i64,
+            repository: PayloadRepository,
+        }
+        #[derive(Deserialize)]
+        struct PayloadRepository {
+            id: RepositoryId,
+        }
+
+        if req.header("X-GitHub-Event").map(|event| event.as_str()) != Some("pull_request") {
+            return Ok(next.run(req).await);
+        }
+        let payload: Payload = req.body_json().await?;
+        if payload.action == "closed" {
+            self.0.close(payload.repository.id, payload.number).await;
+        }
+        Ok(tide::Response::new(200))
+    }
+}
+
+/// Whether `text` matches `pattern`, in which `*` matches any (possibly empty) sequence of
+/// characters. Every other character, `?` included, only matches itself, so an empty pattern only
+/// matches an empty `text`. Matching ignores case, like Github's repository names.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.to_lowercase(), text.to_lowercase());
+    let mut parts = pattern.split('*');
+    // Without a `*` there's a single part, which must be all of `text`
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Commands the webhook handler answers itself, without cloning the repository or running a
+/// script. They take precedence over scripts with the same name (except for `Help`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BuiltinCommand {
+    /// Lists the available commands, unless the repository provides its own `help` script
+    Help,
+    /// Lists the running and queued commands of the issue it's given on
+    Status,
+    /// Cancels the job running for the issue it's given on
+    Cancel,
+    /// Runs the last command given on the same issue again
+    Rerun,
+    /// Shows the version of the bot
+    Version,
+}
+
+impl BuiltinCommand {
+    const ALL: [BuiltinCommand; 5] = [
+        BuiltinCommand::Help,
+        BuiltinCommand::Status,
+        BuiltinCommand::Cancel,
+        BuiltinCommand::Rerun,
+        BuiltinCommand::Version,
+    ];
+
+    fn parse(command: &Command) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|builtin| builtin.name() == command.script)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            BuiltinCommand::Help => "help",
+            BuiltinCommand::Status => "status",
+            BuiltinCommand::Cancel => "cancel",
+            BuiltinCommand::Rerun => "rerun",
+            BuiltinCommand::Version => "version",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            BuiltinCommand::Help => "list the available commands",
+            BuiltinCommand::Status => "show the running and queued commands of this issue",
+            BuiltinCommand::Cancel => "cancel the running command of this issue",
+            BuiltinCommand::Rerun => "run the last command of this issue again",
+            BuiltinCommand::Version => "show the version of the bot",
+        }
+    }
+}
+
+/// Version of the running bot, see `BuiltinCommand::Version` and `/version`
+#[derive(Debug, Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    /// Commit the bot was built from, if it was known at build time (see `build.rs`)
+    git_sha: Option<&'static str>,
+    uptime_secs: u64,
+}
+
+impl VersionInfo {
+    fn new(started: Instant) -> Self {
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: option_env!("CI_SCRIPT_GIT_SHA"),
+            uptime_secs: started.elapsed().as_secs(),
+        }
+    }
+}
+
+impl std::fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ci-script {}", self.version)?;
+        if let Some(sha) = self.git_sha {
+            write!(f, " (commit {sha})")?;
+        }
+        let secs = self.uptime_secs;
+        write!(f, ", up for ")?;
+        if secs >= 24 * 60 * 60 {
+            write!(f, "{}d ", secs / (24 * 60 * 60))?;
+        }
+        if secs >= 60 * 60 {
+            write!(f, "{}h ", secs / (60 * 60) % 24)?;
+        }
+        if secs >= 60 {
+            write!(f, "{}m ", secs / 60 % 60)?;
+        }
+        write!(f, "{}s", secs % 60)
+    }
+}
+
+/// Longest wait between attempts of the runner to get a job from an unreachable queue
+const MAX_GET_JOB_RETRY_WAIT: Duration = Duration::from_secs(30);
+
+/// Number of issues to remember the last command of, for `BuiltinCommand::Rerun`
+const MAX_RECENT_COMMANDS: usize = 1000;
+
+/// Ids and cancellation flags of the running jobs by repository and issue number, see
+/// `BuiltinCommand::Cancel` and `/queue/:id/cancel`
+type Cancellations =
+    Arc<std::sync::Mutex<HashMap<(RepositoryId, i64), (String, Arc<AtomicBool>)>>>;
+
+/// Repository and issue number of the jobs whose thread is still running, see
+/// `--one-job-per-issue`
+type InFlight = Arc<std::sync::Mutex<HashSet<(RepositoryId, i64)>>>;
+
+/// Marks the issue of a job as in flight until the job's thread drops it
+struct InFlightGuard {
+    in_flight: InFlight,
+    key: (RepositoryId, i64),
+}
+
+impl InFlightGuard {
+    /// `None` if a job of the issue is already in flight
+    fn acquire(in_flight: &InFlight, key: (RepositoryId, i64)) -> Option<Self> {
+        let inserted = in_flight
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .insert(key);
+        inserted.then(|| InFlightGuard {
+            in_flight: in_flight.clone(),
+            key,
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .remove(&self.key);
+    }
+}
+
+/// Limits how many jobs a repository can enqueue within a sliding time window
+struct RateLimiter {
+    /// `None` means unlimited
+    max_jobs: Option<usize>,
+    window: Duration,
+    enqueued: HashMap<RepositoryId, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    fn new(max_jobs: Option<usize>, window: Duration) -> Self {
+        let enqueued = HashMap::new();
+        Self {
+            max_jobs,
+            window,
+            enqueued,
+        }
+    }
+
+    /// Record a job for `repo`, unless that would exceed the limit
+    fn try_acquire(&mut self, repo: RepositoryId) -> bool {
+        let max_jobs = match self.max_jobs {
+            Some(max_jobs) => max_jobs,
+            None => return true,
+        };
+        let now = Instant::now();
+        let window = self.window;
+        let enqueued = self.enqueued.entry(repo).or_default();
+        while matches!(enqueued.front(), Some(t) if now.duration_since(*t) >= window) {
+            enqueued.pop_front();
+        }
+        if enqueued.len() >= max_jobs {
+            false
+        } else {
+            enqueued.push_back(now);
+            true
+        }
+    }
+}
+
+/// Comment on an issue in the background, for webhook handlers that can't wait for Github
+fn spawn_comment(
+    rt: &tokio::runtime::Handle,
+    auth: Arc<dyn AuthProvider>,
+    repo: Repository,
+    issue_nr: i64,
+    body: String,
+) {
+    let issue_nr: u64 = match issue_nr.try_into() {
+        Ok(issue_nr) => issue_nr,
+        Err(_) => return,
+    };
+    rt.spawn(async move {
+        let full_name = format!("{}/{}", repo.owner.login, repo.name);
+        let github = github::Client::new(auth);
+        let comment =
+            tokio::task::spawn_blocking(move || github.create_comment(&full_name, issue_nr, &body));
+        match comment.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log::warn!("Failed to comment on issue: {err}"),
+            Err(err) => log::warn!("Failed to comment on issue: {err}"),
+        }
+    });
+}
+
+/// Handles the commands received by the webhook handler: built-in commands are answered right
+/// away, everything else becomes a job for the runner
+#[derive(Clone)]
+struct Dispatcher {
+    /// Prefix shown in the help comment
+    command_prefix: String,
+    queue: Arc<Mutex<LocalQueue<String, Job>>>,
+    events: Arc<Mutex<Broadcast<JobEvent>>>,
+    /// See `State::running`
+    running: Arc<Mutex<IndexMap<String, JobEvent>>>,
+    rt: tokio::runtime::Handle,
+    auth: Arc<dyn AuthProvider>,
+    rate_limiter: Arc<std::sync::Mutex<RateLimiter>>,
+    cancellations: Cancellations,
+    /// `None` unless `--one-job-per-issue` is set
+    in_flight: Option<InFlight>,
+    /// See `Config::max_clone_size`
+    max_clone_size: Option<u64>,
+    /// Last command of each issue, least recently used first, for `BuiltinCommand::Rerun`
+    recent_commands: Arc<std::sync::Mutex<IndexMap<(RepositoryId, i64), Command>>>,
+    /// See `State::started`
+    started: Instant,
+}
+
+impl Dispatcher {
+    fn dispatch(&self, command: Command, repo: Repository, user: User, issue: Issue) {
+        match BuiltinCommand::parse(&command) {
+            Some(BuiltinCommand::Help) => self.help(command, repo, user, issue),
+            Some(BuiltinCommand::Status) => self.status(repo, issue.number),
+            Some(BuiltinCommand::Cancel) => self.cancel(repo, issue.number),
+            Some(BuiltinCommand::Rerun) => self.rerun(repo, user, issue),
+            Some(BuiltinCommand::Version) => {
+                self.comment(repo, issue.number, VersionInfo::new(self.started).to_string())
+            }
+            None => self.enqueue(command, repo, user, issue),
+        }
+    }
+
+    fn comment(&self, repo: Repository, issue_nr: i64, body: String) {
+        spawn_comment(&self.rt, self.auth.clone(), repo, issue_nr, body);
+    }
+
+    /// Comment the scripts of the pull request, listed through the Github API. Repositories with
+    /// their own `help` script, and anything the API can't answer, go through the runner.
+    fn help(&self, command: Command, repo: Repository, user: User, issue: Issue) {
+        let dispatcher = self.clone();
+        self.rt.spawn(async move {
+            let commands = dispatcher.list_commands(&command, &repo, issue.number);
+            match commands.await {
+                Ok(Some(commands)) => {
+                    let body = help_comment(&dispatcher.command_prefix, &commands);
+                    dispatcher.comment(repo, issue.number, body);
+                }
+                Ok(None) => dispatcher.enqueue(command, repo, user, issue),
+                Err(e) => {
+                    log::warn!("Failed to list commands, running `help` as a job: {e}");
+                    dispatcher.enqueue(command, repo, user, issue);
+                }
+            }
+        });
+    }
+
+    /// The scripts in the directory of `command` at the head of pull request `issue_nr`, like
+    /// `ci_script::job::list_commands` finds them in a checkout. `None` if there's a `help`
+    /// script.
+    async fn list_commands(
+        &self,
+        command: &Command,
+        repo: &Repository,
+        issue_nr: i64,
+    ) -> anyhow::Result<Option<Vec<(String, String)>>> {
+        let full_name = format!("{}/{}", repo.owner.login, repo.name);
+        let auth = self.auth.clone();
+        let client = tokio::task::spawn_blocking(move || auth.client(&full_name)).await??;
+        let dir = command
+            .dir
+            .file_name()
+            .and_then(|dir| dir.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid command directory {:?}", command.dir))?;
+        let contents = client
+            .repos(&repo.owner.login, &repo.name)
+            .get_content()
+            .path(format!(".github/{dir}"))
+            .r#ref(format!("refs/pull/{issue_nr}/head"))
+            .send()
+            .await;
+        let items = match contents {
+            Ok(mut contents) => contents.take_items(),
+            Err(octocrab::Error::GitHub { source, .. }) if source.message == "Not Found" => {
+                vec![]
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut commands = vec![];
+        for item in items {
+            match item.name.strip_suffix(".rhai") {
+                Some("help") => return Ok(None),
+                // Symlinks are skipped, like in a checkout
+                Some(script) if item.r#type == "file" => {
+                    commands.push((dir.to_string(), script.to_string()))
+                }
+                _ => {}
+            }
+        }
+        commands.sort();
+        Ok(Some(commands))
+    }
+
+    /// Look up the branch of pull request `issue_nr`, which the webhook payload doesn't include,
+    /// see `Job::head`
+    async fn pr_head(&self, repo: &Repository, issue_nr: i64) -> anyhow::Result<PullRequestHead> {
+        let full_name = format!("{}/{}", repo.owner.login, repo.name);
+        let auth = self.auth.clone();
+        let client = tokio::task::spawn_blocking(move || auth.client(&full_name)).await??;
+        let pr = client
+            .pulls(&repo.owner.login, &repo.name)
+            .get(issue_nr.try_into()?)
+            .await?;
+        Ok(pr.try_into()?)
+    }
+
+    /// Size of the repository in bytes according to Github, if it says
+    async fn repo_size(&self, repo: &Repository) -> anyhow::Result<Option<u64>> {
+        let full_name = format!("{}/{}", repo.owner.login, repo.name);
+        let auth = self.auth.clone();
+        let client = tokio::task::spawn_blocking(move || auth.client(&full_name)).await??;
+        let repo = client.repos(&repo.owner.login, &repo.name).get().await?;
+        // In KiB
+        Ok(repo.size.map(|size| u64::from(size) * 1024))
+    }
+
+    fn status(&self, repo: Repository, issue_nr: i64) {
+        let dispatcher = self.clone();
+        async_std::task::spawn(async move {
+            let full_name = format!("{}/{}", repo.owner.login, repo.name);
+            let mut lines: Vec<String> = dispatcher
+                .running
+                .lock()
+                .await
+                .values()
+                .filter(|event| event.repository == full_name && event.issue == issue_nr)
+                .map(|event| format!("* `{}` is running", event.command))
+                .collect();
+            {
+                let queue = dispatcher.queue.lock().await;
+                for (position, (_, job)) in queue.iter().enumerate() {
+                    if job.repository.id == repo.id && job.issue.number == issue_nr {
+                        lines.push(format!(
+                            "* `{}` is queued at position {} of {}",
+                            job.command,
+                            position + 1,
+                            queue.len()
+                        ));
+                    }
+                }
+            }
+            let body = if lines.is_empty() {
+                "No commands are running or queued for this issue".into()
+            } else {
+                format!("Commands of this issue:\n{}", lines.join("\n"))
+            };
+            dispatcher.comment(repo, issue_nr, body);
+        });
+    }
+
+    fn cancel(&self, repo: Repository, issue_nr: i64) {
+        let running = self
+            .cancellations
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(&(repo.id, issue_nr))
+            .map(|(_, cancelled)| cancelled.store(true, Ordering::SeqCst))
+            .is_some();
+        let body = if running {
+            "Cancelling the running command"
+        } else {
+            "No command is running for this issue"
+        };
+        self.comment(repo, issue_nr, body.into());
+    }
+
+    /// Drop the queued jobs of a closed pull request and cancel its running one, without
+    /// commenting on it
+    async fn close(&self, repo_id: RepositoryId, pr_nr: i64) {
+        let mut removed = vec![];
+        {
+            let mut queue = self.queue.lock().await;
+            let of_pr = |job: &Job| job.repository.id == repo_id && job.issue.number == pr_nr;
+            while let Ok(Some(job)) = queue.remove_matching(of_pr) {
+                removed.push(JobEvent::new(&job, Phase::Cancelled));
+            }
+        }
+        if !removed.is_empty() {
+            log::info!("Removed {} jobs of closed pull request {pr_nr}", removed.len());
+        }
+        let mut events = self.events.lock().await;
+        for event in removed {
+            events.send(event);
+        }
+        drop(events);
+        let running = self
+            .cancellations
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(&(repo_id, pr_nr))
+            .map(|(id, cancelled)| (id.clone(), cancelled.swap(true, Ordering::SeqCst)));
+        if let Some((id, false)) = running {
+            log::info!("Cancelling job {id} of closed pull request {pr_nr}");
+        }
+    }
+
+    fn rerun(&self, repo: Repository, user: User, issue: Issue) {
+        let last = self
+            .recent_commands
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(&(repo.id, issue.number))
+            .cloned();
+        match last {
+            Some(last) => self.enqueue(last, repo, user, issue),
+            None => self.comment(
+                repo,
+                issue.number,
+                "Nothing to rerun: no command was run on this issue yet".into(),
+            ),
+        }
+    }
+
+    /// Add a job running `command` to the queue, unless the repository is too large (see
+    /// `Config::max_clone_size`) or over the rate limit, which the command is only counted
+    /// against (and remembered for `rerun`) if it isn't refused for its size
+    fn enqueue(&self, command: Command, repo: Repository, user: User, issue: Issue) {
+        if let Some(in_flight) = &self.in_flight {
+            let running = in_flight
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .contains(&(repo.id, issue.number));
+            if running {
+                log::info!("Rejected command for busy issue {} in {}", issue.number, repo.name);
+                self.comment(repo, issue.number, Error::JobRunning.to_string());
+                return;
+            }
+        }
+
+        let mut job = Job::new(command, user, repo, issue);
+        let id = job.id.clone();
+
+        let dispatcher = self.clone();
+        async_std::task::spawn(async move {
+            if let Some(max_size) = dispatcher.max_clone_size {
+                let (size_dispatcher, repo) = (dispatcher.clone(), job.repository.clone());
+                let size = dispatcher
+                    .rt
+                    .spawn(async move { size_dispatcher.repo_size(&repo).await })
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|size| size);
+                let refusal = match size {
+                    Ok(Some(size)) if size > max_size => {
+                        Some(Error::RepositoryTooLarge { size, max_size }.to_string())
+                    }
+                    Ok(_) => None,
+                    // Cloning it could be what fills the disk
+                    Err(e) => Some(format!("Failed to check the size of the repository: {e}")),
+                };
+                if let Some(refusal) = refusal {
+                    log::info!("Refused job {} in {}: {refusal}", id, job.repository.name);
+                    dispatcher.comment(job.repository, job.issue.number, refusal);
+                    return;
+                }
+            }
+            // Only now, so a refused command doesn't count against the limit or get rerun
+            let within_limit = dispatcher
+                .rate_limiter
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .try_acquire(job.repository.id);
+            if !within_limit {
+                log::info!("Rate limited command in repository {}", job.repository.name);
+                dispatcher.comment(
+                    job.repository,
+                    job.issue.number,
+                    "Rate limited: too many commands for this repository, please try again later"
+                        .into(),
+                );
+                return;
+            }
+            {
+                let issue_key = (job.repository.id, job.issue.number);
+                let mut recent_commands = dispatcher
+                    .recent_commands
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner);
+                // Move the issue to the back, so the least recently used issue is dropped
+                recent_commands.shift_remove(&issue_key);
+                recent_commands.insert(issue_key, job.command.clone());
+                if recent_commands.len() > MAX_RECENT_COMMANDS {
+                    recent_commands.shift_remove_index(0);
+                }
+            }
+            if job.issue.pull_request.is_some() {
+                let (pr_dispatcher, repo, issue_nr) =
+                    (dispatcher.clone(), job.repository.clone(), job.issue.number);
+                let head = dispatcher
+                    .rt
+                    .spawn(async move { pr_dispatcher.pr_head(&repo, issue_nr).await })
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|head| head);
+                match head {
+                    Ok(head) => job.head = Some(head),
+                    // Scripts that don't push to the pull request's branch don't need it
+                    Err(e) => {
+                        log::warn!("Failed to look up pull request {}: {e}", job.issue.number)
+                    }
+                }
+            }
+            let event = JobEvent::new(&job, Phase::Enqueued);
+            let (repo, issue_nr) = (job.repository.clone(), job.issue.number);
+            let added = dispatcher.queue.lock().await.add(id, job);
+            match added {
+                Ok(()) => dispatcher.events.lock().await.send(event),
+                Err(e) => {
+                    log::warn!("Failed to enqueue job {}: {e}", event.id);
+                    dispatcher.comment(repo, issue_nr, format!("Failed to enqueue command: {e}"));
+                }
+            }
+        });
+    }
+}
+
+/// Body of failed `/queue` responses
+#[derive(Debug, Serialize, Deserialize)]
+struct ErrorBody {
+    /// Human readable description of what went wrong
+    error: String,
+    /// Machine readable error code
+    code: String,
+}
+
+fn error_response<E: std::fmt::Display>(status: u16, code: &str, error: E) -> tide::Result {
+    let body = ErrorBody {
+        error: format!("{error}"),
+        code: code.into(),
+    };
+    Ok(tide::Response::builder(status)
+        .body(tide::Body::from_json(&body)?)
+        .build())
 }
 
 async fn remove_from_queue(req: tide::Request<State>) -> tide::Result {
@@ -54,79 +1188,484 @@ async fn remove_from_queue(req: tide::Request<State>) -> tide::Result {
     #[serde(default)]
     struct Options {
         long_poll: bool,
+        /// Only take jobs with this label (see `Job::has_label`), any job if not given
+        label: Option<String>,
     }
 
+    let Options { long_poll, label } = match req.query() {
+        Ok(options) => options,
+        Err(e) => return error_response(400, "invalid_query", e),
+    };
+
     // We lock the Mutex in a separate scope so it can be unlocked (dropped)
     // before we try to .await another future (MutexGuard is not Send).
     let recv = {
-        let queue = req.state();
+        let queue = &req.state().queue;
 
         let mut queue = queue.lock().await;
 
-        match queue.remove() {
-            Some(job) => return Ok(tide::Body::from_json(&job)?.into()),
-            None => {
-                let Options { long_poll } = req.query()?;
-                if long_poll {
-                    let (send, recv) = async_std::channel::bounded(1);
-                    queue.register_watcher(send);
-                    Some(recv)
-                } else {
-                    None
+        let removed = match &label {
+            Some(label) => queue.remove_matching(|job| job.has_label(label)),
+            None => queue.remove(),
+        };
+        match removed {
+            Ok(Some(job)) => {
+                req.state().job_started(&job).await;
+                return Ok(tide::Body::from_json(&job)?.into());
+            }
+            Err(e) => return error_response(503, "queue_closed", e),
+            Ok(None) if long_poll => {
+                let (send, recv) = async_std::channel::bounded(1);
+                match label {
+                    Some(label) => {
+                        queue.register_watcher_matching(send, move |job| job.has_label(&label))
+                    }
+                    None => queue.register_watcher(send),
                 }
+                Some(recv)
             }
+            Ok(None) => None,
         }
     };
 
     match recv {
         Some(recv) => {
             let mut res = tide::Response::new(200);
-            let job = recv.recv().await?;
+            let job = match recv.recv().await {
+                Ok(job) => job,
+                // Waiting watchers are only dropped when the queue is closed
+                Err(_) => return error_response(503, "queue_closed", "The queue is closed"),
+            };
+            req.state().job_started(&job).await;
             res.set_body(tide::Body::from_json(&job)?);
             Ok(res)
         }
-        None => Ok(tide::Response::builder(404).build()),
+        None => error_response(404, "queue_empty", "The queue is empty"),
+    }
+}
+
+async fn add_result(mut req: tide::Request<State>) -> tide::Result {
+    let result: JobResult = match req.body_json().await {
+        Ok(result) => result,
+        Err(e) => return error_response(400, "invalid_result", e),
+    };
+    let phase = if result.success {
+        Phase::Finished
+    } else {
+        Phase::Failed
+    };
+    let event = match req.state().running.lock().await.shift_remove(&result.id) {
+        Some(started) => JobEvent { phase, ..started },
+        // E.g. the job started before a restart
+        None => JobEvent {
+            id: result.id.clone(),
+            repository: String::new(),
+            issue: 0,
+            command: String::new(),
+            phase,
+        },
+    };
+    req.state().events.lock().await.send(event);
+
+    let max_results = req.state().max_results;
+    let mut results = req.state().results.lock().await;
+    results.insert(result.id.clone(), result);
+    while results.len() > max_results {
+        results.shift_remove_index(0);
     }
+    Ok(tide::Response::new(204))
 }
 
-fn prepare_command(command: Vec<String>) -> Result<Vec<String>, Error> {
-    // The first argument (.e.g `/bot` is also the name of the directory the script is in
-    let dir = command
+async fn get_result(req: tide::Request<State>) -> tide::Result {
+    let id = req.param("id")?;
+    match req.state().results.lock().await.get(id) {
+        Some(result) => Ok(tide::Body::from_json(result)?.into()),
+        None => error_response(404, "result_not_found", format!("No result for job {id}")),
+    }
+}
+
+/// The queued jobs, next to run first
+async fn list_queue(req: tide::Request<State>) -> tide::Result {
+    let queue = req.state().queue.lock().await;
+    let jobs: Vec<QueuedJob> = queue
         .iter()
-        .next()
-        .map(|cmd| {
-            if let Some(cmd) = cmd.strip_prefix('/') {
-                String::from(cmd)
-            } else {
-                String::from(cmd)
-            }
+        .enumerate()
+        .map(|(position, (id, job))| QueuedJob {
+            id: id.clone(),
+            position,
+            repository: format!("{}/{}", job.repository.owner.login, job.repository.name),
+            issue: job.issue.number,
+            command: job.command.to_string(),
         })
-        .ok_or(Error::NoCmd)?;
-    let file = command
+        .collect();
+    Ok(tide::Body::from_json(&jobs)?.into())
+}
+
+/// Take a job out of the queue, or cancel it like `BuiltinCommand::Cancel` if it's running. Needs
+/// the admin token.
+async fn cancel_job(req: tide::Request<State>) -> tide::Result {
+    if let Some(response) = check_admin_token(&req) {
+        return response;
+    }
+    let id = req.param("id")?;
+    let queued = req.state().queue.lock().await.take(&id.to_string());
+    if let Some(job) = queued {
+        log::info!("Removed job {id} from the queue");
+        let event = JobEvent::new(&job, Phase::Cancelled);
+        req.state().events.lock().await.send(event);
+        return Ok(json!({ "id": id, "was": "queued" }).into());
+    }
+    let running = req
+        .state()
+        .cancellations
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .values()
+        .find(|(job_id, _)| job_id == id)
+        .map(|(_, cancelled)| cancelled.store(true, Ordering::SeqCst))
+        .is_some();
+    if running {
+        log::info!("Cancelling running job {id}");
+        return Ok(json!({ "id": id, "was": "running" }).into());
+    }
+    error_response(404, "job_not_found", format!("No queued or running job {id}"))
+}
+
+/// Drop all queued jobs (running jobs keep running), for operators during an incident. Needs the
+/// admin token.
+async fn clear_queue(req: tide::Request<State>) -> tide::Result {
+    if let Some(response) = check_admin_token(&req) {
+        return response;
+    }
+    let mut queue = req.state().queue.lock().await;
+    let events: Vec<_> = queue
         .iter()
-        .nth(1)
-        .map(|cmd| format!("{}.rhai", cmd))
-        .ok_or(Error::NoCmd)?;
-    let mut args: Vec<String> = command.into_iter().skip(2).collect();
-    let script_path = String::from(Path::new(".github").join(dir).join(file).to_string_lossy());
-    let mut res = vec![script_path];
-    res.append(&mut args);
-    Ok(res)
+        .map(|(_, job)| JobEvent::new(job, Phase::Cancelled))
+        .collect();
+    let cleared = queue.clear();
+    drop(queue);
+    log::warn!("Cleared the queue, dropping {cleared} jobs");
+    let mut broadcast = req.state().events.lock().await;
+    for event in events {
+        broadcast.send(event);
+    }
+    Ok(json!({ "cleared": cleared }).into())
+}
+
+/// The last `tail` (100 by default) lines of the log as text, oldest first. Needs the admin
+/// token, since the log names repositories and users.
+async fn recent_logs(req: tide::Request<State>) -> tide::Result {
+    #[derive(Deserialize)]
+    #[serde(default)]
+    struct Options {
+        tail: usize,
+    }
+
+    impl Default for Options {
+        fn default() -> Self {
+            Options { tail: 100 }
+        }
+    }
+
+    if let Some(response) = check_admin_token(&req) {
+        return response;
+    }
+    let Options { tail } = match req.query() {
+        Ok(options) => options,
+        Err(e) => return error_response(400, "invalid_query", e),
+    };
+    let body: String = req
+        .state()
+        .logs
+        .tail(tail)
+        .into_iter()
+        .map(|line| line + "\n")
+        .collect();
+    Ok(tide::Response::builder(200)
+        .body(body)
+        .content_type(tide::http::mime::PLAIN)
+        .build())
+}
+
+/// The error response for requests to admin routes without the right admin token, if any
+fn check_admin_token(req: &tide::Request<State>) -> Option<tide::Result> {
+    let expected = match &req.state().admin_token {
+        Some(token) => token,
+        None => {
+            let error = "Admin routes are disabled, the server has no --admin-token";
+            return Some(error_response(403, "admin_disabled", error));
+        }
+    };
+    let given = req
+        .header("Authorization")
+        .and_then(|value| value.as_str().strip_prefix("Bearer "));
+    match given {
+        Some(given) if constant_time_eq(given.as_bytes(), expected.as_bytes()) => None,
+        _ => Some(error_response(401, "unauthorized", "Missing or wrong admin token")),
+    }
+}
+
+/// Compares the whole of both values, so the time it takes doesn't tell how much of a guessed
+/// token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Stream a server-sent `job` event for every `JobEvent`
+async fn job_events(req: tide::Request<State>, sender: tide::sse::Sender) -> tide::Result<()> {
+    let events = req.state().events.lock().await.subscribe();
+    while let Ok(event) = events.recv().await {
+        let data = tide::Body::from_json(&event)?.into_string().await?;
+        sender.send("job", data, Some(&event.id)).await?;
+    }
+    Ok(())
+}
+
+/// Stream a server-sent `enqueued` event for every job added to the queue
+async fn queue_events(req: tide::Request<State>, sender: tide::sse::Sender) -> tide::Result<()> {
+    let enqueued = req.state().queue.lock().await.subscribe();
+    while let Ok(event) = enqueued.recv().await {
+        let data = json!({
+            "id": event.id,
+            "position": event.position,
+        });
+        sender.send("enqueued", data.to_string(), None).await?;
+    }
+    Ok(())
+}
+
+async fn health(_req: tide::Request<State>) -> tide::Result {
+    Ok(tide::Response::new(200))
+}
+
+async fn version(req: tide::Request<State>) -> tide::Result {
+    Ok(tide::Response::builder(200)
+        .body(json!(VersionInfo::new(req.state().started)))
+        .build())
+}
+
+async fn ready(req: tide::Request<State>) -> tide::Result {
+    let runner_alive = req.state().runner_alive.load(Ordering::SeqCst);
+    let github_authenticated = req.state().github_authenticated.load(Ordering::SeqCst);
+    let status = if (runner_alive || req.state().no_runner) && github_authenticated {
+        200
+    } else {
+        503
+    };
+    Ok(tide::Response::builder(status)
+        .body(json!({
+            "runner_alive": runner_alive,
+            "github_authenticated": github_authenticated,
+        }))
+        .build())
+}
+
+/// A Markdown list item for each of the `commands` (`(dir, script)` pairs, see
+/// `ci_script::job::list_commands`) that is in the script directory of `prefix`
+fn command_list(prefix: &str, commands: &[(String, String)]) -> Vec<String> {
+    let dir = prefix.strip_prefix('/').unwrap_or(prefix);
+    commands
+        .iter()
+        .filter(|(command_dir, _)| command_dir == dir)
+        .map(|(_, name)| format!("* `{prefix} {name}`"))
+        .collect()
+}
+
+/// Comment listing the commands available for the given prefix, which are the scripts in
+/// `.github/<prefix>/`, and the built-in commands
+fn help_comment(prefix: &str, commands: &[(String, String)]) -> String {
+    let dir = prefix.strip_prefix('/').unwrap_or(prefix);
+    let commands = command_list(prefix, commands);
+    let builtins: Vec<String> = BuiltinCommand::ALL
+        .iter()
+        .map(|builtin| format!("* `{prefix} {}`: {}", builtin.name(), builtin.description()))
+        .collect();
+    let builtins = builtins.join("\n");
+    if commands.is_empty() {
+        format!(
+            "No commands available, add scripts to `.github/{dir}/` to create them\n\n\
+             Built-in commands:\n{builtins}"
+        )
+    } else {
+        format!(
+            "Available commands:\n{}\n\nBuilt-in commands:\n{builtins}",
+            commands.join("\n")
+        )
+    }
+}
+
+/// Explains that the job's `script` doesn't exist, and which commands do
+fn missing_script_message(prefix: &str, script: &Path, commands: &[(String, String)]) -> String {
+    let commands = command_list(prefix, commands);
+    if commands.is_empty() {
+        let dir = prefix.strip_prefix('/').unwrap_or(prefix);
+        format!(
+            "Script `{}` not found, add scripts to `.github/{dir}/` to create commands",
+            script.display()
+        )
+    } else {
+        format!(
+            "Script `{}` not found. Available commands:\n{}",
+            script.display(),
+            commands.join("\n")
+        )
+    }
+}
+
+/// Move the working directory `dir` of a failed job to `kept`, replacing what an earlier failure
+/// left there, so the next checkout starts over instead of resetting it
+fn keep_workdir(dir: &Path, kept: &Path) {
+    if !dir.is_dir() {
+        return;
+    }
+    if kept.exists() {
+        if let Err(e) = std::fs::remove_dir_all(kept) {
+            log::warn!("Failed to remove the previously kept working directory {kept:?}: {e}");
+            return;
+        }
+    }
+    match std::fs::rename(dir, kept) {
+        Ok(()) => log::info!("Kept the working directory of the failed job in {kept:?}"),
+        Err(e) => log::warn!("Failed to keep the working directory {dir:?}: {e}"),
+    }
+}
+
+/// Load the file given by `--env-file` (or `ENV_FILE`) into the environment. `Config::from_args`
+/// reads the environment while parsing the arguments, so the flag is looked up by hand first.
+fn load_env_file() -> Result<(), Error> {
+    let mut path = std::env::var_os("ENV_FILE");
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--env-file" {
+            path = args.next();
+        } else if let Some(value) = arg.to_str().and_then(|arg| arg.strip_prefix("--env-file=")) {
+            path = Some(value.into());
+        }
+    }
+    if let Some(path) = path {
+        dotenvy::from_path(path)?;
+    }
+    Ok(())
+}
+
+/// Resolves at the first SIGTERM or SIGINT (Ctrl-C) after it's called
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => Ok(()),
+            res = tokio::signal::ctrl_c() => res,
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// Waits for a shutdown signal, then closes the queue, so new commands are refused while the
+/// queued and running jobs finish, and resolves once they have. Another signal exits right away.
+async fn graceful_shutdown(rt: tokio::runtime::Handle, state: State) {
+    let signal = rt.spawn(shutdown_signal()).await.map_err(std::io::Error::from);
+    if let Err(e) = signal.and_then(|signal| signal) {
+        log::warn!("Failed to listen for shutdown signals: {e}");
+        return futures_lite::future::pending().await;
+    }
+    log::info!("Shutting down once the queued and running jobs are done, signal again to exit now");
+    state.queue.lock().await.close();
+    rt.spawn(async {
+        if shutdown_signal().await.is_ok() {
+            log::warn!("Exiting without waiting for the jobs");
+            std::process::exit(1);
+        }
+    });
+    while !state.queue.lock().await.is_empty() || !state.running.lock().await.is_empty() {
+        async_std::task::sleep(Duration::from_secs(1)).await;
+    }
+    log::info!("All jobs are done, exiting");
 }
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
+    load_env_file()?;
     let config = Config::from_args();
-    pretty_env_logger::formatted_timed_builder()
+    let logs = Arc::new(LogBuffer::new(config.log_buffer_lines));
+    let logger = pretty_env_logger::formatted_timed_builder()
         .filter(None, config.log_level)
-        .init();
+        .build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(BufferedLogger::new(logger, logs.clone())))?;
+    if let Some(env_file) = &config.env_file {
+        log::info!("Loaded environment from {:?}", env_file);
+    }
+
+    let command_prefixes = config.command_prefix.clone();
+    let runner_prefix = command_prefixes
+        .first()
+        .cloned()
+        .ok_or(Error::NoCommandPrefix)?;
+    let default_script = config.default_script.clone();
+    let repos_allowlist = config.repos_allowlist.clone();
+
+    let state = State {
+        queue: Arc::new(Mutex::new(match config.max_queue_len {
+            Some(max_queue_len) => LocalQueue::with_capacity(max_queue_len),
+            None => LocalQueue::new(),
+        })),
+        results: Arc::new(Mutex::new(IndexMap::new())),
+        max_results: config.max_results,
+        runner_alive: Arc::new(AtomicBool::new(false)),
+        no_runner: config.no_runner,
+        github_authenticated: Arc::new(AtomicBool::new(false)),
+        deliveries: Arc::new(Mutex::new(IndexMap::new())),
+        events: Arc::new(Mutex::new(Broadcast::new())),
+        running: Arc::new(Mutex::new(IndexMap::new())),
+        cancellations: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        started: Instant::now(),
+        admin_token: match (&config.admin_token, &config.admin_token_file) {
+            (None, None) => None,
+            (token, file) => Some(read_secret(token, file)?),
+        },
+        logs,
+    };
 
-    let command_prefix = config.command_prefix.clone();
+    let app_key = read_secret(&config.app_key, &config.app_key_file)?;
+    let webhook_secret = read_secret(&config.webhook_secret, &config.webhook_secret_file)?;
+    let mut app_auth = AppAuth::new(config.app_id, &app_key, config.github_api_url.clone())?;
+    if let Some(user_agent) = &config.github_user_agent {
+        app_auth = app_auth.with_user_agent(user_agent);
+    }
+    let app_auth = Arc::new(app_auth);
+    let auth: Arc<dyn AuthProvider> = app_auth.clone();
+    let tokio_rt = tokio::runtime::Runtime::new()?;
 
-    let queue = Arc::new(Mutex::new(LocalQueue::new()));
+    let cancellations = state.cancellations.clone();
+    let in_flight = config
+        .one_job_per_issue
+        .then(|| Arc::new(std::sync::Mutex::new(HashSet::new())));
+    let dispatcher = Dispatcher {
+        command_prefix: runner_prefix.clone(),
+        queue: state.queue.clone(),
+        events: state.events.clone(),
+        running: state.running.clone(),
+        rt: tokio_rt.handle().clone(),
+        auth: auth.clone(),
+        rate_limiter: Arc::new(std::sync::Mutex::new(RateLimiter::new(
+            config.rate_limit,
+            Duration::from_secs(60),
+        ))),
+        cancellations: cancellations.clone(),
+        in_flight: in_flight.clone(),
+        max_clone_size: config.max_clone_size,
+        recent_commands: Arc::new(std::sync::Mutex::new(IndexMap::new())),
+        started: state.started,
+    };
 
-    let mut app = tide::with_state(queue.clone());
-    let github = tide_github::new(&config.webhook_secret)
+    let mut app = tide::with_state(state.clone());
+    let closed_pull_requests = ClosedPullRequests(dispatcher.clone());
+    let mut github = tide_github::new(&webhook_secret)
         .on(Event::IssueComment, move |payload| {
             let payload: tide_github::payload::IssueCommentPayload = match payload.try_into() {
                 Ok(payload) => payload,
@@ -137,30 +1676,14 @@ async fn main() -> tide::Result<()> {
             };
 
             if let Some(body) = payload.comment.body {
-                if body.starts_with(&command_prefix) {
-                    let command = body
-                        .split_once('\n')
-                        .map(|(cmd, _)| cmd.into())
-                        .map(|cmd| {
-                            shell_words::split(cmd).expect("Failed to split command as shell words")
-                        })
-                        .unwrap_or_else(|| body.split(" ").map(|x| x.to_string()).collect());
-
-                    let command = match prepare_command(command) {
-                        Ok(command) => command,
-                        Err(e) => {
-                            log::warn!("Failed to determine command: {e}");
-                            return;
-                        }
-                    };
-
-                    let id = format!(
-                        "{}_{}_{}",
-                        payload.repository.name,
-                        command.join(" "),
-                        uuid::Uuid::new_v4(),
-                    );
-
+                let command = match Command::parse(&body, &command_prefixes, &default_script) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        log::warn!("Failed to determine command: {e}");
+                        return;
+                    }
+                };
+                if let Some(command) = command {
                     let repo: Repository = match payload.repository.try_into() {
                         Ok(repo) => repo,
                         Err(err) => {
@@ -168,133 +1691,430 @@ async fn main() -> tide::Result<()> {
                             return;
                         }
                     };
-
-                    let job = Job {
-                        command,
-                        // user: payload.comment.user,
-                        repository: repo,
-                        issue: payload.issue,
-                    };
-
-                    let q = queue.clone();
-                    async_std::task::spawn(async move {
-                        q.lock().await.add(id, job);
-                    });
+                    let full_name = format!("{}/{}", repo.owner.login, repo.name);
+                    if !repos_allowlist.is_empty()
+                        && !repos_allowlist.iter().any(|pattern| glob_match(pattern, &full_name))
+                    {
+                        log::info!("Ignoring command in {full_name}, it's not on the allowlist");
+                        return;
+                    }
+                    dispatcher.dispatch(command, repo, payload.comment.user, payload.issue);
                 }
             }
         })
         .build();
-    app.at("/").nest(github);
+    github.with(closed_pull_requests);
+    app.with(LogErrors);
+    app.at("/").with(SkipRedeliveries).nest(github);
+    app.at("/queue").get(list_queue);
     app.at("/queue/remove").post(remove_from_queue);
+    app.at("/queue/:id/cancel").post(cancel_job);
+    app.at("/queue/clear").post(clear_queue);
+    app.at("/queue/result").post(add_result);
+    app.at("/queue/result/:id").get(get_result);
+    app.at("/queue/events").get(tide::sse::endpoint(queue_events));
+    app.at("/events").get(tide::sse::endpoint(job_events));
+    app.at("/health").get(health);
+    app.at("/ready").get(ready);
+    app.at("/version").get(version);
+    app.at("/logs").get(recent_logs);
 
-    let self_url = format!("http://{}:{}", config.address, config.port);
+    let listen = config
+        .listen
+        .clone()
+        .unwrap_or_else(|| Listen::Tcp(format!("{}:{}", config.address, config.port)));
+    // The runner takes jobs from the queue through its HTTP API. Surf can't connect to Unix
+    // sockets, so then it sends its requests to the server directly instead.
+    let (queue_client, self_url) = match &listen {
+        Listen::Tcp(address) => (surf::Client::new(), format!("http://{address}")),
+        Listen::Unix(_) => (
+            surf::Client::with_http_client(app.clone()),
+            "http://localhost".to_string(),
+        ),
+    };
     let repos_root = config.repos_root.clone();
-    let github_client = {
-        let token = {
-            let app_id = octocrab::models::AppId::from(config.app_id);
-            let app_key = jsonwebtoken::EncodingKey::from_rsa_pem(config.app_key.as_bytes())?;
-            octocrab::auth::create_jwt(app_id, &app_key)?
-        };
-        Octocrab::builder().personal_token(token).build()?
+    let checkout_options = CheckoutOptions {
+        submodules: config.submodules,
+        recursive_submodules: config.recursive_submodules,
+        clone_depth: config.clone_depth,
+        keep_ignored: config.keep_ignored,
+        compare_base: config.compare_base,
+        clone_limit: config
+            .max_concurrent_clones
+            .map(|max| std::sync::Arc::new(Semaphore::new(max.get()))),
+        pr_ref: config.pr_ref,
+        signer: config.signing_key.clone().map(|key| {
+            Arc::new(GpgSigner {
+                key,
+                passphrase_file: config.signing_key_passphrase_file.clone(),
+            })
+        }),
+        store_dir: config.store_dir.clone(),
+        data_dir: config.data_dir.clone(),
+        read_only_scripts: config.read_only_scripts.clone(),
+        cargo: Cargo {
+            path: config.cargo_path.clone(),
+            toolchain: config.cargo_toolchain.clone(),
+        },
+        commit_template: config.commit_template.clone(),
     };
+    let hooks = Hooks {
+        pre_script: config.pre_script.clone(),
+        post_script: config.post_script.clone(),
+    };
+    // Fail at startup rather than in every job
+    for script in hooks.pre_script.iter().chain(&hooks.post_script) {
+        if !script.is_file() {
+            return Err(Error::HookNotFound(script.clone()).into());
+        }
+    }
 
-    let tokio_rt = tokio::runtime::Runtime::new()?;
-    async_std::task::spawn(async move {
-        async fn run<P: AsRef<std::path::Path> + AsRef<std::ffi::OsStr>>(
-            repos_root: P,
-            job: Job,
-            github_client: octocrab::Octocrab,
-            //tokio_handle: tokio::runtime::Handle,
+    let job_timeout = config.job_timeout;
+    let keep_workdir_on_failure = config.keep_workdir_on_failure;
+    let notify_url = config.notify_url.clone();
+    let repo_cache = std::sync::Arc::new(std::sync::Mutex::new(RepoCache::new(
+        &repos_root,
+        Limits {
+            max_dirs: config.max_repo_dirs,
+            max_bytes: config.max_repos_size,
+        },
+    )));
+    let engine = Arc::new(ci_script::job::build_engine()?);
+
+    match app_auth.installations() {
+        Ok(_) => state.github_authenticated.store(true, Ordering::SeqCst),
+        Err(e) => log::warn!("Failed to authenticate with Github: {e}"),
+    }
+
+    let job_runner = Runner {
+        repos_root: repos_root.clone(),
+        checkout_options,
+        hooks,
+        command_prefix: runner_prefix,
+        auth: auth.clone(),
+        engine,
+        repo_cache,
+        skip_closed: config.skip_closed.then(|| tokio_rt.handle().clone()),
+    };
+    let runner_label = config.runner_label.clone();
+    let runner_alive = state.runner_alive.clone();
+    let runner = async move {
+        let _alive = RunnerAliveGuard::new(runner_alive);
+
+        async fn get_job<D: std::fmt::Display>(
+            client: &surf::Client,
+            url: D,
+            label: Option<&str>,
+        ) -> anyhow::Result<Job> {
+            let mut query = String::from("long_poll=true");
+            if let Some(label) = label {
+                query.push_str("&label=");
+                query.extend(url::form_urlencoded::byte_serialize(label.as_bytes()));
+            }
+            let mut res = client
+                .post(format!("{}/queue/remove?{query}", url))
+                .await
+                .map_err(|e| e.into_inner())?;
+            if !res.status().is_success() {
+                let ErrorBody { error, code } = res
+                    .body_json::<ErrorBody>()
+                    .await
+                    .map_err(|e| e.into_inner())?;
+                return Err(Error::QueueResponse {
+                    status: res.status().into(),
+                    code,
+                    error,
+                }
+                .into());
+            }
+            res.body_json::<Job>().await.map_err(|e| e.into_inner())
+        }
+
+        async fn post_result<D: std::fmt::Display>(
+            client: &surf::Client,
+            url: D,
+            result: &JobResult,
         ) -> anyhow::Result<()> {
-            //let github = Arc::try_unwrap(github_client).into_inner();
-            //let github = std::sync::Arc::new(std::sync::Mutex::new(github));
-            job.checkout(&repos_root)?
-                .prepare_script(github_client)?
-                .run()?;
+            let res = client
+                .post(format!("{}/queue/result", url))
+                .body_json(result)
+                .map_err(|e| e.into_inner())?
+                .await
+                .map_err(|e| e.into_inner())?;
+            if !res.status().is_success() {
+                anyhow::bail!("Queue responded with {}", res.status());
+            }
             Ok(())
         }
 
-        async fn get_job<D: std::fmt::Display>(url: D) -> anyhow::Result<Job> {
-            let mut res = surf::post(format!("{}/queue/remove?long_poll=true", url))
+        async fn notify(url: &url::Url, notification: &Notification) -> anyhow::Result<()> {
+            let res = surf::post(url.as_str())
+                .body_json(notification)
+                .map_err(|e| e.into_inner())?
                 .await
                 .map_err(|e| e.into_inner())?;
-            res.body_json::<Job>().await.map_err(|e| e.into_inner())
+            if !res.status().is_success() {
+                anyhow::bail!("Responded with {}", res.status());
+            }
+            Ok(())
         }
 
-        let rt_handle = tokio_rt.handle();
+        // Waits between failed attempts to get a job, so an unreachable queue doesn't make the
+        // loop spin
+        let mut retry_wait = backoff::ExponentialBackoff {
+            initial_interval: Duration::from_millis(100),
+            max_interval: MAX_GET_JOB_RETRY_WAIT,
+            max_elapsed_time: None,
+            ..Default::default()
+        };
+        // Threads of timed out jobs that may still be stopping, by their working directory
+        let mut stopping = HashMap::<PathBuf, async_std::task::JoinHandle<_>>::new();
         loop {
-            let github_client = github_client.clone();
-            match get_job(&self_url).await {
+            match get_job(&queue_client, &self_url, runner_label.as_deref()).await {
                 Ok(ref job) => {
+                    retry_wait.reset();
                     log::info!(
                         "Processing command {} in repo {}",
-                        job.command.join(" "),
+                        job.command,
                         job.repository.url
                     );
 
-                    // TODO: Fix block_on
-                    let gh_client = github_client.clone();
-                    let github_installation_client = match rt_handle.block_on(async move {
-                        let installations = gh_client
-                            .apps()
-                            .installations()
-                            .send()
-                            .await
-                            .unwrap()
-                            .take_items();
-                        let mut access_token_req = CreateInstallationAccessToken::default();
-                        access_token_req.repository_ids = vec![job.repository.id];
-                        // TODO: Properly fill-in installation
-                        let access: octocrab::models::InstallationToken = gh_client
-                            .post(
-                                installations[0].access_tokens_url.as_ref().unwrap(),
-                                Some(&access_token_req),
-                            )
-                            .await?;
-                        octocrab::OctocrabBuilder::new()
-                            .personal_token(access.token)
-                            .build()
-                    }) {
-                        Ok(github_installation_client) => github_installation_client,
-                        _ => {
-                            log::warn!("Failed to require octocrab Github client");
-                            return;
-                        }
-                    };
-
-                    let repo_owner = job.repository.owner.login.clone();
-                    let repo_name = job.repository.name.clone();
+                    let full_name =
+                        format!("{}/{}", job.repository.owner.login, job.repository.name);
+                    let github = github::Client::new(auth.clone());
                     let issue_nr = job.issue.number.try_into();
+                    // The phase is set once the outcome is known
+                    let finished = JobEvent::new(job, Phase::Finished);
+                    let issue_url = job.issue.html_url.to_string();
 
-                    let gh_client = github_client.clone();
+                    let job_id = job.id.clone();
+                    let workdirs = (job.repo_dir(&repos_root), job.failed_repo_dir(&repos_root));
+                    // Checking out while the timed out job's script still uses the working
+                    // directory would change it under the script
+                    if let Some(previous) = stopping.remove(&workdirs.0) {
+                        log::info!("Waiting for the timed out job in {}", workdirs.0.display());
+                        let _: anyhow::Result<Option<String>> = previous.await;
+                    }
                     let job = job.clone();
                     //if let Err(job_err) = run(&repos_root, job, gh_client, rt_handle.clone()).await {
-                    if let Err(job_err) = run(&repos_root, job, gh_client).await {
-                        log::warn!("Error running job: {job_err}");
-
-                        // TODO: create separate tokio threadpool and send messages to
-                        // it
-                        if let Ok(issue_nr) = issue_nr {
-                            match rt_handle.block_on(async {
-                                github_installation_client
-                                    .issues(&repo_owner, &repo_name)
-                                    .create_comment(
-                                        issue_nr,
-                                        format!("Error running job: {job_err}"),
-                                    )
-                                    .await
-                            }) {
-                                Ok(_) => {}
-                                Err(err) => log::warn!("Failed to comment on issue: {err}"),
-                            };
+                    let cancelled = Arc::new(AtomicBool::new(false));
+                    let job_key = (job.repository.id, job.issue.number);
+                    // Rejects jobs queued while a timed out job of the same issue still runs
+                    let in_flight = in_flight
+                        .as_ref()
+                        .map(|in_flight| InFlightGuard::acquire(in_flight, job_key));
+                    let busy = matches!(in_flight, Some(None));
+                    cancellations
+                        .lock()
+                        .unwrap_or_else(std::sync::PoisonError::into_inner)
+                        .insert(job_key, (job_id.clone(), cancelled.clone()));
+                    let job_cancelled = cancelled.clone();
+                    let job_runner = job_runner.clone();
+                    let mut job_run = async_std::task::spawn_blocking(move || {
+                        // Held until the job's thread ends, even after a timeout
+                        let _in_flight = match in_flight {
+                            Some(None) => return Err(Error::JobRunning.into()),
+                            in_flight => in_flight,
                         };
+                        job_runner.run(job, job_cancelled)
+                    });
+                    // Cancelling a timed out job kills its cargo command and stops its script at
+                    // the next step, but hooks or Github requests may keep its thread busy a
+                    // little longer. We report the timeout right away, and the next job in the
+                    // same working directory waits for the thread to end.
+                    let job_res = match job_timeout {
+                        Some(timeout) => {
+                            match async_std::future::timeout(timeout, &mut job_run).await {
+                                Ok(res) => res,
+                                Err(_) => {
+                                    cancelled.store(true, Ordering::SeqCst);
+                                    stopping.insert(workdirs.0.clone(), job_run);
+                                    Err(Error::JobTimeout(timeout).into())
+                                }
+                            }
+                        }
+                        None => job_run.await,
                     };
+                    cancellations
+                        .lock()
+                        .unwrap_or_else(std::sync::PoisonError::into_inner)
+                        .remove(&job_key);
+                    // A timed out job may still be running in its working directory (which is
+                    // also why busy jobs are rejected), and cancelled jobs didn't fail by
+                    // themselves
+                    if job_res.is_err()
+                        && keep_workdir_on_failure
+                        && !busy
+                        && !cancelled.load(Ordering::SeqCst)
+                    {
+                        let (dir, kept) = workdirs;
+                        async_std::task::spawn_blocking(move || keep_workdir(&dir, &kept)).await;
+                    }
+                    let comment = match job_res {
+                        Ok(ref comment) => comment.clone(),
+                        Err(ref job_err) => {
+                            log::warn!("Error running job: {job_err}");
+                            Some(format!("Error running job: {job_err}"))
+                        }
+                    };
+
+                    let result = JobResult {
+                        id: job_id,
+                        success: job_res.is_ok(),
+                        logs: comment.clone().unwrap_or_default(),
+                        artifacts: vec![],
+                    };
+                    if let Err(e) = post_result(&queue_client, &self_url, &result).await {
+                        log::warn!("Failed to report result of job {}: {e}", result.id);
+                    }
+                    if let Some(notify_url) = &notify_url {
+                        let notification = Notification::new(finished, issue_url, result.success);
+                        if let Err(e) = notify(notify_url, &notification).await {
+                            log::warn!("Failed to notify {notify_url} of job {}: {e}", result.id);
+                        }
+                    }
+
+                    // TODO: create separate tokio threadpool and send messages to
+                    // it
+                    if let (Some(comment), Ok(issue_nr)) = (comment, issue_nr) {
+                        if let Err(err) = github.create_comment(&full_name, issue_nr, &comment) {
+                            log::warn!("Failed to comment on issue: {err}");
+                        }
+                    };
+                }
+                // Only once no queued jobs are left, see `graceful_shutdown`
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<Error>(),
+                        Some(Error::QueueResponse { code, .. }) if code == "queue_closed"
+                    ) =>
+                {
+                    log::info!("The queue is closed, stopping the runner");
+                    break;
+                }
+                Err(e) => {
+                    let wait = retry_wait.next_backoff().unwrap_or(MAX_GET_JOB_RETRY_WAIT);
+                    log::warn!("Failed to retrieve job from queue, retrying in {wait:?}: {e}");
+                    async_std::task::sleep(wait).await;
                 }
-                Err(e) => log::warn!("Failed to retrieve job from queue: {}", e),
             }
         }
-    });
+    };
+    if config.no_runner {
+        log::info!("Not running jobs, runners take them from /queue/remove");
+    } else {
+        async_std::task::spawn(runner);
+    }
 
-    app.listen((config.address, config.port)).await?;
-    Ok(())
+    let serve = async move {
+        match listen {
+            Listen::Tcp(address) => app.listen(address).await?,
+            Listen::Unix(path) => {
+                // Left behind by an earlier run, binding fails while it exists
+                #[cfg(unix)]
+                if std::fs::symlink_metadata(&path).is_ok_and(|metadata| {
+                    std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type())
+                }) {
+                    std::fs::remove_file(&path)?;
+                }
+                app.listen(format!("http+unix://{}", path.display())).await?
+            }
+        }
+        Ok(())
+    };
+    let shutdown = async {
+        graceful_shutdown(tokio_rt.handle().clone(), state).await;
+        Ok(())
+    };
+    futures_lite::future::or(serve, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        for (pattern, text, expected) in [
+            ("myorg/bench", "myorg/bench", true),
+            ("myorg/bench", "MyOrg/Bench", true),
+            ("myorg/bench", "myorg/bench2", false),
+            ("myorg/*", "myorg/bench", true),
+            ("myorg/*", "myorg/", true),
+            ("myorg/*", "otherorg/bench", false),
+            ("*/bench", "myorg/bench", true),
+            ("*", "myorg/bench", true),
+            ("*", "", true),
+            ("my*/*-bench", "myorg/pallet-bench", true),
+            ("my*/*-bench", "myorg/pallet-bench-old", false),
+            ("a*a", "a", false),
+            ("*b*bc", "bc", false),
+            ("?", "?", true),
+            ("myorg/bench?", "myorg/bench2", false),
+            ("", "", true),
+            ("", "myorg/bench", false),
+        ] {
+            assert_eq!(glob_match(pattern, text), expected, "{:?} {:?}", pattern, text);
+        }
+    }
+
+    fn job() -> Job {
+        let user = json!({
+            "login": "o", "id": 1, "node_id": "", "avatar_url": "http://localhost/",
+            "gravatar_id": "", "url": "http://localhost/", "html_url": "http://localhost/",
+            "followers_url": "http://localhost/", "following_url": "http://localhost/",
+            "gists_url": "http://localhost/", "starred_url": "http://localhost/",
+            "subscriptions_url": "http://localhost/", "organizations_url": "http://localhost/",
+            "repos_url": "http://localhost/", "events_url": "http://localhost/",
+            "received_events_url": "http://localhost/", "type": "User", "site_admin": false,
+        });
+        let repository = serde_json::from_value(json!({
+            "id": 1, "name": "r", "url": "http://localhost/", "owner": user,
+            "clone_url": "http://localhost/",
+        }))
+        .unwrap();
+        let issue = serde_json::from_value(json!({
+            "id": 1, "node_id": "", "url": "http://localhost/",
+            "repository_url": "http://localhost/", "labels_url": "http://localhost/",
+            "comments_url": "http://localhost/", "events_url": "http://localhost/",
+            "html_url": "http://localhost/", "number": 1, "state": "open", "title": "",
+            "body": null, "user": user, "labels": [], "assignees": [],
+            "author_association": "OWNER", "locked": false, "comments": 0,
+            "created_at": "2022-01-01T00:00:00Z", "updated_at": "2022-01-01T00:00:00Z",
+        }))
+        .unwrap();
+        let command = Command::from_script_path("bench/pallet.rhai", vec![]).unwrap();
+        Job::new(command, serde_json::from_value(user).unwrap(), repository, issue)
+    }
+
+    #[test]
+    fn queue_survives_panicking_holder() {
+        let queue: Arc<Mutex<LocalQueue<String, Job>>> = Arc::new(Mutex::new(LocalQueue::new()));
+        let first = job();
+        let first_id = first.id.clone();
+        async_std::task::block_on(queue.lock())
+            .add(first_id.clone(), first)
+            .unwrap();
+
+        let holder = queue.clone();
+        let panicked = std::thread::spawn(move || {
+            async_std::task::block_on(async {
+                let _queue = holder.lock().await;
+                panic!("panicking while holding the queue");
+            })
+        })
+        .join();
+        assert!(panicked.is_err());
+
+        async_std::task::block_on(async {
+            let mut queue = queue.lock().await;
+            let second = job();
+            let second_id = second.id.clone();
+            queue.add(second_id.clone(), second).unwrap();
+            assert_eq!(queue.len(), 2);
+            assert_eq!(queue.remove().unwrap().map(|job| job.id), Some(first_id));
+            assert_eq!(queue.remove().unwrap().map(|job| job.id), Some(second_id));
+            assert!(queue.is_empty());
+        });
+    }
 }
@@ -0,0 +1,41 @@
+/// Number of messages a subscriber can lag behind before it misses some
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+/// Sends a copy of every message to all subscribers. A subscriber that falls behind misses
+/// messages instead of holding up the sender.
+#[derive(Debug)]
+pub struct Broadcast<T> {
+    subscribers: Vec<async_std::channel::Sender<T>>,
+}
+
+impl<T: Clone> Broadcast<T> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: vec![],
+        }
+    }
+
+    pub fn subscribe(&mut self) -> async_std::channel::Receiver<T> {
+        let (sender, receiver) = async_std::channel::bounded(SUBSCRIBER_CAPACITY);
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn send(&mut self, message: T) {
+        self.subscribers
+            .retain(|subscriber| match subscriber.try_send(message.clone()) {
+                Err(async_std::channel::TrySendError::Closed(_)) => false,
+                Err(async_std::channel::TrySendError::Full(_)) => {
+                    log::debug!("Subscriber is lagging behind, dropping message");
+                    true
+                }
+                Ok(()) => true,
+            });
+    }
+}
+
+impl<T: Clone> Default for Broadcast<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Limits on the working directories in a repositories root, `None` means unlimited
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// Maximum number of directories
+    pub max_dirs: Option<usize>,
+    /// Maximum combined size of all directories in bytes
+    pub max_bytes: Option<u64>,
+}
+
+impl Limits {
+    fn exceeded(&self, dirs: usize, bytes: u64) -> bool {
+        matches!(self.max_dirs, Some(max_dirs) if dirs > max_dirs)
+            || matches!(self.max_bytes, Some(max_bytes) if bytes > max_bytes)
+    }
+}
+
+/// Tracks the working directories in a repositories root by last use, and deletes the least
+/// recently used ones when the root exceeds its `Limits`.
+///
+/// Directories leased by a running job are never deleted. Directories the cache doesn't know
+/// about (like repositories cloned by scripts, or checkouts from before a restart) are ordered by
+/// their modification time and only deleted while no job is running, since they might belong to
+/// one.
+#[derive(Debug)]
+pub struct RepoCache {
+    root: PathBuf,
+    limits: Limits,
+    last_used: HashMap<PathBuf, SystemTime>,
+    /// Number of leases per directory
+    in_use: HashMap<PathBuf, usize>,
+}
+
+/// Marks a directory as in use until dropped, see `RepoCache::lease`
+#[derive(Debug)]
+pub struct Lease {
+    cache: Arc<Mutex<RepoCache>>,
+    dir: PathBuf,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // Nothing in here can panic while holding the lock, but don't wedge the cache if it does
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = cache.in_use.get_mut(&self.dir) {
+            *count -= 1;
+            if *count == 0 {
+                cache.in_use.remove(&self.dir);
+            }
+        }
+        cache.last_used.insert(self.dir.clone(), SystemTime::now());
+    }
+}
+
+struct Entry {
+    dir: PathBuf,
+    last_used: SystemTime,
+    tracked: bool,
+    bytes: u64,
+}
+
+impl RepoCache {
+    pub fn new<P: Into<PathBuf>>(root: P, limits: Limits) -> Self {
+        RepoCache {
+            root: root.into(),
+            limits,
+            last_used: HashMap::new(),
+            in_use: HashMap::new(),
+        }
+    }
+
+    /// Mark `dir` (a directory in the root, like `Job::repo_dir`) as used and protect it from
+    /// eviction until the returned `Lease` is dropped.
+    pub fn lease<P: Into<PathBuf>>(cache: &Arc<Mutex<RepoCache>>, dir: P) -> Lease {
+        let dir = dir.into();
+        let mut guard = cache.lock().unwrap_or_else(|e| e.into_inner());
+        *guard.in_use.entry(dir.clone()).or_default() += 1;
+        guard.last_used.insert(dir.clone(), SystemTime::now());
+        Lease {
+            cache: cache.clone(),
+            dir,
+        }
+    }
+
+    /// Delete the least recently used directories until the root is within the limits again, or
+    /// only directories that can't be deleted are left. Returns the deleted directories.
+    pub fn evict(&mut self) -> std::io::Result<Vec<PathBuf>> {
+        if self.limits.max_dirs.is_none() && self.limits.max_bytes.is_none() {
+            return Ok(vec![]);
+        }
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|entry| entry.last_used);
+
+        let mut dirs = entries.len();
+        let mut bytes: u64 = entries.iter().map(|entry| entry.bytes).sum();
+        let busy = !self.in_use.is_empty();
+        let mut evicted = vec![];
+        for entry in entries {
+            if !self.limits.exceeded(dirs, bytes) {
+                break;
+            }
+            if self.in_use.contains_key(&entry.dir) || (busy && !entry.tracked) {
+                continue;
+            }
+            log::info!("Evicting {:?} from the repository cache", entry.dir);
+            if let Err(e) = std::fs::remove_dir_all(&entry.dir) {
+                log::warn!("Failed to evict {:?}: {e}", entry.dir);
+                continue;
+            }
+            self.last_used.remove(&entry.dir);
+            dirs -= 1;
+            bytes -= entry.bytes;
+            evicted.push(entry.dir);
+        }
+        Ok(evicted)
+    }
+
+    fn entries(&self) -> std::io::Result<Vec<Entry>> {
+        let read_dir = match std::fs::read_dir(&self.root) {
+            Ok(read_dir) => read_dir,
+            // Nothing was cloned yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut entries = vec![];
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry?;
+            // Doesn't follow symlinks, so we never delete anything outside the root
+            if !dir_entry.file_type()?.is_dir() {
+                continue;
+            }
+            let dir = dir_entry.path();
+            let (last_used, tracked) = match self.last_used.get(&dir) {
+                Some(last_used) => (*last_used, true),
+                None => (dir_entry.metadata()?.modified()?, false),
+            };
+            let bytes = match self.limits.max_bytes {
+                Some(_) => dir_size(&dir),
+                None => 0,
+            };
+            entries.push(Entry {
+                dir,
+                last_used,
+                tracked,
+                bytes,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// Combined size of the files in `dir`, skipping anything that can't be read
+fn dir_size(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
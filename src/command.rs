@@ -0,0 +1,278 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to split command into words: {0}")]
+    Split(#[from] shell_words::ParseError),
+    #[error("Invalid command `{0}`: only plain names are allowed")]
+    InvalidToken(String),
+    #[error("Invalid option `{0}`")]
+    InvalidOption(String),
+    #[error("Invalid script path {0}: expected a `.rhai` file inside the repository")]
+    InvalidScriptPath(PathBuf),
+}
+
+/// `--name` and `--name=value` options of a command
+pub type Options = BTreeMap<String, Option<String>>;
+
+/// A bot command, e.g. `/benchbot bench --profile=release pallet_balances`, parsed and validated
+/// once when it's received.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Command {
+    /// Directory containing the script, relative to the repository root (e.g. `.github/benchbot`)
+    pub dir: PathBuf,
+    /// Name of the script without the `.rhai` extension
+    pub script: String,
+    /// Arguments following the script name
+    pub args: Vec<String>,
+    /// Options given before the first argument. Everything from the first argument or a bare `--`
+    /// on is an argument, see `split_options`.
+    pub options: Options,
+}
+
+impl Command {
+    /// Parse the first line of a comment `body` into a command, if it starts with one of
+    /// `prefixes`. Whichever prefix is used, the script lives in the directory of the first
+    /// (canonical) one, `.github/<prefix without '/'>/`. Without a script name, `default_script`
+    /// is used.
+    pub fn parse(
+        body: &str,
+        prefixes: &[String],
+        default_script: &str,
+    ) -> Result<Option<Command>, Error> {
+        let rest = match strip_prefix(body, prefixes) {
+            Some(rest) => rest,
+            None => return Ok(None),
+        };
+        let canonical = &prefixes[0];
+        let dir = check_token(canonical.strip_prefix('/').unwrap_or(canonical))?;
+        let line = rest.lines().next().unwrap_or_default();
+        let (options, mut words) = split_options(shell_words::split(line)?, true)?;
+        let script = if words.is_empty() {
+            String::from(default_script)
+        } else {
+            words.remove(0)
+        };
+        Ok(Some(Command {
+            dir: Path::new(".github").join(dir),
+            script: String::from(check_token(&script)?),
+            args: words,
+            options,
+        }))
+    }
+
+    /// Build a command from the path of a script relative to the repository root and the
+    /// arguments to pass to it, like the command line of `cis`.
+    pub fn from_script_path<P: AsRef<Path>>(path: P, args: Vec<String>) -> Result<Command, Error> {
+        let path = path.as_ref();
+        let invalid = || Error::InvalidScriptPath(path.to_path_buf());
+        let inside_repository = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !inside_repository || path.extension() != Some("rhai".as_ref()) {
+            return Err(invalid());
+        }
+        let script = path
+            .file_stem()
+            .and_then(|script| script.to_str())
+            .ok_or_else(invalid)?;
+        let (options, args) = split_options(args, false)?;
+        Ok(Command {
+            dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            script: String::from(check_token(script)?),
+            args,
+            options,
+        })
+    }
+
+    /// Path of the script relative to the repository root
+    pub fn script_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.rhai", self.script))
+    }
+
+    /// The script's directory below `.github` and its name, like `bench/pallet` for
+    /// `.github/bench/pallet.rhai`, which tells apart scripts of the same name in different
+    /// command directories
+    pub fn qualified_name(&self) -> String {
+        let dir = self.dir.strip_prefix(".github").unwrap_or(&self.dir);
+        if dir.as_os_str().is_empty() {
+            return self.script.clone();
+        }
+        format!("{}/{}", dir.display(), self.script)
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.script_path().display())?;
+        for (name, value) in &self.options {
+            match value {
+                Some(value) => write!(f, " --{name}={value}")?,
+                None => write!(f, " --{name}")?,
+            }
+        }
+        for arg in &self.args {
+            write!(f, " {arg}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Strip the first of `prefixes` that `body` starts with and return the remainder. Prefixes only
+/// match as a whole word, so `/bench` doesn't match `/benchbot`.
+fn strip_prefix<'a>(body: &'a str, prefixes: &[String]) -> Option<&'a str> {
+    prefixes.iter().find_map(|prefix| {
+        let rest = body.strip_prefix(prefix.as_str())?;
+        match rest.chars().next() {
+            Some(c) if !c.is_whitespace() => None,
+            _ => Some(rest),
+        }
+    })
+}
+
+/// Only allow plain names as the tokens that make up the script path, so a command can't escape
+/// its directory.
+fn check_token(token: &str) -> Result<&str, Error> {
+    if token.is_empty() || token == "." || token == ".." || token.contains(['/', '\\', '\0']) {
+        Err(Error::InvalidToken(token.into()))
+    } else {
+        Ok(token)
+    }
+}
+
+/// Separate the leading `--name[=value]` options from the other words. Options end at the first
+/// argument (the first word that isn't an option, after the script name if `script_name` is set)
+/// or a bare `--`, so arguments that look like options (like `--nocapture` for a script that
+/// runs tests) are passed to the script as they are.
+fn split_options(words: Vec<String>, script_name: bool) -> Result<(Options, Vec<String>), Error> {
+    let mut options = BTreeMap::new();
+    let mut rest = vec![];
+    let mut words = words.into_iter();
+    while let Some(word) = words.next() {
+        if word == "--" {
+            rest.extend(words);
+            break;
+        }
+        let option = match word.strip_prefix("--") {
+            Some(option) => option,
+            None => {
+                let is_script_name = script_name && rest.is_empty();
+                rest.push(word);
+                if !is_script_name {
+                    rest.extend(words);
+                    break;
+                }
+                continue;
+            }
+        };
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(String::from(value))),
+            None => (option, None),
+        };
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(Error::InvalidOption(word));
+        }
+        options.insert(String::from(name), value);
+    }
+    Ok((options, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> Result<Option<Command>, Error> {
+        Command::parse(body, &["/bench".into(), "/b".into()], "main")
+    }
+
+    fn options(options: &[(&str, Option<&str>)]) -> Options {
+        options
+            .iter()
+            .map(|(name, value)| (String::from(*name), value.map(String::from)))
+            .collect()
+    }
+
+    #[test]
+    fn qualified_names() {
+        let name = |path| Command::from_script_path(path, vec![]).unwrap().qualified_name();
+        assert_eq!(name(".github/bench/pallet.rhai"), "bench/pallet");
+        assert_eq!(name(".github/pallet.rhai"), "pallet");
+        assert_eq!(name("scripts/pallet.rhai"), "scripts/pallet");
+        assert_eq!(name("pallet.rhai"), "pallet");
+        let command = parse("/b pallet").unwrap().unwrap();
+        assert_eq!(command.qualified_name(), "bench/pallet");
+    }
+
+    #[test]
+    fn parse_commands() {
+        for (body, script, args, opts) in [
+            ("/bench", "main", vec![], options(&[])),
+            ("/b pallet", "pallet", vec![], options(&[])),
+            ("/bench pallet a 'b c'", "pallet", vec!["a", "b c"], options(&[])),
+            (
+                "/bench --label=heavy pallet --profile=release --quick a",
+                "pallet",
+                vec!["a"],
+                options(&[("label", Some("heavy")), ("profile", Some("release")), ("quick", None)]),
+            ),
+            ("/bench --quick", "main", vec![], options(&[("quick", None)])),
+            // Options end at the first argument and at `--`
+            ("/bench test a --nocapture", "test", vec!["a", "--nocapture"], options(&[])),
+            ("/bench test -- --nocapture", "test", vec!["--nocapture"], options(&[])),
+            ("/bench -- test --x", "test", vec!["--x"], options(&[])),
+            // Only the first line is parsed
+            ("/bench pallet\nsecond line", "pallet", vec![], options(&[])),
+        ] {
+            let command = parse(body).unwrap().unwrap();
+            assert_eq!(command.dir, Path::new(".github/bench"), "{}", body);
+            assert_eq!(command.script, script, "{}", body);
+            assert_eq!(command.args, args, "{}", body);
+            assert_eq!(command.options, opts, "{}", body);
+        }
+    }
+
+    #[test]
+    fn parse_other_comments() {
+        for body in ["", "bench", "/benchbot pallet", "Run /bench pallet"] {
+            assert!(matches!(parse(body), Ok(None)), "{}", body);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_commands() {
+        for body in [
+            "/bench ..",
+            "/bench a/b",
+            "/bench '' x",
+            "/bench --=x",
+            "/bench --a.b",
+            "/bench 'a",
+        ] {
+            assert!(parse(body).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn from_script_paths() {
+        let args = |args: &[&str]| args.iter().map(|arg| String::from(*arg)).collect();
+        let command =
+            Command::from_script_path(".github/bench/pallet.rhai", args(&["--quick", "a", "--b"]))
+                .unwrap();
+        assert_eq!(command.dir, Path::new(".github/bench"));
+        assert_eq!(command.script, "pallet");
+        assert_eq!(command.args, ["a", "--b"]);
+        assert_eq!(command.options, options(&[("quick", None)]));
+        assert_eq!(command.to_string(), ".github/bench/pallet.rhai --quick a --b");
+        assert_eq!(command.qualified_name(), "bench/pallet");
+        for path in ["../x.rhai", "/abs/x.rhai", ".github/bench/x.txt", ".github/bench"] {
+            assert!(Command::from_script_path(path, vec![]).is_err(), "{}", path);
+        }
+    }
+}
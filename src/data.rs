@@ -0,0 +1,42 @@
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read {0} from the data directory: {1}")]
+    Io(String, std::io::Error),
+    #[error("`{0}` isn't a relative path inside the data directory")]
+    OutsideDir(String),
+}
+
+/// Files the host provides to the scripts of all repositories (like shared benchmark baselines or
+/// tool configs), which scripts can read but not write
+#[derive(Clone, Debug)]
+pub struct DataDir {
+    dir: PathBuf,
+}
+
+impl DataDir {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        DataDir { dir: dir.into() }
+    }
+
+    /// Contents of the file at `path`, relative to the data directory. Paths leading outside of
+    /// it, with `..` or through symlinks, are refused.
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let io = |e| Error::Io(path.into(), e);
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(Error::OutsideDir(path.into()));
+        }
+        let dir = self.dir.canonicalize().map_err(io)?;
+        let full_path = dir.join(relative).canonicalize().map_err(io)?;
+        if !full_path.starts_with(&dir) {
+            return Err(Error::OutsideDir(path.into()));
+        }
+        std::fs::read(full_path).map_err(io)
+    }
+}
@@ -1,9 +1,15 @@
 use crate::api;
+use crate::repo_config::{self, RepoConfig};
+use crate::semaphore::Semaphore;
+use crate::Command;
 use git2::build::{CheckoutBuilder, RepoBuilder};
 use octocrab::models::issues::Issue;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::convert::{TryFrom, TryInto};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use thiserror::Error;
 use rhai::exported_module;
 
@@ -18,18 +24,134 @@ pub enum Error {
     NoScriptFound(#[from] std::io::Error),
     #[error("Failed to find a URL to clone the repository")]
     NoCloneUrl,
-    #[error("Missing bot command")]
-    NoCmd,
     #[error("Failed to checkout repository because path {0} exists but is not a directory")]
     NoDirectory(PathBuf),
+    #[error("Script {0} not found")]
+    ScriptNotFound(PathBuf),
+    #[error("Script {0} is outside of its command directory in the repository")]
+    ScriptOutsideCommandDir(PathBuf),
     #[error("Failed to execute script: {0}")]
     ScriptExecution(#[from] Box<rhai::EvalAltResult>),
     #[error("Failed to parse script")]
     ScriptParse(#[from] rhai::ParseError),
+    #[error("Failed to execute pre-script: {0}")]
+    PreScript(Box<rhai::EvalAltResult>),
+    #[error("Failed to execute post-script: {0}")]
+    PostScript(Box<rhai::EvalAltResult>),
     #[error("Failed to parse cargo command")]
     CargoCmdParse,
     #[error("Failed to parse Repository: missing field \"{0}\"")]
     MissingRepositoryField(String),
+    #[error("Failed to find the merge base with the default branch: {0}")]
+    NoMergeBase(git2::Error),
+    #[error("Failed to remove stale base checkout: {0}")]
+    StaleBaseCheckout(std::io::Error),
+    #[error("Failed to fetch `{0}`, the remote doesn't have it")]
+    RefNotFound(String),
+    #[error(
+        "Pull request #{0} can't be merged (Github has no merge commit for it), resolve its \
+         conflicts or run the job on its head instead"
+    )]
+    NotMergeable(i64),
+    #[error("The repository of the pull request's branch was deleted")]
+    NoHeadRepository,
+    #[error("{0}")]
+    RepoConfig(#[from] repo_config::Error),
+    #[error("Scripts in {0} are not enabled in the `script_dirs` of {}", repo_config::PATH)]
+    ScriptDirNotAllowed(PathBuf),
+    #[error("Script timed out after {0:?} (the `timeout` of {})", repo_config::PATH)]
+    ScriptTimeout(std::time::Duration),
+    #[error("The job was cancelled")]
+    Cancelled,
+}
+
+/// Name of the worktree (and its branch) holding the base checkout, see
+/// `CheckoutOptions::compare_base`
+const BASE_WORKTREE: &str = "merge-base";
+
+thread_local! {
+    /// The job (as `owner/repo#issue`) whose script runs on this thread, to tag the script's log
+    /// messages with, see `RunnableJob::run`
+    static SCRIPT_JOB: RefCell<String> = const { RefCell::new(String::new()) };
+
+    /// What the `cargo` syntax needs of the job whose script runs on this thread, see
+    /// `RunnableJob::run`
+    static SCRIPT_CONTEXT: RefCell<Option<ScriptContext>> = const { RefCell::new(None) };
+}
+
+/// The job state `cargo` commands run with. The engine is shared between jobs, and functions
+/// defined in a script don't see the constants of its scope, so it can't come from either.
+#[derive(Clone, Debug)]
+struct ScriptContext {
+    /// Directory of the job's `REPO`, which `cargo` runs in
+    dir: PathBuf,
+    cargo: api::cargo::Cargo,
+    /// See `CheckedoutJob::read_only`, `cargo` fails if set
+    read_only: bool,
+    /// See `RunnableJob::cancel_token`
+    cancelled: Option<Arc<AtomicBool>>,
+    /// Set once the timeout of `.github/bbot.toml` passes, which stops the scripts like
+    /// `cancelled` does, but without making the job count as cancelled
+    timed_out: Option<Arc<AtomicBool>>,
+    /// See `RunnableJob::record_cargo`
+    cargo_log: Option<CargoLog>,
+}
+
+impl ScriptContext {
+    /// Whether the scripts should stop, because the job was cancelled or timed out
+    fn stopped(&self) -> bool {
+        self.cancelled
+            .iter()
+            .chain(&self.timed_out)
+            .any(|flag| flag.load(Ordering::SeqCst))
+    }
+
+    /// The context of the job whose script runs on this thread
+    fn current() -> Result<Self, Box<rhai::EvalAltResult>> {
+        SCRIPT_CONTEXT
+            .with(|context| context.borrow().clone())
+            .ok_or_else(|| "`cargo` can only be used in the script of a job".into())
+    }
+}
+
+/// Log `text` written by a script (with `print`, `debug` or `log`), tagged with its job
+fn script_log(level: log::Level, text: &str) {
+    SCRIPT_JOB.with(|job| log::log!(level, "[{}] {text}", job.borrow()));
+}
+
+/// Ends a script with `finish`, carrying its message. It's a termination rather than an error,
+/// so scripts can't catch it.
+#[derive(Clone, Debug)]
+struct Finished(String);
+
+/// Ends the script of a cancelled or timed out job, see `RunnableJob::cancel_token`
+#[derive(Clone, Debug)]
+struct Cancelled;
+
+/// The message of the `finish` that ended a script, if it ended that way
+fn finished_message(e: &rhai::EvalAltResult) -> Option<String> {
+    match e {
+        rhai::EvalAltResult::ErrorTerminated(token, _) => {
+            token.clone().try_cast::<Finished>().map(|finished| finished.0)
+        }
+        // Called from a function of the script or a module
+        rhai::EvalAltResult::ErrorInFunctionCall(_, _, e, _)
+        | rhai::EvalAltResult::ErrorInModule(_, e, _) => finished_message(e),
+        _ => None,
+    }
+}
+
+/// The `cargo` commands a job ran, oldest first
+pub type CargoLog = Arc<std::sync::Mutex<Vec<CargoRecord>>>;
+
+/// A `cargo` command run by a script, see `RunnableJob::record_cargo`
+#[derive(Clone, Debug, Serialize)]
+pub struct CargoRecord {
+    /// The arguments given to `cargo`, without the flags from the options
+    pub args: Vec<String>,
+    pub duration_ms: u64,
+    #[serde(flatten)]
+    pub result: api::cargo::CargoResult,
 }
 
 // We use our own `Repository` definition instead of `octocrab::models::Repository` so we can make
@@ -64,39 +186,236 @@ impl std::convert::TryFrom<octocrab::models::Repository> for Repository {
     }
 }
 
+impl Repository {
+    pub fn clone_url(&self) -> &url::Url {
+        &self.clone_url
+    }
+}
+
+/// The branch a pull request merges from and the repository it's in, which is a fork for pull
+/// requests from forks. `Job::checkout` doesn't need it (it fetches `pull/N/head` from the base
+/// repository), but scripts do to push to the pull request's branch.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PullRequestHead {
+    /// Branch name, without `refs/heads/`
+    pub branch: String,
+    pub repository: Repository,
+    /// Whether the author allowed maintainers of the base repository to push to `branch`
+    pub maintainer_can_modify: bool,
+}
+
+impl std::convert::TryFrom<octocrab::models::pulls::PullRequest> for PullRequestHead {
+    type Error = Error;
+
+    fn try_from(pr: octocrab::models::pulls::PullRequest) -> Result<Self, Self::Error> {
+        let repository = pr.head.repo.ok_or(Error::NoHeadRepository)?.try_into()?;
+        Ok(PullRequestHead {
+            branch: pr.head.ref_field,
+            repository,
+            maintainer_can_modify: pr.maintainer_can_modify,
+        })
+    }
+}
+
+/// Options controlling how `Job::checkout` prepares the working directory
+#[derive(Clone, Debug, Default)]
+pub struct CheckoutOptions {
+    /// Initialize and update the repository's git submodules after checking out
+    pub submodules: bool,
+    /// Also update nested submodules (implies `submodules`)
+    pub recursive_submodules: bool,
+    /// Only clone and fetch this many commits of history, `None` fetches everything
+    pub clone_depth: Option<i32>,
+    /// Keep ignored files (like cargo's `target/`) when resetting an existing checkout so build
+    /// caches survive between jobs. Tracked files are still forced to the new ref.
+    pub keep_ignored: bool,
+    /// Also check out the merge base of the pull request and the repository's default branch, in
+    /// a worktree next to the job's repository (see `Job::base_repo_dir`), so scripts can compare
+    /// the two. Needs enough history (`clone_depth`) to find the merge base.
+    pub compare_base: bool,
+    /// Shared by all jobs to limit how many clones and fetches (`checkout` and the script's
+    /// `Git.clone`) run at the same time. `None` means unlimited.
+    pub clone_limit: Option<Arc<Semaphore>>,
+    /// Which ref of the pull request to check out
+    pub pr_ref: PrRef,
+    /// Signs the commits of all jobs' scripts, see `CheckedoutJob::signer`
+    pub signer: Option<Arc<api::git::GpgSigner>>,
+    /// Directory holding the stores of all repositories, see `CheckedoutJob::store_dir`
+    pub store_dir: Option<PathBuf>,
+    /// Directory of files the scripts of all jobs can read, see `CheckedoutJob::data_dir`
+    pub data_dir: Option<PathBuf>,
+    /// Cargo the scripts of all jobs run
+    pub cargo: api::cargo::Cargo,
+    /// Template of the messages of `commit_templated`, see `api::git::CommitTemplate`
+    pub commit_template: Option<String>,
+    /// Scripts that run read-only, by their `Command::qualified_name` (like `bench/lint`), see
+    /// `CheckedoutJob::read_only`. It's an option of the host rather than part of the
+    /// repository's configuration.
+    pub read_only_scripts: Vec<String>,
+}
+
+/// The refs Github keeps for every pull request, see `CheckoutOptions::pr_ref`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PrRef {
+    /// `pull/N/head`, the tip of the pull request's branch
+    #[default]
+    Head,
+    /// `pull/N/merge`, the commit Github would create when merging the pull request into its base
+    /// branch. It only exists while the pull request can be merged without conflicts.
+    Merge,
+}
+
+impl std::str::FromStr for PrRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "head" => Ok(PrRef::Head),
+            "merge" => Ok(PrRef::Merge),
+            _ => Err(format!("Unknown pull request ref `{s}`, expected `head` or `merge`")),
+        }
+    }
+}
+
+impl CheckoutOptions {
+    /// `what` describes the clone or fetch in the progress logs
+    fn fetch_options(&self, what: String) -> git2::FetchOptions<'static> {
+        let mut fetch_options = api::git::fetch_options(what);
+        if let Some(depth) = self.clone_depth {
+            fetch_options.depth(depth);
+        }
+        fetch_options
+    }
+
+    fn checkout_builder(&self) -> CheckoutBuilder<'static> {
+        let mut checkout = CheckoutBuilder::new();
+        checkout
+            .remove_untracked(true)
+            .remove_ignored(!self.keep_ignored)
+            .force();
+        checkout
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Job {
-    pub command: Vec<String>,
-    //pub user: octocrab::models::User,
+    /// Identifies the job in the queue and its result
+    pub id: String,
+    pub command: Command,
+    /// The user whose comment triggered the job
+    pub user: octocrab::models::User,
     pub repository: Repository,
     pub issue: Issue,
+    /// Head of the pull request the command was given on, `None` for issues or if it couldn't be
+    /// looked up
+    #[serde(default)]
+    pub head: Option<PullRequestHead>,
+    /// Pool of runners the job is for, from the command's `--label=<label>` option. Runners
+    /// asking for jobs of a label (see `Job::has_label`) only get jobs with that label.
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+/// Outcome of a finished job, as reported by a runner
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JobResult {
+    /// Id of the `Job` this is the result of
+    pub id: String,
+    pub success: bool,
+    /// Output of the job, like the error message on failure
+    pub logs: String,
+    /// Files produced by the job
+    pub artifacts: Vec<String>,
+}
+
+/// A job waiting in the queue, as listed by the webhook reactor's `/queue` endpoint
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct QueuedJob {
+    pub id: String,
+    /// Number of jobs ahead of this one
+    pub position: usize,
+    /// `owner/name` of the repository
+    pub repository: String,
+    /// Number of the issue or pull request the command was given on
+    pub issue: i64,
+    pub command: String,
 }
 
 impl Job {
-    fn pr_branch(&self) -> String {
-        format!("pull/{}/head", self.issue.number)
+    /// A job with a new id, see `Job::new_id`
+    pub fn new(
+        command: Command,
+        user: octocrab::models::User,
+        repository: Repository,
+        issue: Issue,
+    ) -> Self {
+        let label = command
+            .options
+            .get("label")
+            .cloned()
+            .flatten()
+            .filter(|label| !label.is_empty());
+        Job {
+            id: Self::new_id(repository.id, issue.number),
+            command,
+            user,
+            repository,
+            issue,
+            head: None,
+            label,
+        }
+    }
+
+    /// Whether the job is labeled `label`, where an empty `label` stands for jobs without one
+    pub fn has_label(&self, label: &str) -> bool {
+        self.label.as_deref().unwrap_or_default() == label
+    }
+
+    /// Unique id for a job of the repository on issue `issue_nr`, like
+    /// `1296269-1347-936da01f-9abd-4d9d-80c7-02af85c822a8`. Repository names and commands aren't
+    /// part of it, since names aren't unique across owners and ids go into URLs (see
+    /// `/queue/result/:id`).
+    pub fn new_id(repository: octocrab::models::RepositoryId, issue_nr: i64) -> String {
+        format!("{}-{}-{}", repository, issue_nr, uuid::Uuid::new_v4())
     }
 
-    // This function assumes at most one Job::checkout() run at any time. This requirement is
-    // because of FS mutation, which unfortunately the type checker can't help us with. Currently
-    // this is guaranteed by spawning only one thread that synchronously runs jobs.
-    pub fn checkout<R: AsRef<Path> + Copy>(&self, root: R) -> Result<CheckedoutJob, Error>
+    fn pr_branch(&self, pr_ref: PrRef) -> String {
+        let name = match pr_ref {
+            PrRef::Head => "head",
+            PrRef::Merge => "merge",
+        };
+        format!("pull/{}/{}", self.issue.number, name)
+    }
+
+    // This function assumes no other job uses the working directory (`repo_dir`, and
+    // `base_repo_dir`) while it runs, since the type checker can't help us with FS mutation.
+    // Checkouts of different repositories may run at the same time (`clone_limit` bounds how
+    // many), e.g. while a timed out job is still stopping in the background. The runner makes the
+    // next job in the same working directory wait until the timed out one has stopped, see
+    // `stopping` in the runner loop of `cis-gh-reactor`.
+    pub fn checkout<R: AsRef<Path> + Copy>(
+        &self,
+        root: R,
+        options: &CheckoutOptions,
+    ) -> Result<CheckedoutJob, Error>
     where
         PathBuf: From<R>,
     {
+        let _permit = options.clone_limit.as_ref().map(|limit| limit.acquire());
+        // Not counting the wait for the permit
+        let started = std::time::Instant::now();
         let dir = self.repo_dir(root);
-        let branch = self.pr_branch();
+        let branch = self.pr_branch(options.pr_ref);
         let repo = match std::fs::metadata(&dir) {
             Ok(metadata) if metadata.is_dir() => git2::Repository::open(&dir)?,
             Err(_) => {
                 // Path doesn't exist
                 let url = self.repository.clone_url.as_ref();
 
-                let mut checkout = CheckoutBuilder::new();
-                checkout.remove_untracked(true).remove_ignored(true).force();
                 log::info!("Cloning {} to {:?}", &self.repository.clone_url, &dir);
                 RepoBuilder::new()
-                    .with_checkout(checkout)
+                    .fetch_options(options.fetch_options(format!("Cloning {url}")))
+                    .with_checkout(options.checkout_builder())
                     .clone(url.as_ref(), &dir)?
             }
             Ok(_) => {
@@ -107,270 +426,1017 @@ impl Job {
 
         log::info!("Fetching {} in {:?}", branch, dir);
         repo.find_remote("origin")?.fetch(
-            &[&format!("refs/{}:refs/heads/{}", branch, branch)],
-            None,
+            &[&api::git::FetchRef::new(&branch).refspec()],
+            Some(&mut options.fetch_options(format!("Fetching {branch}"))),
             None,
         )?;
 
-        let rev = repo.revparse_single("FETCH_HEAD")?;
+        // Fetching a ref the remote doesn't have succeeds, but leaves `FETCH_HEAD` empty
+        let rev = match fetched(&repo)? {
+            Some(oid) => repo.find_object(oid, None)?,
+            None if options.pr_ref == PrRef::Merge => {
+                return Err(Error::NotMergeable(self.issue.number))
+            }
+            None => return Err(Error::RefNotFound(branch)),
+        };
         repo.reset(
             &rev,
             git2::ResetType::Hard,
-            Some(
-                CheckoutBuilder::new()
-                    .remove_untracked(true)
-                    .remove_ignored(true)
-                    .force(),
-            ),
+            Some(&mut options.checkout_builder()),
+        )?;
+
+        if options.submodules || options.recursive_submodules {
+            api::git::update_submodules(&repo, options.recursive_submodules)?;
+        }
+
+        // Github's merge commits have the base branch as first and the pull request's head as
+        // second parent
+        let head_sha = match options.pr_ref {
+            PrRef::Head => rev.id(),
+            PrRef::Merge => rev.peel_to_commit()?.parent_id(1)?,
+        };
+
+        log::info!("Fetching default branch in {:?}", dir);
+        repo.find_remote("origin")?.fetch(
+            &["HEAD"],
+            Some(&mut options.fetch_options("Fetching default branch".into())),
+            None,
         )?;
+        let default_branch = repo.revparse_single("FETCH_HEAD")?.id();
+        // Not from the checkout, which the pull request's author controls
+        let repo_config = RepoConfig::load_committed(&repo, default_branch)?;
+
+        let base_dir = if options.compare_base {
+            Some(self.checkout_base(&repo, rev.id(), default_branch, root, options)?)
+        } else {
+            None
+        };
+        let checkout_duration = started.elapsed();
+        log::info!("Checked out {} in {:?} in {:?}", branch, dir, checkout_duration);
 
         let job = CheckedoutJob {
             //job: self.clone(),
             command: self.command.clone(),
             dir,
+            base_dir,
             clone_dir: PathBuf::from(root),
+            clone_limit: options.clone_limit.clone(),
             gh_repo: self.repository.clone(),
             gh_issue: Some(self.issue.clone()),
+            gh_user: Some(self.user.clone()),
+            head_sha: Some(head_sha.to_string()),
+            pr_head: self.head.clone(),
+            signer: options.signer.clone(),
+            store_dir: options.store_dir.clone(),
+            data_dir: options.data_dir.clone(),
+            cargo: options.cargo.clone(),
+            commit_template: options.commit_template.clone(),
+            read_only: options
+                .read_only_scripts
+                .contains(&self.command.qualified_name()),
+            checkout_duration: Some(checkout_duration),
+            repo_config,
         };
         Ok(job)
     }
 
-    fn repo_dir<R: AsRef<Path>>(&self, root: R) -> PathBuf
+    /// Check out the merge base of `head` and the `default_branch` of the remote in the worktree
+    /// at `base_repo_dir`, creating it if needed
+    fn checkout_base<R: AsRef<Path> + Copy>(
+        &self,
+        repo: &git2::Repository,
+        head: git2::Oid,
+        default_branch: git2::Oid,
+        root: R,
+        options: &CheckoutOptions,
+    ) -> Result<PathBuf, Error>
+    where
+        PathBuf: From<R>,
+    {
+        let merge_base = repo
+            .merge_base(head, default_branch)
+            .map_err(Error::NoMergeBase)?;
+        let commit = repo.find_commit(merge_base)?;
+
+        let dir = self.base_repo_dir(root);
+        let base_repo = match git2::Repository::open(&dir) {
+            Ok(base_repo) => base_repo,
+            Err(_) => {
+                // Either the base checkout doesn't exist yet, or it belonged to a repository that
+                // was evicted (or the other way around), so start over
+                if dir.exists() {
+                    std::fs::remove_dir_all(&dir).map_err(Error::StaleBaseCheckout)?;
+                }
+                if let Ok(worktree) = repo.find_worktree(BASE_WORKTREE) {
+                    worktree.prune(Some(git2::WorktreePruneOptions::new().valid(true)))?;
+                }
+                log::info!("Creating base worktree {:?}", dir);
+                let branch = repo.branch(BASE_WORKTREE, &commit, true)?;
+                let mut add_options = git2::WorktreeAddOptions::new();
+                add_options.reference(Some(branch.get()));
+                let worktree = repo.worktree(BASE_WORKTREE, &dir, Some(&add_options))?;
+                git2::Repository::open_from_worktree(&worktree)?
+            }
+        };
+
+        log::info!("Checking out merge base {} in {:?}", merge_base, dir);
+        base_repo.set_head_detached(merge_base)?;
+        base_repo.reset(
+            commit.as_object(),
+            git2::ResetType::Hard,
+            Some(&mut options.checkout_builder()),
+        )?;
+        if options.submodules || options.recursive_submodules {
+            api::git::update_submodules(&base_repo, options.recursive_submodules)?;
+        }
+        Ok(dir)
+    }
+
+    /// Working directory of the job's repository in `root`
+    pub fn repo_dir<R: AsRef<Path>>(&self, root: R) -> PathBuf
+    where
+        PathBuf: From<R>,
+    {
+        PathBuf::from(root).join(self.repo_dir_name())
+    }
+
+    /// Working directory of the base checkout next to `repo_dir`, see
+    /// `CheckoutOptions::compare_base`
+    pub fn base_repo_dir<R: AsRef<Path>>(&self, root: R) -> PathBuf
+    where
+        PathBuf: From<R>,
+    {
+        PathBuf::from(root).join(format!("{}_base", self.repo_dir_name()))
+    }
+
+    /// Where the working directory of the last failed job of this repository, issue and user
+    /// combination is kept, if the runner keeps them
+    pub fn failed_repo_dir<R: AsRef<Path>>(&self, root: R) -> PathBuf
     where
         PathBuf: From<R>,
     {
-        let mut full_path = PathBuf::from(root);
-        let dir_name = format!(
+        PathBuf::from(root).join(format!("{}_failed", self.repo_dir_name()))
+    }
+
+    fn repo_dir_name(&self) -> String {
+        format!(
             "{}_{}_{}_{}_{}",
             self.repository.id,
             self.issue.number,
             self.issue.user.login,
             &self.repository.owner.login,
             &self.repository.name
+        )
+    }
+}
+
+/// The commit the last fetch in `repo` got, `None` if it didn't get anything
+fn fetched(repo: &git2::Repository) -> Result<Option<git2::Oid>, git2::Error> {
+    let mut fetched = None;
+    repo.fetchhead_foreach(|_, _, oid, _| {
+        fetched = Some(*oid);
+        false
+    })
+    .or_else(|e| match e.code() {
+        // Stopping at the first entry reports the callback's `false` as an error
+        git2::ErrorCode::User => Ok(()),
+        _ => Err(e),
+    })?;
+    Ok(fetched)
+}
+
+/// A `cargo` command of a script, ready to run
+struct CargoCommand {
+    /// The arguments given to `cargo`, see `CargoRecord::args`
+    args: Vec<String>,
+    run: api::cargo::Run,
+}
+
+impl CargoCommand {
+    /// Parse the (shell quoted) `args` of a cargo command of the script running on this thread
+    fn new(args: &str, options: api::cargo::Options) -> Result<Self, Box<rhai::EvalAltResult>> {
+        let context = ScriptContext::current()?;
+        if context.read_only {
+            return Err(format!("{}", api::git::Error::ReadOnly("cargo")).into());
+        }
+        let args = shell_words::split(args).map_err(|_| "Failed to parse `cargo` arguments")?;
+        let mut run = api::cargo::Run::new(&args, &context.dir)
+            .options(options)
+            .cargo(context.cargo);
+        for flag in context.cancelled.into_iter().chain(context.timed_out) {
+            run = run.cancel(flag);
+        }
+        Ok(CargoCommand { args, run })
+    }
+}
+
+/// Record the `result` of the cargo command with `args` that took `duration` (see
+/// `RunnableJob::record_cargo`), and fail the script if the job was cancelled
+fn finish_cargo(
+    args: Vec<String>,
+    duration: std::time::Duration,
+    result: api::cargo::CargoResult,
+) -> Result<api::cargo::CargoResult, Box<rhai::EvalAltResult>> {
+    let context = ScriptContext::current()?;
+    if let Some(log) = &context.cargo_log {
+        log.lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .push(CargoRecord {
+                args,
+                duration_ms: u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+                result: result.clone(),
+            });
+    }
+    if context.stopped() {
+        return Err("`cargo` was cancelled".into());
+    }
+    Ok(result)
+}
+
+/// Build the `rhai::Engine` with the entire CI script API registered. The engine doesn't hold any
+/// job specific state, so it can be built once and shared by all jobs (see
+/// `CheckedoutJob::prepare_script`).
+pub fn build_engine() -> Result<rhai::Engine, Error> {
+    let mut engine = rhai::Engine::new();
+
+    engine
+        .register_type::<api::cargo::CargoResult>()
+        .register_fn("is_ok", api::cargo::CargoResult::is_ok)
+        .register_get("stdout", api::cargo::CargoResult::get_stdout)
+        .register_get("stderr", api::cargo::CargoResult::get_stderr)
+        .register_fn("tail", api::cargo::tail);
+
+    // Either `cargo "<args>"` or `cargo <options map> "<args>"`. The options are followed by a
+    // second expression, which starts with a string or a variable/function name.
+    engine.register_custom_syntax_raw(
+        "cargo",
+        |symbols, look_ahead| match symbols.len() {
+            1 => Ok(Some("$expr$".into())),
+            2 if look_ahead == "string"
+                || look_ahead.starts_with(|c: char| c.is_alphabetic() || c == '_') =>
+            {
+                Ok(Some("$expr$".into()))
+            }
+            _ => Ok(None),
+        },
+        false,
+        |context, inputs| {
+            let (options, expr) = match inputs {
+                [options, expr] => {
+                    let options = context
+                        .eval_expression_tree(options)?
+                        .try_cast::<rhai::Map>()
+                        .ok_or("`cargo` options must be an object map")?;
+                    (api::cargo::Options::from_map(options)?, expr)
+                }
+                [expr] => (api::cargo::Options::default(), expr),
+                _ => unreachable!("`cargo` takes one or two expressions"),
+            };
+            let value = context
+                .eval_expression_tree(expr)?
+                .try_cast::<String>()
+                .ok_or("Failed to parse `cargo` arguments into a string")?;
+
+            let cargo = CargoCommand::new(&value, options)?;
+            let started = std::time::Instant::now();
+            let result = cargo.run.run();
+            finish_cargo(cargo.args, started.elapsed(), result)
+                .map(rhai::Dynamic::from)
+        },
+    );
+
+    // `cargo_parallel(commands, max)` runs the `commands` (like the arguments of `cargo`, or
+    // object maps with the arguments as `args` next to the options), at most `max` at a time,
+    // and returns their results in the same order. It's syntax like `cargo`, with which it
+    // shares the `ScriptContext`.
+    engine.register_custom_syntax(
+        ["cargo_parallel", "(", "$expr$", ",", "$expr$", ")"],
+        false,
+        |context, inputs| {
+            let commands = context
+                .eval_expression_tree(&inputs[0])?
+                .try_cast::<rhai::Array>()
+                .ok_or("`cargo_parallel` commands must be an array")?;
+            let max = context
+                .eval_expression_tree(&inputs[1])?
+                .as_int()
+                .ok()
+                .and_then(|max| usize::try_from(max).ok())
+                .filter(|max| *max > 0)
+                .ok_or("`cargo_parallel` needs a positive maximum number of commands to run")?;
+            // Set up every command before running any, so a typo doesn't leave the others running
+            let mut cargos = vec![];
+            for command in commands {
+                let (args, options) = if command.is::<rhai::Map>() {
+                    let mut options = command.cast::<rhai::Map>();
+                    let args = options
+                        .remove("args")
+                        .and_then(|args| args.try_cast::<String>())
+                        .ok_or("`cargo_parallel` commands need their arguments as `args`")?;
+                    (args, api::cargo::Options::from_map(options)?)
+                } else {
+                    let args = command
+                        .try_cast::<String>()
+                        .ok_or("Failed to parse `cargo` arguments into a string")?;
+                    (args, api::cargo::Options::default())
+                };
+                cargos.push(CargoCommand::new(&args, options)?);
+            }
+
+            let (args, runs): (Vec<_>, Vec<_>) =
+                cargos.into_iter().map(|cargo| (cargo.args, cargo.run)).unzip();
+            let results = api::cargo::run_parallel(runs, max);
+            let mut array = rhai::Array::new();
+            for (args, (duration, result)) in args.into_iter().zip(results) {
+                let result = finish_cargo(args, duration, result)?;
+                array.push(rhai::Dynamic::from(result));
+            }
+            Ok(array.into())
+        },
+    )?;
+
+    engine
+        .register_type::<api::Issue>()
+        .register_result_fn("comment", api::Issue::create_comment::<String>)
+        .register_result_fn("comment", api::Issue::create_comment::<&str>)
+        .register_result_fn(
+            "comment",
+            api::Issue::create_comment::<rhai::ImmutableString>,
+        )
+        .register_result_fn("check_run", api::Issue::create_check_run::<String>)
+        .register_result_fn("check_run", api::Issue::create_check_run::<&str>)
+        .register_result_fn(
+            "check_run",
+            api::Issue::create_check_run::<rhai::ImmutableString>,
+        )
+        .register_result_fn("comment_gist", api::Issue::create_comment_gist::<String>)
+        .register_result_fn("comment_gist", api::Issue::create_comment_gist::<&str>)
+        .register_result_fn(
+            "comment_gist",
+            api::Issue::create_comment_gist::<rhai::ImmutableString>,
+        )
+        .register_result_fn("comment_table", api::Issue::comment_table)
+        .register_get("number", api::Issue::get_number)
+        .register_get("title", api::Issue::get_title)
+        .register_get("is_pull_request", api::Issue::get_is_pull_request)
+        .register_get("html_url", api::Issue::get_html_url)
+        .register_get("head_repo", api::Issue::get_head_repo)
+        .register_get("head_branch", api::Issue::get_head_branch)
+        .register_get("head_clone_url", api::Issue::get_head_clone_url)
+        .register_get("is_fork", api::Issue::get_is_fork);
+
+    engine
+        .register_type::<api::git::Git>()
+        .register_result_fn("clone", api::git::Git::clone::<String>)
+        .register_result_fn("clone", api::git::Git::clone::<&str>)
+        .register_result_fn("clone", api::git::Git::clone::<rhai::ImmutableString>)
+        .register_result_fn("fetch_file", api::git::Git::fetch_file);
+
+    engine
+        .register_type::<api::git::LocalRepo>()
+        .register_result_fn("read", api::git::LocalRepo::read_file::<PathBuf>)
+        .register_result_fn(
+            "read",
+            api::git::LocalRepo::read_file::<api::git::DirEntryPath>,
+        )
+        .register_result_fn("read", api::git::LocalRepo::read_file::<&Path>)
+        .register_result_fn("read", api::git::LocalRepo::read_file::<String>)
+        .register_result_fn("read", api::git::LocalRepo::read_file::<&str>)
+
+        .register_result_fn("write", api::git::LocalRepo::write_file::<PathBuf>)
+        .register_result_fn(
+            "write",
+            api::git::LocalRepo::write_file::<api::git::DirEntryPath>,
+        )
+        .register_result_fn("write", api::git::LocalRepo::write_file::<&Path>)
+        .register_result_fn("write", api::git::LocalRepo::write_file::<String>)
+        .register_result_fn("write", api::git::LocalRepo::write_file::<&str>)
+        .register_result_fn(
+            "workspace_members",
+            api::git::LocalRepo::pub_workspace_members_root,
+        )
+        .register_result_fn(
+            "workspace_members",
+            api::git::LocalRepo::pub_workspace_members::<api::git::DirEntryPath>,
+        )
+        .register_result_fn(
+            "workspace_members",
+            api::git::LocalRepo::pub_workspace_members::<String>,
+        )
+        .register_result_fn(
+            "workspace_members",
+            api::git::LocalRepo::pub_workspace_members::<&str>,
+        )
+        .register_result_fn("read_text", api::git::LocalRepo::read_text::<PathBuf>)
+        .register_result_fn(
+            "read_text",
+            api::git::LocalRepo::read_text::<api::git::DirEntryPath>,
+        )
+        .register_result_fn("read_text", api::git::LocalRepo::read_text::<&Path>)
+        .register_result_fn("read_text", api::git::LocalRepo::read_text::<String>)
+        .register_result_fn("read_text", api::git::LocalRepo::read_text::<&str>)
+        .register_result_fn("write_text", api::git::LocalRepo::write_text::<PathBuf>)
+        .register_result_fn(
+            "write_text",
+            api::git::LocalRepo::write_text::<api::git::DirEntryPath>,
+        )
+        .register_result_fn("write_text", api::git::LocalRepo::write_text::<&Path>)
+        .register_result_fn("write_text", api::git::LocalRepo::write_text::<String>)
+        .register_result_fn("write_text", api::git::LocalRepo::write_text::<&str>)
+
+        .register_result_fn("ls", api::git::LocalRepo::list_files)
+        .register_result_fn("ls", api::git::LocalRepo::list_files_in_dir::<PathBuf>)
+        .register_result_fn("ls", api::git::LocalRepo::list_files_in_dir::<&Path>)
+        .register_result_fn("ls", api::git::LocalRepo::list_files_in_dir::<String>)
+        .register_result_fn("ls", api::git::LocalRepo::list_files_in_dir::<&str>)
+        .register_result_fn("ls_files", api::git::LocalRepo::ls_files)
+        .register_result_fn("ls_files", api::git::LocalRepo::ls_files_in_dir::<PathBuf>)
+        .register_result_fn("ls_files", api::git::LocalRepo::ls_files_in_dir::<&Path>)
+        .register_result_fn("ls_files", api::git::LocalRepo::ls_files_in_dir::<String>)
+        .register_result_fn("ls_files", api::git::LocalRepo::ls_files_in_dir::<&str>)
+        .register_result_fn("add", api::git::LocalRepo::add::<api::git::DirEntryPath>)
+        .register_result_fn("add", api::git::LocalRepo::add::<String>)
+        .register_result_fn("add", api::git::LocalRepo::add::<&str>)
+        .register_result_fn("reset_path", api::git::LocalRepo::reset_path::<api::git::DirEntryPath>)
+        .register_result_fn("reset_path", api::git::LocalRepo::reset_path::<String>)
+        .register_result_fn("reset_path", api::git::LocalRepo::reset_path::<&str>)
+        .register_result_fn("set_config", api::git::LocalRepo::set_config::<String, String>)
+        .register_result_fn("set_config", api::git::LocalRepo::set_config::<&str, &str>)
+        .register_result_fn(
+            "set_config",
+            api::git::LocalRepo::set_config::<rhai::ImmutableString, rhai::ImmutableString>,
+        )
+        .register_result_fn("ls_modified", api::git::LocalRepo::list_modified)
+        .register_result_fn("is_ignored", api::git::LocalRepo::is_ignored::<api::git::DirEntryPath>)
+        .register_result_fn("is_ignored", api::git::LocalRepo::is_ignored::<String>)
+        .register_result_fn("is_ignored", api::git::LocalRepo::is_ignored::<&str>)
+        .register_result_fn("is_tracked", api::git::LocalRepo::is_tracked::<api::git::DirEntryPath>)
+        .register_result_fn("is_tracked", api::git::LocalRepo::is_tracked::<String>)
+        .register_result_fn("is_tracked", api::git::LocalRepo::is_tracked::<&str>)
+        .register_result_fn("status", api::git::LocalRepo::pub_status)
+        .register_result_fn("diff_stats", api::git::LocalRepo::pub_diff_stats)
+        .register_result_fn("commit", api::git::LocalRepo::pub_commit::<String>)
+        .register_result_fn("commit_templated", api::git::LocalRepo::pub_commit_templated)
+        .register_result_fn("branch", api::git::LocalRepo::pub_branch::<String>)
+        .register_result_fn("branch", api::git::LocalRepo::pub_branch::<&str>)
+        .register_result_fn(
+            "branch",
+            api::git::LocalRepo::pub_branch::<rhai::ImmutableString>,
+        )
+        .register_result_fn("ensure_branch", api::git::LocalRepo::pub_ensure_branch::<String>)
+        .register_result_fn("ensure_branch", api::git::LocalRepo::pub_ensure_branch::<&str>)
+        .register_result_fn("unique_branch", api::git::LocalRepo::pub_unique_branch)
+        .register_result_fn("checkout", api::git::LocalRepo::pub_checkout::<String>)
+        .register_result_fn("checkout", api::git::LocalRepo::pub_checkout::<&str>)
+        .register_result_fn(
+            "checkout",
+            api::git::LocalRepo::pub_checkout::<rhai::ImmutableString>,
+        )
+        .register_result_fn("current_branch", api::git::LocalRepo::pub_current_branch)
+        .register_result_fn("head_sha", api::git::LocalRepo::head_sha)
+        .register_result_fn("head_message", api::git::LocalRepo::head_message)
+        .register_result_fn("log", api::git::LocalRepo::pub_log)
+        .register_result_fn("reset_hard", api::git::LocalRepo::pub_reset_hard)
+        .register_result_fn("stash", api::git::LocalRepo::pub_stash)
+        .register_result_fn("stash_pop", api::git::LocalRepo::pub_stash_pop)
+        .register_result_fn("push", api::git::LocalRepo::pub_push::<String, String>)
+        .register_result_fn("push", api::git::LocalRepo::pub_push::<&str, &str>)
+        .register_result_fn(
+            "push",
+            api::git::LocalRepo::pub_push::<rhai::ImmutableString, rhai::ImmutableString>,
+        )
+        .register_result_fn("push_verified", api::git::LocalRepo::pub_push_verified::<String>)
+        .register_result_fn("push_verified", api::git::LocalRepo::pub_push_verified::<&str>)
+        .register_result_fn(
+            "push_verified",
+            api::git::LocalRepo::pub_push_verified::<rhai::ImmutableString>,
+        )
+        .register_result_fn("push_to_pr", api::git::LocalRepo::pub_push_to_pr::<String>)
+        .register_result_fn("push_to_pr", api::git::LocalRepo::pub_push_to_pr::<&str>)
+        .register_result_fn(
+            "push_to_pr",
+            api::git::LocalRepo::pub_push_to_pr::<rhai::ImmutableString>,
+        )
+        .register_result_fn(
+            "push_to_pr_verified",
+            api::git::LocalRepo::pub_push_to_pr_verified::<String>,
+        )
+        .register_result_fn(
+            "push_to_pr_verified",
+            api::git::LocalRepo::pub_push_to_pr_verified::<&str>,
+        )
+        .register_result_fn(
+            "push_to_pr_verified",
+            api::git::LocalRepo::pub_push_to_pr_verified::<rhai::ImmutableString>,
+        )
+        .register_result_fn("create_pr", api::git::LocalRepo::pub_create_pr)
+        .register_result_fn("default_branch", api::git::LocalRepo::pub_default_branch)
+        .register_result_fn("url", api::git::LocalRepo::pub_url)
+        .register_result_fn(
+            "set_author_from_issue",
+            api::git::LocalRepo::set_author_from_issue,
+        )
+        .register_result_fn("co_authored_by", api::git::LocalRepo::co_authored_by)
+        .register_result_fn("update_submodules", api::git::LocalRepo::pub_update_submodules)
+        .register_result_fn(
+            "update_submodules",
+            api::git::LocalRepo::pub_update_submodules_recursive,
+        )
+        .register_get("owner", api::git::LocalRepo::get_owner)
+        .register_get("name", api::git::LocalRepo::get_name)
+        .register_get("url", api::git::LocalRepo::get_url);
+
+    engine
+        .register_type::<api::git::DirEntry>()
+        .register_get("path", api::git::DirEntry::get_path)
+        .register_fn("is_file", api::git::DirEntry::is_file)
+        .register_fn("is_dir", api::git::DirEntry::is_dir)
+        .register_fn("is_symlink", api::git::DirEntry::is_symlink);
+
+    engine
+        .register_type::<api::git::Status>()
+        .register_result_fn("changed", api::git::Status::pub_changed)
+        .register_result_fn("added", api::git::Status::pub_added)
+        .register_result_fn("deleted", api::git::Status::pub_deleted);
+
+    engine
+        .register_type::<api::git::DirEntryPath>()
+        .register_result_fn("file_name", api::git::DirEntryPath::file_name)
+        .register_fn("to_string", api::git::DirEntryPath::to_string)
+        .register_fn(
+            "strip_prefix",
+            api::git::DirEntryPath::strip_prefix::<PathBuf>,
+        )
+        .register_fn(
+            "strip_prefix",
+            api::git::DirEntryPath::strip_prefix::<&Path>,
+        )
+        .register_fn(
+            "strip_prefix",
+            api::git::DirEntryPath::strip_prefix::<String>,
+        )
+        .register_fn("strip_prefix", api::git::DirEntryPath::strip_prefix::<&str>)
+        .register_fn("==",
+            |item1: &mut api::git::DirEntryPath, item2: rhai::ImmutableString| item1.to_string() == item2
         );
-        full_path.set_file_name(dir_name);
-        full_path
+
+    // Script output goes to the log (of the bot, rather than its stdout), tagged with the job
+    engine
+        .on_print(|text| script_log(log::Level::Info, text))
+        .on_debug(|text, _, pos| script_log(log::Level::Info, &format!("{pos} | {text}")))
+        .register_fn("log", |text: &str| script_log(log::Level::Info, text))
+        .register_result_fn(
+            "log",
+            |level: &str, text: &str| -> Result<(), Box<rhai::EvalAltResult>> {
+                let level = level
+                    .parse()
+                    .map_err(|_| format!("Invalid log level `{level}`"))?;
+                script_log(level, text);
+                Ok(())
+            },
+        );
+
+    // Ends the script early, with a message for the user rather than an error
+    engine.register_result_fn(
+        "finish",
+        |message: &str| -> Result<(), Box<rhai::EvalAltResult>> {
+            let finished = rhai::Dynamic::from(Finished(message.into()));
+            Err(rhai::EvalAltResult::ErrorTerminated(finished, rhai::Position::NONE).into())
+        },
+    );
+
+    // Stops the script of a cancelled job at its next operation, so loops, git and API calls
+    // stop as well as `cargo`
+    engine.on_progress(|_| {
+        let stopped = SCRIPT_CONTEXT.with(|context| {
+            context.borrow().as_ref().is_some_and(ScriptContext::stopped)
+        });
+        stopped.then(|| rhai::Dynamic::from(Cancelled))
+    });
+
+    engine.register_static_module("env", exported_module!(api::rhai::env).into());
+    engine.register_static_module("cargo_toml", exported_module!(api::rhai::toml).into());
+    engine.register_static_module("md", exported_module!(api::rhai::md).into());
+    engine.register_static_module("sh", exported_module!(api::rhai::sh).into());
+    engine.register_static_module("time", exported_module!(api::rhai::time).into());
+    engine.register_static_module("store", exported_module!(api::rhai::store).into());
+    engine.register_static_module("data", exported_module!(api::rhai::data).into());
+    /*
+    let module = exported_module!(api::rhai::env);
+    engine.register_static_module("env", module.into());
+    */
+
+    Ok(engine)
+}
+
+/// List the commands available in the repository at `repo_dir` as `(dir, script)` pairs, one for
+/// every `.github/<dir>/<script>.rhai`. Symlinks are skipped so the scan stays inside the
+/// repository.
+pub fn list_commands<P: AsRef<Path>>(repo_dir: P) -> Result<Vec<(String, String)>, Error> {
+    let mut commands = vec![];
+    for dir in std::fs::read_dir(repo_dir.as_ref().join(".github"))? {
+        let dir = dir?;
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+        let dir_name = match dir.file_name().into_string() {
+            Ok(dir_name) => dir_name,
+            Err(_) => continue,
+        };
+        for script in std::fs::read_dir(dir.path())? {
+            let script = script?;
+            let path = script.path();
+            if !script.file_type()?.is_file() || path.extension() != Some("rhai".as_ref()) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                commands.push((dir_name.clone(), name.to_string()));
+            }
+        }
     }
+    commands.sort();
+    Ok(commands)
 }
 
 #[derive(Debug)]
 pub struct CheckedoutJob {
     //job: Job,
-    pub command: Vec<String>,
+    pub command: Command,
     pub dir: PathBuf,
+    /// Checkout of the merge base with the default branch, if `CheckoutOptions::compare_base` is
+    /// set
+    pub base_dir: Option<PathBuf>,
     pub clone_dir: PathBuf,
+    /// See `CheckoutOptions::clone_limit`
+    pub clone_limit: Option<Arc<Semaphore>>,
     pub gh_repo: Repository,
     pub gh_issue: Option<Issue>,
+    pub gh_user: Option<octocrab::models::User>,
+    /// Commit of the pull request that was checked out, its head even when the merge ref was
+    /// checked out (see `CheckoutOptions::pr_ref`). Check runs are created for it.
+    pub head_sha: Option<String>,
+    /// See `Job::head`, scripts push to the pull request's branch through it
+    pub pr_head: Option<PullRequestHead>,
+    /// Signs the commits the script creates, if set
+    pub signer: Option<Arc<api::git::GpgSigner>>,
+    /// Directory of the `store::Store`s the scripts of each repository keep values between jobs
+    /// in. Scripts can't use `store` without it.
+    pub store_dir: Option<PathBuf>,
+    /// Directory of files on the host (like shared baselines) the script can read with
+    /// `data::read`, but nothing outside of it
+    pub data_dir: Option<PathBuf>,
+    /// Cargo the script's `cargo` commands run
+    pub cargo: api::cargo::Cargo,
+    /// See `CheckoutOptions::commit_template`
+    pub commit_template: Option<String>,
+    /// How long `Job::checkout` took (cloning or fetching, and checking out), which scripts get
+    /// as `CHECKOUT_MS` (`()` without it)
+    pub checkout_duration: Option<std::time::Duration>,
+    /// Whether the script only gets to look at the repository: everything in the script API that
+    /// writes files, commits, pushes or creates pull requests fails, and so does `cargo` (which
+    /// runs build scripts)
+    pub read_only: bool,
+    /// Settings of the repository, from `.github/bbot.toml` on its default branch for checked out
+    /// jobs (see `RepoConfig::load_committed`)
+    pub repo_config: RepoConfig,
 }
 
 impl CheckedoutJob {
-    fn prepare_engine(&self) -> Result<rhai::Engine, Error> {
-        let mut engine = rhai::Engine::new();
-
-        engine
-            .register_type::<api::cargo::CargoResult>()
-            .register_fn("is_ok", api::cargo::CargoResult::is_ok)
-            .register_get("stdout", api::cargo::CargoResult::get_stdout)
-            .register_get("stderr", api::cargo::CargoResult::get_stderr);
-
-        let cargo_dir = self.dir.clone();
-        engine.register_custom_syntax(&["cargo", "$expr$"], false, move |context, inputs| {
-            let expr = &inputs[0];
-            let value = context
-                .eval_expression_tree(expr)?
-                .try_cast::<String>()
-                .ok_or("Failed to parse `cargo` arguments into a string")?;
-
-            let value =
-                shell_words::split(&value).map_err(|_| "Failed to parse `cargo` arguments")?;
-            let cargo = api::cargo::Run::new(value, &cargo_dir);
-            let result = cargo.run();
-            Ok(rhai::Dynamic::from(result))
-        })?;
-
-        engine
-            .register_type::<api::Issue>()
-            .register_result_fn("comment", api::Issue::create_comment::<String>)
-            .register_result_fn("comment", api::Issue::create_comment::<&str>)
-            .register_result_fn(
-                "comment",
-                api::Issue::create_comment::<rhai::ImmutableString>,
-            );
-
-        engine
-            .register_type::<api::git::Git>()
-            .register_result_fn("clone", api::git::Git::clone::<String>)
-            .register_result_fn("clone", api::git::Git::clone::<&str>)
-            .register_result_fn("clone", api::git::Git::clone::<rhai::ImmutableString>);
-
-        engine
-            .register_type::<api::git::LocalRepo>()
-            .register_result_fn("read", api::git::LocalRepo::read_file::<PathBuf>)
-            .register_result_fn(
-                "read",
-                api::git::LocalRepo::read_file::<api::git::DirEntryPath>,
-            )
-            .register_result_fn("read", api::git::LocalRepo::read_file::<&Path>)
-            .register_result_fn("read", api::git::LocalRepo::read_file::<String>)
-            .register_result_fn("read", api::git::LocalRepo::read_file::<&str>)
-
-            .register_result_fn("write", api::git::LocalRepo::write_file::<PathBuf>)
-            .register_result_fn(
-                "write",
-                api::git::LocalRepo::write_file::<api::git::DirEntryPath>,
-            )
-            .register_result_fn("write", api::git::LocalRepo::write_file::<&Path>)
-            .register_result_fn("write", api::git::LocalRepo::write_file::<String>)
-            .register_result_fn("write", api::git::LocalRepo::write_file::<&str>)
-
-            .register_result_fn("ls", api::git::LocalRepo::list_files)
-            .register_result_fn("ls", api::git::LocalRepo::list_files_in_dir::<PathBuf>)
-            .register_result_fn("ls", api::git::LocalRepo::list_files_in_dir::<&Path>)
-            .register_result_fn("ls", api::git::LocalRepo::list_files_in_dir::<String>)
-            .register_result_fn("ls", api::git::LocalRepo::list_files_in_dir::<&str>)
-            .register_result_fn("ls_files", api::git::LocalRepo::ls_files)
-            .register_result_fn("ls_files", api::git::LocalRepo::ls_files_in_dir::<PathBuf>)
-            .register_result_fn("ls_files", api::git::LocalRepo::ls_files_in_dir::<&Path>)
-            .register_result_fn("ls_files", api::git::LocalRepo::ls_files_in_dir::<String>)
-            .register_result_fn("ls_files", api::git::LocalRepo::ls_files_in_dir::<&str>)
-            .register_result_fn("add", api::git::LocalRepo::add::<api::git::DirEntryPath>)
-            .register_result_fn("ls-modified", api::git::LocalRepo::list_modified)
-            .register_result_fn("status", api::git::LocalRepo::pub_status)
-            .register_result_fn("commit", api::git::LocalRepo::pub_commit::<String>)
-            .register_result_fn("branch", api::git::LocalRepo::pub_branch::<String>)
-            .register_result_fn("branch", api::git::LocalRepo::pub_branch::<&str>)
-            .register_result_fn(
-                "branch",
-                api::git::LocalRepo::pub_branch::<rhai::ImmutableString>,
-            )
-            .register_result_fn("current_branch", api::git::LocalRepo::pub_current_branch)
-            .register_result_fn("push", api::git::LocalRepo::pub_push::<String, String>)
-            .register_result_fn("push", api::git::LocalRepo::pub_push::<&str, &str>)
-            .register_result_fn(
-                "push",
-                api::git::LocalRepo::pub_push::<rhai::ImmutableString, rhai::ImmutableString>,
-            )
-            .register_result_fn("create_pr", api::git::LocalRepo::pub_create_pr)
-            .register_result_fn("url", api::git::LocalRepo::pub_url);
-
-        engine
-            .register_type::<api::git::DirEntry>()
-            .register_get("path", api::git::DirEntry::get_path)
-            .register_fn("is_file", api::git::DirEntry::is_file)
-            .register_fn("is_dir", api::git::DirEntry::is_dir)
-            .register_fn("is_symlink", api::git::DirEntry::is_symlink);
-
-        engine
-            .register_type::<api::git::Status>()
-            .register_result_fn("changed", api::git::Status::pub_changed)
-            .register_result_fn("added", api::git::Status::pub_added)
-            .register_result_fn("deleted", api::git::Status::pub_deleted);
-
-        engine
-            .register_type::<api::git::DirEntryPath>()
-            .register_result_fn("file_name", api::git::DirEntryPath::file_name)
-            .register_fn("to_string", api::git::DirEntryPath::to_string)
-            .register_fn(
-                "strip_prefix",
-                api::git::DirEntryPath::strip_prefix::<PathBuf>,
-            )
-            .register_fn(
-                "strip_prefix",
-                api::git::DirEntryPath::strip_prefix::<&Path>,
-            )
-            .register_fn(
-                "strip_prefix",
-                api::git::DirEntryPath::strip_prefix::<String>,
-            )
-            .register_fn("strip_prefix", api::git::DirEntryPath::strip_prefix::<&str>)
-            .register_fn("==",
-                |item1: &mut api::git::DirEntryPath, item2: rhai::ImmutableString| item1.to_string() == item2
-            );
-
-        engine.register_static_module("env", exported_module!(api::rhai::env).into());
-        engine.register_static_module("cargo_toml", exported_module!(api::rhai::toml).into());
-        /*
-        let module = exported_module!(api::rhai::env);
-        engine.register_static_module("env", module.into());
-        */
-
-        Ok(engine)
+    /// Whether this job is for the built-in `help` command, i.e. a `help` command for which the
+    /// repository doesn't provide its own script.
+    pub fn is_help(&self) -> bool {
+        self.command.script == "help" && !self.dir.join(self.command.script_path()).exists()
     }
 
     pub fn prepare_script(
         self,
-        github_client: octocrab::Octocrab,
+        github: Arc<dyn api::github::Github>,
+        engine: Arc<rhai::Engine>,
     ) -> Result<RunnableJob<'static>, Error> {
         log::debug!("Preparing script");
-        //let script_path = self.script_path()?;
-        let script_path = PathBuf::from(self.command.get(0).ok_or(Error::NoCmd)?);
-
-        let engine = self.prepare_engine()?;
-
-        let client = Arc::new(Mutex::new(github_client));
+        let script_path = self.command.script_path();
+        let repo_config = self.repo_config.clone();
+        if !repo_config.allows_dir(&self.command.dir) {
+            return Err(Error::ScriptDirNotAllowed(self.command.dir.clone()));
+        }
+        let read_only = self.read_only;
+        if read_only {
+            log::info!("Running {} read-only", script_path.display());
+        }
+        let label = match &self.gh_issue {
+            Some(issue) => format!(
+                "{}/{}#{}",
+                self.gh_repo.owner.login, self.gh_repo.name, issue.number
+            ),
+            None => format!("{}/{}", self.gh_repo.owner.login, self.gh_repo.name),
+        };
 
         let scope = {
             let mut scope = rhai::Scope::new();
+            let store = self
+                .store_dir
+                .as_ref()
+                .map(|dir| crate::store::Store::new(dir, self.gh_repo.id));
             let repo_name = self.gh_repo.name.clone();
             let repo_owner = self.gh_repo.owner.login.clone();
+            let issue_number = self.gh_issue.as_ref().map(|issue| issue.number);
+            let commit_template = self.commit_template.as_ref().map(|template| {
+                let mut values = std::collections::BTreeMap::new();
+                values.insert("command".to_string(), self.command.to_string());
+                values.insert("repo".to_string(), format!("{repo_owner}/{repo_name}"));
+                if let Some(issue_number) = issue_number {
+                    values.insert("issue".to_string(), issue_number.to_string());
+                }
+                if let Some(user) = &self.gh_user {
+                    values.insert("user".to_string(), user.login.clone());
+                }
+                api::git::CommitTemplate {
+                    template: template.clone(),
+                    values,
+                }
+            });
             if let Some(gh_issue) = self.gh_issue {
-                let issue = api::Issue::new(client.clone(), self.gh_repo, gh_issue);
+                let issue = api::Issue::new(github.clone(), self.gh_repo, gh_issue)
+                    .with_head_sha(self.head_sha)
+                    .with_pr_head(self.pr_head.clone());
                 scope.push_constant("ISSUE", issue);
             }
             log::debug!("local repo dir: {:?}", &self.dir);
             let local_repo = git2::Repository::open(&self.dir)?;
-            let repo = api::git::LocalRepo::new(
+            let mut repo = api::git::LocalRepo::new(
                 &self.dir,
-                repo_owner,
-                repo_name,
+                &repo_owner,
+                &repo_name,
                 local_repo,
-                client.clone(),
+                github.clone(),
             );
+            repo.set_trigger_user(self.gh_user.clone());
+            repo.set_signer(self.signer.clone());
+            repo.set_pr_head(self.pr_head);
+            repo.set_store(store);
+            repo.set_data_dir(self.data_dir.map(crate::data::DataDir::new));
+            repo.set_cargo(self.cargo.clone());
+            repo.set_read_only(read_only);
+            repo.set_issue_number(issue_number);
+            repo.set_commit_template(commit_template.clone());
             scope.push_constant("REPO", repo);
+            if let Some(base_dir) = &self.base_dir {
+                log::debug!("base repo dir: {:?}", base_dir);
+                let mut base_repo = api::git::LocalRepo::new(
+                    base_dir,
+                    &repo_owner,
+                    &repo_name,
+                    git2::Repository::open(base_dir)?,
+                    github.clone(),
+                );
+                base_repo.set_trigger_user(self.gh_user.clone());
+                base_repo.set_signer(self.signer.clone());
+                base_repo.set_cargo(self.cargo.clone());
+                base_repo.set_read_only(read_only);
+                base_repo.set_commit_template(commit_template.clone());
+                scope.push_constant("BASE_REPO", base_repo);
+            }
             // TODO: replace with proper module export
             let git = api::git::Git {
                 path: self.dir.clone(),
-                root: self.clone_dir,
-                github_client: client,
+                root: self.clone_dir.clone(),
+                clone_limit: self.clone_limit,
+                github,
+                trigger_user: self.gh_user,
+                signer: self.signer,
+                cargo: self.cargo.clone(),
+                read_only,
+                commit_template,
+                repo: format!("{repo_owner}/{repo_name}"),
             };
             scope.push_constant("Git", git);
+            // Always defined, so scripts can check it instead of failing on an unknown variable
+            let checkout_ms = match self.checkout_duration {
+                Some(duration) => {
+                    rhai::INT::try_from(duration.as_millis()).unwrap_or(rhai::INT::MAX).into()
+                }
+                None => rhai::Dynamic::UNIT,
+            };
+            scope.push_constant_dynamic("CHECKOUT_MS", checkout_ms);
             Box::new(scope)
         };
 
         Ok(RunnableJob {
             //job: self.job,
+            context: ScriptContext {
+                dir: self.dir.clone(),
+                cargo: self.cargo,
+                read_only,
+                cancelled: None,
+                timed_out: None,
+                cargo_log: None,
+            },
             dir: self.dir,
+            clone_dir: self.clone_dir,
             script_path,
+            label,
             engine,
             scope,
+            hooks: Hooks::default(),
+            timeout: repo_config.timeout,
         })
     }
 }
 
+/// Replace the given directories in an error message, so internal paths don't leak into (public)
+/// issue comments.
+pub fn redact_paths<E: std::fmt::Display>(err: E, dirs: &[&Path]) -> String {
+    let mut dirs: Vec<String> = dirs
+        .iter()
+        .flat_map(|dir| vec![Some(dir.to_path_buf()), dir.canonicalize().ok()])
+        .flatten()
+        // Relative paths (like `.`) and `/` would mangle the entire message
+        .filter(|dir| dir.is_absolute() && dir.parent().is_some())
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .collect();
+    // Replace the longest paths first, so a directory doesn't partially redact a nested one
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.len()));
+    dirs.iter()
+        .fold(format!("{err}"), |msg, dir| msg.replace(dir.as_str(), "<redacted>"))
+}
+
+/// Scripts on the host (not in the job's repository) run before and after the job's script, see
+/// `RunnableJob::hooks`
+#[derive(Clone, Debug, Default)]
+pub struct Hooks {
+    /// Runs before the job's script, which is skipped if this fails or calls `finish`
+    pub pre_script: Option<PathBuf>,
+    /// Runs after the job's script (or the pre-script) even if that failed
+    pub post_script: Option<PathBuf>,
+}
+
+/// Set `timed_out` (which stops the scripts, see `ScriptContext::timed_out`) once `timeout`
+/// passes, unless the returned sender is dropped before
+fn watchdog(
+    timeout: std::time::Duration,
+    timed_out: Arc<AtomicBool>,
+) -> std::sync::mpsc::Sender<()> {
+    let (finished, watchdog) = std::sync::mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = watchdog.recv_timeout(timeout) {
+            log::info!("Stopping job after the timeout of {}", repo_config::PATH);
+            timed_out.store(true, Ordering::SeqCst);
+        }
+    });
+    finished
+}
+
 pub struct RunnableJob<'a> {
     dir: PathBuf,
+    clone_dir: PathBuf,
     script_path: PathBuf,
-    engine: rhai::Engine,
+    /// Tags the log messages of the scripts, see `SCRIPT_JOB`
+    label: String,
+    engine: Arc<rhai::Engine>,
     scope: Box<rhai::Scope<'a>>,
+    /// Set as the `SCRIPT_CONTEXT` while the scripts run
+    context: ScriptContext,
+    hooks: Hooks,
+    /// See `RepoConfig::timeout`
+    timeout: Option<std::time::Duration>,
 }
 
 impl RunnableJob<'_> {
-    pub fn run(mut self) -> Result<(), Error> {
+    /// Stop the scripts at their next operation and kill their running `cargo` commands once
+    /// `cancelled` is set, which fails the job with `Error::Cancelled`
+    pub fn cancel_token(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.context.cancelled = Some(cancelled);
+        self
+    }
+
+    /// Add every `cargo` command the script runs, with its result, to `log`
+    pub fn record_cargo(mut self, log: CargoLog) -> Self {
+        self.context.cargo_log = Some(log);
+        self
+    }
+
+    /// Run the `hooks` around the job's script. They get the same scope constants (like `REPO`
+    /// and `ISSUE`) as the job's script, but none of the variables it defines.
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Run the hooks and the job's script, returning the message of `finish` if one of them ended
+    /// with it
+    pub fn run(mut self) -> Result<Option<String>, Error> {
+        SCRIPT_JOB.with(|job| job.replace(self.label.clone()));
+        let timed_out = Arc::new(AtomicBool::new(false));
+        // Stops the watchdog when dropped
+        let _finished = self.timeout.map(|timeout| {
+            self.context.timed_out = Some(timed_out.clone());
+            watchdog(timeout, timed_out.clone())
+        });
+        SCRIPT_CONTEXT.with(|context| context.replace(Some(self.context.clone())));
+        let pre_script = self.hooks.pre_script.take();
+        let post_script = self.hooks.post_script.take();
+        let mut res = match pre_script {
+            Some(path) => self.run_hook(&path).map_err(Error::PreScript),
+            None => Ok(None),
+        };
+        // A pre-script that finished ends the job before its script
+        if let Ok(None) = res {
+            let scope_len = self.scope.len();
+            res = self.run_script();
+            self.scope.rewind(scope_len);
+        }
+        if let Some(path) = post_script {
+            match (self.run_hook(&path), &res) {
+                (Err(e), Ok(_)) => res = Err(Error::PostScript(e)),
+                // Report the job's own failure, which likely caused this one
+                (Err(e), Err(_)) => log::warn!("Failed to execute post-script: {e}"),
+                (Ok(Some(message)), Ok(None)) => res = Ok(Some(message)),
+                (Ok(_), _) => {}
+            }
+        }
+        SCRIPT_JOB.with(|job| job.borrow_mut().clear());
+        SCRIPT_CONTEXT.with(|context| context.replace(None));
+        let cancelled = matches!(
+            &self.context.cancelled,
+            Some(cancelled) if cancelled.load(Ordering::SeqCst)
+        );
+        match (res, self.timeout) {
+            (Err(_), Some(timeout)) if timed_out.load(Ordering::SeqCst) => {
+                Err(Error::ScriptTimeout(timeout))
+            }
+            (Err(_), _) if cancelled => Err(Error::Cancelled),
+            (res, _) => res,
+        }
+    }
+
+    /// Run the host script at `path` in the job's scope
+    fn run_hook(&mut self, path: &Path) -> Result<Option<String>, Box<rhai::EvalAltResult>> {
+        log::info!("Executing hook {:?} in {:?}", path, self.dir);
+        let dirs = [
+            self.dir.as_path(),
+            self.clone_dir.as_path(),
+            path.parent().unwrap_or(path),
+        ];
+        let (engine, scope) = (&self.engine, &mut self.scope);
+        let scope_len = scope.len();
+        let res = engine
+            .compile_file(path.into())
+            .map_err(|e| redact_paths(e, &dirs).into())
+            .and_then(|ast| match engine.run_ast_with_scope(scope, &ast) {
+                Ok(()) => Ok(None),
+                Err(e) => match finished_message(&e) {
+                    Some(message) => Ok(Some(message)),
+                    None => Err(redact_paths(e, &dirs).into()),
+                },
+            });
+        scope.rewind(scope_len);
+        res
+    }
+
+    fn run_script(&mut self) -> Result<Option<String>, Error> {
         log::info!(
             "Executing {} in {:?}",
             self.script_path.to_string_lossy(),
             self.dir
         );
 
+        // Resolve symlinks and `..` so the script can't be read from outside its command's
+        // directory (like `.github/benchbot`), which itself must be inside the repository. A
+        // symlinked script could otherwise run the script of a directory `script_dirs` doesn't
+        // allow.
+        let command_dir = self.script_path.parent().unwrap_or_else(|| Path::new(""));
+        let script_path = match (
+            self.dir.join(&self.script_path).canonicalize(),
+            self.dir.join(command_dir).canonicalize(),
+            self.dir.canonicalize(),
+        ) {
+            (Ok(script_path), Ok(command_dir), Ok(dir))
+                if command_dir.starts_with(&dir) && script_path.starts_with(&command_dir) =>
+            {
+                script_path
+            }
+            (Ok(_), Ok(_), Ok(_)) => {
+                return Err(Error::ScriptOutsideCommandDir(self.script_path.clone()))
+            }
+            _ => return Err(Error::ScriptNotFound(self.script_path.clone())),
+        };
+        if !script_path.is_file() {
+            return Err(Error::ScriptNotFound(self.script_path.clone()));
+        }
+
         // We don't want to leak any internal fs details
-        //let ast = self.engine.compile_file(self.dir.join(self.script_path.clone()))
+        let dirs = [self.dir.as_path(), self.clone_dir.as_path()];
         let ast = self
             .engine
-            .compile_file(self.script_path.clone())
-            // Don't leak in the internal path
-            .map_err(|e| Error::ScriptExecution(format!("{e}").into()))?;
+            .compile_file(script_path)
+            .map_err(|e| Error::ScriptExecution(redact_paths(e, &dirs).into()))?;
 
-        self.engine.run_ast_with_scope(&mut self.scope, &ast)?;
-        Ok(())
+        match self.engine.run_ast_with_scope(&mut self.scope, &ast) {
+            Ok(()) => Ok(None),
+            Err(e) => match finished_message(&e) {
+                Some(message) => Ok(Some(message)),
+                None => Err(Error::ScriptExecution(redact_paths(e, &dirs).into())),
+            },
+        }
     }
 }
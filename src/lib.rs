@@ -1,17 +1,28 @@
 pub mod api;
+pub mod broadcast;
+pub mod cache;
+pub mod command;
+pub mod data;
 pub mod job;
+pub mod log_buffer;
 mod local_queue;
+pub mod repo_config;
+pub mod semaphore;
+pub mod store;
+pub mod units;
 
+pub use broadcast::Broadcast;
+pub use command::Command;
 pub use job::Job;
-pub use local_queue::LocalQueue;
+pub use local_queue::{Enqueued, LocalQueue};
 
 pub trait Queue {
     type Err;
     type Id;
     type Item;
 
-    fn add(&mut self, id: Self::Id, item: Self::Item);
-    fn remove(&mut self) -> Option<Self::Item>;
+    fn add(&mut self, id: Self::Id, item: Self::Item) -> Result<(), Self::Err>;
+    fn remove(&mut self) -> Result<Option<Self::Item>, Self::Err>;
     fn len(&self) -> usize;
     fn pos(&self, id: Self::Id) -> Option<usize>;
 
@@ -1,51 +1,201 @@
-use crate::Queue;
+use crate::{Broadcast, Queue};
 use indexmap::IndexMap;
 use std::hash::Hash;
 
 #[derive(thiserror::Error, Debug)]
-pub enum Error {}
+pub enum Error {
+    #[error("The queue is closed")]
+    Closed,
+    #[error("The queue is full")]
+    Full,
+}
+
+/// Notification that an item was added to the queue, see `LocalQueue::subscribe`
+#[derive(Clone, Debug)]
+pub struct Enqueued<Id> {
+    pub id: Id,
+    /// Position in the queue, `None` if the item was handed to a waiting watcher right away
+    pub position: Option<usize>,
+}
+
+/// Which items a watcher takes, see `LocalQueue::register_watcher_matching`
+type Filter<Item> = Box<dyn Fn(&Item) -> bool + Send + Sync>;
+
+struct Watcher<Item> {
+    sender: async_std::channel::Sender<Item>,
+    /// Takes every item if `None`
+    filter: Option<Filter<Item>>,
+}
+
+impl<Item> Watcher<Item> {
+    fn accepts(&self, item: &Item) -> bool {
+        match &self.filter {
+            Some(filter) => filter(item),
+            None => true,
+        }
+    }
+}
+
+impl<Item> std::fmt::Debug for Watcher<Item> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Watcher")
+            .field("sender", &self.sender)
+            .field("filtered", &self.filter.is_some())
+            .finish()
+    }
+}
 
 #[derive(Debug)]
 pub struct LocalQueue<Id, Item> {
     queue: IndexMap<Id, Item>,
-    watchers: Vec<async_std::channel::Sender<Item>>,
+    watchers: Vec<Watcher<Item>>,
+    subscribers: Broadcast<Enqueued<Id>>,
+    /// Maximum number of queued items, `None` means unlimited
+    capacity: Option<usize>,
+    closed: bool,
 }
 
-impl<Id, Item> LocalQueue<Id, Item> {
+impl<Id: Clone, Item> LocalQueue<Id, Item> {
     pub fn new() -> Self {
         let queue = IndexMap::new();
         let watchers = vec![];
-        Self { queue, watchers }
+        let subscribers = Broadcast::new();
+        Self {
+            queue,
+            watchers,
+            subscribers,
+            capacity: None,
+            closed: false,
+        }
+    }
+
+    /// A queue that holds at most `capacity` items, adding more fails with `Error::Full`
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: Some(capacity),
+            ..Self::new()
+        }
+    }
+
+    /// Stop accepting items. Items that are already queued can still be removed, after that
+    /// `remove` fails with `Error::Closed`. Waiting watchers are dropped, which closes their
+    /// channels.
+    pub fn close(&mut self) {
+        self.closed = true;
+        self.watchers.clear();
     }
 
+    /// Hand the next added item to `sender` instead of queueing it. Every item goes to exactly
+    /// one watcher. The item is sent without waiting, so `sender` needs room for it (e.g. a
+    /// `bounded(1)` channel), otherwise the item goes to the next watcher or the queue.
     pub fn register_watcher(&mut self, sender: async_std::channel::Sender<Item>) {
-        self.watchers.push(sender);
+        self.watchers.push(Watcher {
+            sender,
+            filter: None,
+        });
+    }
+
+    /// Like `register_watcher`, but only hand it items that `filter` accepts. Other items go to
+    /// the next watcher or the queue.
+    pub fn register_watcher_matching<F>(
+        &mut self,
+        sender: async_std::channel::Sender<Item>,
+        filter: F,
+    ) where
+        F: Fn(&Item) -> bool + Send + Sync + 'static,
+    {
+        self.watchers.push(Watcher {
+            sender,
+            filter: Some(Box::new(filter)),
+        });
+    }
+
+    /// Get notified of every item added to the queue, without taking it from the queue (unlike
+    /// `register_watcher`). A subscriber that falls behind misses notifications instead of
+    /// holding up the queue.
+    pub fn subscribe(&mut self) -> async_std::channel::Receiver<Enqueued<Id>> {
+        self.subscribers.subscribe()
+    }
+
+    /// The queued items with their ids, next to be removed first
+    pub fn iter(&self) -> impl Iterator<Item = (&Id, &Item)> {
+        self.queue.iter()
+    }
+
+    /// Drop all queued items and return how many there were. Waiting watchers stay registered,
+    /// and the queue keeps accepting items.
+    pub fn clear(&mut self) -> usize {
+        let count = self.queue.len();
+        self.queue.clear();
+        count
+    }
+}
+
+impl<Id: Hash + Eq, Item> LocalQueue<Id, Item> {
+    /// Take the item with `id` out of the queue, wherever it is, keeping the order of the rest
+    pub fn take(&mut self, id: &Id) -> Option<Item> {
+        self.queue.shift_remove(id)
+    }
+
+    /// Like `Queue::remove`, but take the first item `filter` accepts, keeping the order of the
+    /// rest. Fails with `Error::Closed` only once the queue has no such items left.
+    pub fn remove_matching<F: Fn(&Item) -> bool>(
+        &mut self,
+        filter: F,
+    ) -> Result<Option<Item>, Error> {
+        match self.queue.values().position(filter) {
+            Some(index) => Ok(self.queue.shift_remove_index(index).map(|(_k, v)| v)),
+            None if self.closed => Err(Error::Closed),
+            None => Ok(None),
+        }
     }
 }
 
 impl<Id, Item> Queue for LocalQueue<Id, Item>
 where
-    Id: Hash + Eq,
-    Item: Send + 'static,
+    Id: Hash + Eq + Clone,
 {
     type Err = Error;
     type Id = Id;
     type Item = Item;
 
-    fn add(&mut self, id: Self::Id, item: Self::Item) {
-        if !self.watchers.is_empty() {
-            let watcher = self.watchers.remove(0);
-            async_std::task::spawn(async move { watcher.send(item).await });
-        } else {
-            self.queue.insert_full(id, item);
+    fn add(&mut self, id: Self::Id, item: Self::Item) -> Result<(), Self::Err> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        let mut item = item;
+        // Watchers that went away (like long polls that timed out) are dropped on the way, also
+        // ones that wouldn't have taken this item
+        self.watchers.retain(|watcher| !watcher.sender.is_closed());
+        while let Some(index) = self.watchers.iter().position(|watcher| watcher.accepts(&item)) {
+            let watcher = self.watchers.remove(index);
+            match watcher.sender.try_send(item) {
+                Ok(()) => {
+                    self.subscribers.send(Enqueued { id, position: None });
+                    return Ok(());
+                }
+                // The watcher went away or is full, try the next one
+                Err(e) => item = e.into_inner(),
+            }
+        }
+        if matches!(self.capacity, Some(capacity) if self.queue.len() >= capacity) {
+            return Err(Error::Full);
         }
+        let (position, _) = self.queue.insert_full(id.clone(), item);
+        self.subscribers.send(Enqueued {
+            id,
+            position: Some(position),
+        });
+        Ok(())
     }
 
-    fn remove(&mut self) -> Option<Self::Item> {
+    fn remove(&mut self) -> Result<Option<Self::Item>, Self::Err> {
         if !self.queue.is_empty() {
-            self.queue.shift_remove_index(0).map(|(_k, v)| v)
+            Ok(self.queue.shift_remove_index(0).map(|(_k, v)| v))
+        } else if self.closed {
+            Err(Error::Closed)
         } else {
-            None
+            Ok(None)
         }
     }
 
@@ -58,7 +208,7 @@ where
     }
 }
 
-impl<Id, Item> Default for LocalQueue<Id, Item> {
+impl<Id: Clone, Item> Default for LocalQueue<Id, Item> {
     fn default() -> Self {
         Self::new()
     }
@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Longest line (in bytes) a `LogBuffer` keeps, longer ones are cut off and end in
+/// `TRUNCATED_MARKER`, so a few huge lines (like a logged response body) can't use up the memory
+const MAX_LINE_LEN: usize = 4096;
+
+/// Appended to lines cut off at `MAX_LINE_LEN`
+const TRUNCATED_MARKER: &str = "...(truncated)";
+
+/// The last lines logged, oldest first. Once it holds `capacity` lines, every new line drops the
+/// oldest one.
+#[derive(Debug)]
+pub struct LogBuffer {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, mut line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        if line.len() > MAX_LINE_LEN {
+            let mut end = MAX_LINE_LEN - TRUNCATED_MARKER.len();
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+            line.push_str(TRUNCATED_MARKER);
+        }
+        let mut lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+        if lines.len() >= self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// The last `count` lines (or all of them, if there are fewer), oldest first
+    pub fn tail(&self, count: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Passes records on to `inner` (like the logger of `pretty_env_logger`) and keeps the ones it
+/// logs in a `LogBuffer` as well
+pub struct BufferedLogger<L> {
+    inner: L,
+    buffer: Arc<LogBuffer>,
+}
+
+impl<L: log::Log> BufferedLogger<L> {
+    pub fn new(inner: L, buffer: Arc<LogBuffer>) -> Self {
+        Self { inner, buffer }
+    }
+}
+
+impl<L: log::Log> log::Log for BufferedLogger<L> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        self.inner.log(record);
+        self.buffer.push(format!(
+            "{} {:<5} {} > {}",
+            humantime::format_rfc3339_millis(std::time::SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_lines_are_truncated() {
+        let buffer = LogBuffer::new(10);
+        let short = "a".repeat(MAX_LINE_LEN);
+        buffer.push(short.clone());
+        buffer.push("a".repeat(MAX_LINE_LEN + 1));
+        // `€` is 3 bytes, so the cut falls in the middle of one
+        buffer.push("€".repeat(MAX_LINE_LEN));
+
+        let lines = buffer.tail(3);
+        assert_eq!(lines[0], short);
+        for line in &lines[1..] {
+            assert!(line.len() <= MAX_LINE_LEN, "{} bytes", line.len());
+            assert!(line.ends_with(TRUNCATED_MARKER), "{:?}", line);
+        }
+        assert!(lines[2].len() < MAX_LINE_LEN);
+    }
+}
@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Path of a repository's configuration of the bot, relative to its root
+pub const PATH: &str = ".github/bbot.toml";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read {PATH}: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("Failed to read {PATH} from git: {0}")]
+    Git(#[from] git2::Error),
+    #[error("Invalid {PATH}: {0}")]
+    Invalid(String),
+}
+
+/// Settings a repository makes for its own jobs in `.github/bbot.toml`, like
+///
+/// ```toml
+/// # Seconds the script may take
+/// timeout = 1800
+/// # Directories (relative to the repository's root) whose scripts may run
+/// script_dirs = [".github/benchbot"]
+/// ```
+///
+/// Anything it doesn't set falls back to the bot's options.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepoConfig {
+    /// Maximum duration of the job's script (and the hooks around it). The bot's own job timeout
+    /// still applies to the whole job, so this can only make it shorter.
+    pub timeout: Option<Duration>,
+    /// Directories of the commands whose scripts may run (like `.github/benchbot`, see
+    /// `Command::dir`), `None` allows all of them
+    pub script_dirs: Option<Vec<PathBuf>>,
+}
+
+impl RepoConfig {
+    /// The configuration of the repository checked out at `repo_dir`, the default if it has none
+    pub fn load<P: AsRef<Path>>(repo_dir: P) -> Result<Self, Error> {
+        match std::fs::read_to_string(repo_dir.as_ref().join(PATH)) {
+            Ok(toml) => Self::parse(&toml),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The configuration committed in `commit` of `repo`, the default if it has none. Jobs read
+    /// it from the default branch, so a pull request can't change the settings of its own jobs.
+    pub fn load_committed(repo: &git2::Repository, commit: git2::Oid) -> Result<Self, Error> {
+        let tree = repo.find_commit(commit)?.tree()?;
+        let entry = match tree.get_path(Path::new(PATH)) {
+            Ok(entry) => entry,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let blob = entry.to_object(repo)?.peel_to_blob()?;
+        let toml = std::str::from_utf8(blob.content()).map_err(|e| Error::Invalid(e.to_string()))?;
+        Self::parse(toml)
+    }
+
+    pub fn parse(toml: &str) -> Result<Self, Error> {
+        let invalid = |message: String| Error::Invalid(message);
+        let strings = |key: &str, item: &toml_edit::Item| {
+            item.as_array()
+                .and_then(|strings| {
+                    strings
+                        .iter()
+                        .map(|string| string.as_str().map(String::from))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| invalid(format!("`{key}` must be an array of strings")))
+        };
+        let doc = toml
+            .parse::<toml_edit::Document>()
+            .map_err(|e| invalid(e.to_string()))?;
+        let mut config = RepoConfig::default();
+        for (key, item) in doc.iter() {
+            match key {
+                "timeout" => {
+                    let seconds = item
+                        .as_integer()
+                        .filter(|seconds| *seconds > 0)
+                        .ok_or_else(|| invalid("`timeout` must be a positive number".into()))?;
+                    config.timeout = Some(Duration::from_secs(seconds as u64));
+                }
+                "script_dirs" => {
+                    config.script_dirs =
+                        Some(strings(key, item)?.into_iter().map(PathBuf::from).collect())
+                }
+                // Rather than silently ignoring typos
+                key => return Err(invalid(format!("unknown setting `{key}`"))),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Whether the scripts in `dir` (relative to the repository's root) may run
+    pub fn allows_dir(&self, dir: &Path) -> bool {
+        self.script_dirs
+            .as_ref()
+            .is_none_or(|dirs| dirs.iter().any(|allowed| allowed == dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn committed_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(tmp.path()).unwrap();
+        let signature = git2::Signature::now("Test", "test@localhost").unwrap();
+        let commit = |files: &[(&str, &str)]| {
+            let mut builder = repo.treebuilder(None).unwrap();
+            let mut github = repo.treebuilder(None).unwrap();
+            for (name, contents) in files {
+                github
+                    .insert(name, repo.blob(contents.as_bytes()).unwrap(), 0o100644)
+                    .unwrap();
+            }
+            builder.insert(".github", github.write().unwrap(), 0o040000).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            repo.commit(None, &signature, &signature, "Commit", &tree, &[])
+                .unwrap()
+        };
+
+        let configured = commit(&[("bbot.toml", "timeout = 60")]);
+        // The working tree doesn't matter
+        std::fs::create_dir(tmp.path().join(".github")).unwrap();
+        std::fs::write(tmp.path().join(PATH), "timeout = 3600").unwrap();
+        let config = RepoConfig::load_committed(&repo, configured).unwrap();
+        assert_eq!(config.timeout, Some(Duration::from_secs(60)));
+
+        let unconfigured = commit(&[("other.toml", "")]);
+        let config = RepoConfig::load_committed(&repo, unconfigured).unwrap();
+        assert_eq!(config, RepoConfig::default());
+
+        let invalid = commit(&[("bbot.toml", "timeout = -1")]);
+        let result = RepoConfig::load_committed(&repo, invalid);
+        assert!(matches!(result, Err(Error::Invalid(_))), "{:?}", result);
+    }
+}
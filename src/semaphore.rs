@@ -0,0 +1,50 @@
+use std::sync::{Condvar, Mutex};
+
+/// Counting semaphore that limits how many threads hold a `Permit` at the same time, e.g. to
+/// throttle clones and fetches (see `job::CheckoutOptions::clone_limit`)
+#[derive(Debug)]
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Returns its permit to the `Semaphore` when dropped
+#[derive(Debug)]
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut available = self
+            .semaphore
+            .available
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *available += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+impl Semaphore {
+    /// `permits` of 0 are treated as 1, so `acquire` can't block forever
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            available: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until a permit is available
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        while *available == 0 {
+            available = self
+                .released
+                .wait(available)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *available -= 1;
+        Permit { semaphore: self }
+    }
+}
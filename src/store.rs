@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read or write the store: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The store {0:?} is corrupt: {1}")]
+    Corrupt(PathBuf, serde_json::Error),
+}
+
+/// Held while reading and writing a store, so jobs running at the same time (like a timed out job
+/// that didn't stop yet) don't lose each other's values
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Strings that the scripts of a repository keep between jobs (like benchmark results to compare
+/// later runs against), by key in a JSON file on the host
+#[derive(Clone, Debug)]
+pub struct Store {
+    file: PathBuf,
+}
+
+impl Store {
+    /// The store of the repository with the Github id `repo_id` in `dir`, which is named after
+    /// the id rather than the name, since that doesn't change when the repository is renamed
+    pub fn new<P: AsRef<Path>>(dir: P, repo_id: octocrab::models::RepositoryId) -> Self {
+        Store {
+            file: dir.as_ref().join(format!("{repo_id}.json")),
+        }
+    }
+
+    /// The value stored under `key`, if any
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        let _lock = LOCK.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        Ok(self.read()?.remove(key))
+    }
+
+    /// Store `value` under `key`, replacing what was stored under it before
+    pub fn put(&self, key: &str, value: &str) -> Result<(), Error> {
+        let _lock = LOCK.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        let mut values = self.read()?;
+        values.insert(key.to_string(), value.to_string());
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Written next to the store and renamed over it, so a crash can't leave half of it behind
+        let tmp = self.file.with_extension("json.tmp");
+        let json = serde_json::to_vec_pretty(&values)
+            .map_err(|e| Error::Corrupt(self.file.clone(), e))?;
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, &self.file)?;
+        Ok(())
+    }
+
+    fn read(&self) -> Result<BTreeMap<String, String>, Error> {
+        match std::fs::read(&self.file) {
+            Ok(json) => {
+                serde_json::from_slice(&json).map_err(|e| Error::Corrupt(self.file.clone(), e))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("Invalid duration `{0}`: expected seconds or something like `90s`, `30m` or `1h30m`")]
+    Duration(String),
+    #[error("Invalid size `{0}`: expected bytes or something like `500MB`, `2GiB` or `1.5G`")]
+    Size(String),
+}
+
+/// Parse a duration like `30s`, `15m`, `1h30m` or `500ms` (with units `ms`, `s`, `m`, `h` and
+/// `d`), for options like `--job-timeout`. A plain number is a number of seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, Error> {
+    let invalid = || Error::Duration(value.into());
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    if value.is_empty() {
+        return Err(invalid());
+    }
+    let mut duration = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let part = match rest[..unit].trim() {
+            "ms" => Duration::from_millis(number),
+            "s" | "sec" => Duration::from_secs(number),
+            "m" | "min" => Duration::from_secs(number.checked_mul(60).ok_or_else(invalid)?),
+            "h" => Duration::from_secs(number.checked_mul(60 * 60).ok_or_else(invalid)?),
+            "d" => Duration::from_secs(number.checked_mul(24 * 60 * 60).ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        };
+        duration = duration.checked_add(part).ok_or_else(invalid)?;
+        rest = rest[unit..].trim_start();
+    }
+    Ok(duration)
+}
+
+/// Parse a size in bytes like `500MB`, `2GiB` or `1.5G`, for options like `--max-clone-size`.
+/// `KB`, `MB`, `GB` and `TB` are powers of 1000, `KiB`, `MiB`, `GiB` and `TiB` (and `K`, `M`,
+/// `G` and `T`, like in Docker's options) are powers of 1024. A plain number is a number of
+/// bytes.
+pub fn parse_size(value: &str) -> Result<u64, Error> {
+    let invalid = || Error::Size(value.into());
+    let value = value.trim();
+    if let Ok(bytes) = value.parse::<u64>() {
+        return Ok(bytes);
+    }
+    let number_end = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .ok_or_else(invalid)?;
+    let number: f64 = value[..number_end].parse().map_err(|_| invalid())?;
+    let unit: u64 = match value[number_end..].trim().to_ascii_lowercase().as_str() {
+        "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        "tb" => 1000 * 1000 * 1000 * 1000,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+    let bytes = (number * unit as f64).round();
+    if !bytes.is_finite() || bytes > u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        for (value, expected) in [
+            ("90", Some(Duration::from_secs(90))),
+            ("90s", Some(Duration::from_secs(90))),
+            ("500ms", Some(Duration::from_millis(500))),
+            ("30m", Some(Duration::from_secs(30 * 60))),
+            ("1h30m", Some(Duration::from_secs(90 * 60))),
+            ("1h 30m", Some(Duration::from_secs(90 * 60))),
+            ("2d", Some(Duration::from_secs(2 * 24 * 60 * 60))),
+            ("", None),
+            ("-1", None),
+            ("1.5h", None),
+            ("10x", None),
+            ("h", None),
+        ] {
+            let expected = expected.ok_or_else(|| Error::Duration(value.into()));
+            assert_eq!(parse_duration(value), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        for (value, expected) in [
+            ("1024", Some(1024)),
+            ("500MB", Some(500 * 1000 * 1000)),
+            ("500mb", Some(500 * 1000 * 1000)),
+            ("2GiB", Some(2 << 30)),
+            ("1.5G", Some(3 << 29)),
+            // Unlike in durations, `m` isn't minutes
+            ("30m", Some(30 << 20)),
+            ("", None),
+            ("-1", None),
+            ("10x", None),
+            ("1.5.1G", None),
+        ] {
+            let expected = expected.ok_or_else(|| Error::Size(value.into()));
+            assert_eq!(parse_size(value), expected, "{:?}", value);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! `AppAuth` (and the `Client` using it) against a stub of the Github API, which answers
+//! requests with fixed responses
+
+use ci_script::api::auth::{AppAuth, AuthProvider, Error};
+use ci_script::api::github::{self, Client, Github};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+/// Requests the stub received, as their request line (like `GET /app/installations HTTP/1.1`)
+/// and body
+type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+/// Serve the JSON responses of `routes` (request line prefixes like `GET /app/installations ` and
+/// response bodies) on a port of its own, and 404s to other requests
+fn stub_api(routes: &'static [(&'static str, &'static str)]) -> (url::Url, Requests) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let requests = Requests::default();
+    let received = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = BufReader::new(stream.unwrap());
+            let mut request_line = String::new();
+            stream.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut request_body = vec![0; content_length];
+            stream.read_exact(&mut request_body).unwrap();
+            let route = routes
+                .iter()
+                .find(|(prefix, _)| request_line.starts_with(prefix));
+            let (status, body) = match route {
+                Some((_, body)) => ("200 OK", *body),
+                None => ("404 Not Found", r#"{"message": "Not Found"}"#),
+            };
+            received.lock().unwrap().push((
+                request_line.trim().to_string(),
+                String::from_utf8(request_body).unwrap(),
+            ));
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.get_mut().write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (url.parse().unwrap(), requests)
+}
+
+/// A Github App with a freshly generated key
+fn app(api_url: url::Url) -> AppAuth {
+    let key = openssl::rsa::Rsa::generate(2048).unwrap();
+    let pem = String::from_utf8(key.private_key_to_pem().unwrap()).unwrap();
+    AppAuth::new(1, &pem, api_url).unwrap()
+}
+
+#[test]
+fn no_installations() {
+    let (api_url, requests) = stub_api(&[("GET /app/installations ", "[]")]);
+    let result = app(api_url).installations();
+    assert!(matches!(result, Err(Error::NoInstallations)), "{:?}", result);
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].0.starts_with("GET /app/installations "), "{:?}", requests[0]);
+}
+
+/// An installation of the app on `o/r`, and its token
+const TOKEN_ROUTES: &[(&str, &str)] = &[
+    (
+        "GET /repos/o/r/installation ",
+        r#"{
+            "id": 1,
+            "account": {
+                "login": "o", "id": 1, "node_id": "", "avatar_url": "http://localhost/",
+                "gravatar_id": "", "url": "http://localhost/", "html_url": "http://localhost/",
+                "followers_url": "http://localhost/", "following_url": "http://localhost/",
+                "gists_url": "http://localhost/", "starred_url": "http://localhost/",
+                "subscriptions_url": "http://localhost/",
+                "organizations_url": "http://localhost/", "repos_url": "http://localhost/",
+                "events_url": "http://localhost/", "received_events_url": "http://localhost/",
+                "type": "User", "site_admin": false
+            },
+            "access_tokens_url": "/app/installations/1/access_tokens",
+            "permissions": {},
+            "events": []
+        }"#,
+    ),
+    (
+        "POST /app/installations/1/access_tokens ",
+        r#"{"token": "token-of-r", "expires_at": "2999-01-01T00:00:00Z", "permissions": {}}"#,
+    ),
+];
+
+#[test]
+fn tokens_are_scoped_to_the_repository_and_cached() {
+    let (api_url, requests) = stub_api(TOKEN_ROUTES);
+    let app = app(api_url);
+    assert_eq!(app.token("o/r").unwrap(), "token-of-r");
+    assert_eq!(app.token("o/r").unwrap(), "token-of-r");
+    let requests = requests.lock().unwrap();
+    // The second token came from the cache
+    assert_eq!(requests.len(), 2, "{:?}", requests);
+    let (request_line, body) = &requests[1];
+    assert!(request_line.starts_with("POST /app/installations/1/access_tokens "));
+    let body: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(body["repositories"], serde_json::json!(["r"]));
+}
+
+#[test]
+fn file_contents_only_authenticates_when_asked_and_encodes_the_path() {
+    let (api_url, requests) = stub_api(TOKEN_ROUTES);
+    let client = Client::new(Arc::new(app(api_url)));
+    // The stub has no files, so they're not found, but the requests show what was asked for
+    let result = client.file_contents("o/other", "main", "a b/c?d#e.txt", false);
+    assert!(matches!(result, Err(github::Error::FileNotFound { .. })), "{:?}", result);
+    let result = client.file_contents("o/r", "main", "f.txt", true);
+    assert!(matches!(result, Err(github::Error::FileNotFound { .. })), "{:?}", result);
+    for path in ["../other/contents/f.txt", "a/./f.txt", "/f.txt", "a//f.txt"] {
+        let result = client.file_contents("o/r", "main", path, true);
+        assert!(matches!(result, Err(github::Error::InvalidPath(_))), "{:?}", result);
+    }
+
+    let requests: Vec<_> = requests.lock().unwrap().drain(..).map(|(line, _)| line).collect();
+    assert_eq!(
+        requests,
+        [
+            // Without a token for `o/other`
+            "GET /repos/o/other/contents/a%20b/c%3Fd%23e.txt?ref=main HTTP/1.1",
+            "GET /repos/o/r/installation HTTP/1.1",
+            "POST /app/installations/1/access_tokens HTTP/1.1",
+            "GET /repos/o/r/contents/f.txt?ref=main HTTP/1.1",
+        ]
+    );
+}
@@ -0,0 +1,328 @@
+//! Scripts run end-to-end against the `Mock` Github, with a bare repository in a temporary
+//! directory as the `origin` of the job's checkout
+
+use ci_script::api::cargo::Cargo;
+use ci_script::api::github::Mock;
+use ci_script::job::{CheckedoutJob, Error, Repository};
+use ci_script::repo_config::RepoConfig;
+use ci_script::Command;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Branch the fixture's repositories start on
+const BRANCH: &str = "main";
+
+struct Fixture {
+    _tmp: tempfile::TempDir,
+    /// Checkout the jobs run in, a clone of `origin`
+    dir: PathBuf,
+    /// The bare repository `o/r` of the `mock`
+    origin: PathBuf,
+    mock: Arc<Mock>,
+}
+
+impl Fixture {
+    /// A checkout with `files` (paths and contents) committed and pushed to its `origin`
+    fn new(files: &[(&str, &str)]) -> Self {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        let mut init = git2::RepositoryInitOptions::new();
+        init.initial_head(BRANCH);
+        let origin = root.join("github").join("o").join("r");
+        git2::Repository::init_opts(&origin, init.bare(true)).unwrap();
+        let dir = root.join("checkout");
+        let repo = git2::Repository::init_opts(&dir, init.bare(false)).unwrap();
+        commit(&repo, files, "Initial commit");
+        let url = url::Url::from_directory_path(&origin).unwrap();
+        repo.remote("origin", url.as_str())
+            .unwrap()
+            .push(&[format!("refs/heads/{BRANCH}")], None)
+            .unwrap();
+        let mock = Arc::new(Mock::new(root.join("github")).unwrap());
+        Fixture {
+            _tmp: tmp,
+            dir,
+            origin,
+            mock,
+        }
+    }
+
+    /// Run the script at `path` (relative to the checkout) as a job
+    fn run(&self, path: &str) -> Result<Option<String>, Error> {
+        self.run_job(self.job(path))
+    }
+
+    fn run_job(&self, job: CheckedoutJob) -> Result<Option<String>, Error> {
+        let engine = Arc::new(ci_script::job::build_engine().unwrap());
+        job.prepare_script(self.mock.clone(), engine)?.run()
+    }
+
+    /// A job running the script at `path` (relative to the checkout)
+    fn job(&self, path: &str) -> CheckedoutJob {
+        let user = serde_json::json!({
+            "login": "o", "id": 1, "node_id": "", "avatar_url": "http://localhost/",
+            "gravatar_id": "", "url": "http://localhost/", "html_url": "http://localhost/",
+            "followers_url": "http://localhost/", "following_url": "http://localhost/",
+            "gists_url": "http://localhost/", "starred_url": "http://localhost/",
+            "subscriptions_url": "http://localhost/", "organizations_url": "http://localhost/",
+            "repos_url": "http://localhost/", "events_url": "http://localhost/",
+            "received_events_url": "http://localhost/", "type": "User", "site_admin": false,
+        });
+        let url = url::Url::from_directory_path(&self.origin).unwrap();
+        let gh_repo: Repository = serde_json::from_value(serde_json::json!({
+            "id": 1, "name": "r", "url": url, "owner": user, "clone_url": url,
+        }))
+        .unwrap();
+        CheckedoutJob {
+            command: Command::from_script_path(path, vec![]).unwrap(),
+            dir: self.dir.clone(),
+            base_dir: None,
+            clone_dir: self.dir.with_file_name("clones"),
+            clone_limit: None,
+            gh_repo,
+            gh_issue: None,
+            gh_user: None,
+            head_sha: None,
+            pr_head: None,
+            signer: None,
+            store_dir: None,
+            data_dir: None,
+            cargo: Cargo::default(),
+            commit_template: None,
+            checkout_duration: None,
+            read_only: false,
+            // Like `cis`, from the checkout
+            repo_config: RepoConfig::load(&self.dir).unwrap(),
+        }
+    }
+}
+
+/// Write `files` (paths and contents) to the working tree of `repo` and commit them on HEAD
+fn commit(repo: &git2::Repository, files: &[(&str, &str)], message: &str) -> git2::Oid {
+    let dir = repo.workdir().unwrap();
+    for (path, contents) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+        .unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = git2::Signature::now("Test", "test@localhost").unwrap();
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents)
+        .unwrap()
+}
+
+#[test]
+fn cargo_in_script_function() {
+    let fixture = Fixture::new(&[(
+        ".github/test/version.rhai",
+        r#"
+            fn version() {
+                cargo "--version"
+            }
+            let result = version();
+            if !result.is_ok() {
+                throw result.stderr;
+            }
+        "#,
+    )]);
+    assert!(matches!(fixture.run(".github/test/version.rhai"), Ok(None)));
+}
+
+#[test]
+fn read_only_script() {
+    let fixture = Fixture::new(&[
+        (".github/test/write.rhai", r#"REPO.write_text("new.txt", "new");"#),
+        (".github/test/cargo.rhai", r#"cargo "--version";"#),
+    ]);
+    for script in ["write", "cargo"] {
+        let mut job = fixture.job(&format!(".github/test/{script}.rhai"));
+        job.read_only = true;
+        let err = fixture.run_job(job).unwrap_err().to_string();
+        assert!(err.contains("the script is read-only"), "{}: {}", script, err);
+    }
+    assert!(!fixture.dir.join("new.txt").exists());
+    assert!(matches!(fixture.run(".github/test/write.rhai"), Ok(None)));
+    assert!(fixture.dir.join("new.txt").exists());
+}
+
+#[test]
+fn script_timeout_stops_script() {
+    let fixture = Fixture::new(&[
+        (".github/bbot.toml", "timeout = 1"),
+        (".github/test/loop.rhai", "loop {}"),
+    ]);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let engine = Arc::new(ci_script::job::build_engine().unwrap());
+    let result = fixture
+        .job(".github/test/loop.rhai")
+        .prepare_script(fixture.mock.clone(), engine)
+        .unwrap()
+        .cancel_token(cancelled.clone())
+        .run();
+    assert!(matches!(result, Err(Error::ScriptTimeout(_))), "{:?}", result);
+    // Timing out isn't being cancelled by the bot
+    assert!(!cancelled.load(Ordering::SeqCst));
+}
+
+#[test]
+fn script_dirs_allowlist() {
+    let fixture = Fixture::new(&[
+        (".github/bbot.toml", r#"script_dirs = [".github/allowed"]"#),
+        (".github/allowed/main.rhai", ""),
+        (".github/other/main.rhai", ""),
+    ]);
+    assert!(matches!(fixture.run(".github/allowed/main.rhai"), Ok(None)));
+    let result = fixture.run(".github/other/main.rhai");
+    assert!(matches!(result, Err(Error::ScriptDirNotAllowed(_))), "{:?}", result);
+}
+
+#[test]
+fn push_and_create_pr() {
+    let fixture = Fixture::new(&[(
+        ".github/test/update.rhai",
+        r#"
+            REPO.branch("update");
+            REPO.write_text("new.txt", "new");
+            REPO.add("new.txt");
+            REPO.commit("Add new.txt");
+            REPO.push("update");
+            REPO.create_pr("Update", "Adds new.txt", "update", "main");
+        "#,
+    )]);
+    assert!(matches!(fixture.run(".github/test/update.rhai"), Ok(None)));
+    let origin = git2::Repository::open_bare(&fixture.origin).unwrap();
+    let pushed = origin.revparse_single("update:new.txt").unwrap();
+    assert_eq!(pushed.as_blob().unwrap().content(), b"new");
+    let pull_requests = fixture.mock.pull_requests();
+    assert_eq!(pull_requests.len(), 1);
+    let (repo, pr) = &pull_requests[0];
+    assert_eq!(repo, "o/r");
+    assert_eq!(
+        (pr.title.as_str(), pr.head.as_str(), pr.base.as_str()),
+        ("Update", "update", BRANCH)
+    );
+}
+
+#[test]
+fn push_verified_advances_remote_ref() {
+    let fixture = Fixture::new(&[(
+        ".github/test/push.rhai",
+        r#"
+            REPO.write_text("new.txt", "new");
+            REPO.add("new.txt");
+            REPO.commit("Add new.txt");
+            REPO.push_verified("main");
+        "#,
+    )]);
+    let origin = git2::Repository::open_bare(&fixture.origin).unwrap();
+    let branch = format!("refs/heads/{}", BRANCH);
+    let before = origin.refname_to_id(&branch).unwrap();
+    assert!(matches!(fixture.run(".github/test/push.rhai"), Ok(None)));
+    let after = origin.refname_to_id(&branch).unwrap();
+    let checkout = git2::Repository::open(&fixture.dir).unwrap();
+    assert_ne!(after, before);
+    assert_eq!(after, checkout.refname_to_id(&branch).unwrap());
+}
+
+#[test]
+fn status_paths_are_root_relative() {
+    let fixture = Fixture::new(&[
+        ("sub/a.txt", "a"),
+        (
+            ".github/test/paths.rhai",
+            r#"
+                REPO.write_text("sub/a.txt", "changed");
+                REPO.write_text("sub/b.txt", "new");
+                let status = REPO.status();
+                let paths = "";
+                for path in status.changed() {
+                    paths += "changed " + path.to_string() + "\n";
+                }
+                for path in status.added() {
+                    REPO.add(path);
+                    paths += "added " + path.to_string() + "\n";
+                }
+                for entry in REPO.ls("sub") {
+                    paths += "ls " + entry.path.to_string() + "\n";
+                }
+                finish(paths);
+            "#,
+        ),
+    ]);
+    let paths = fixture.run(".github/test/paths.rhai").unwrap().unwrap();
+    let mut paths: Vec<_> = paths.lines().collect();
+    paths.sort_unstable();
+    assert_eq!(
+        paths,
+        ["added sub/b.txt", "changed sub/a.txt", "ls sub/a.txt", "ls sub/b.txt"]
+    );
+    let checkout = git2::Repository::open(&fixture.dir).unwrap();
+    let status = checkout.status_file(std::path::Path::new("sub/b.txt")).unwrap();
+    assert!(status.is_index_new(), "{:?}", status);
+}
+
+#[cfg(unix)]
+#[test]
+fn script_outside_command_dir() {
+    let fixture = Fixture::new(&[
+        (".github/bbot.toml", r#"script_dirs = [".github/test"]"#),
+        (".github/other/main.rhai", ""),
+    ]);
+    let link = fixture.dir.join(".github/test/main.rhai");
+    std::fs::create_dir_all(link.parent().unwrap()).unwrap();
+    std::os::unix::fs::symlink("../other/main.rhai", &link).unwrap();
+    let result = fixture.run(".github/test/main.rhai");
+    assert!(matches!(result, Err(Error::ScriptOutsideCommandDir(_))), "{:?}", result);
+}
+
+#[test]
+fn checkout_ms_is_always_defined() {
+    let fixture = Fixture::new(&[
+        (
+            ".github/test/unknown.rhai",
+            r#"if CHECKOUT_MS != () { throw `CHECKOUT_MS is ${CHECKOUT_MS}`; }"#,
+        ),
+        (
+            ".github/test/known.rhai",
+            r#"if type_of(CHECKOUT_MS) != "i64" { throw `CHECKOUT_MS is ${CHECKOUT_MS}`; }"#,
+        ),
+    ]);
+    for (script, duration) in [("unknown", None), ("known", Some(1500))] {
+        let mut job = fixture.job(&format!(".github/test/{script}.rhai"));
+        job.checkout_duration = duration.map(std::time::Duration::from_millis);
+        let result = fixture.run_job(job);
+        assert!(matches!(result, Ok(None)), "{}: {:?}", script, result);
+    }
+}
+
+#[test]
+fn fetch_file_paths() {
+    let fixture = Fixture::new(&[
+        ("data/a b#1.txt", "hello"),
+        (
+            ".github/test/fetch.rhai",
+            r#"
+                let blob = Git.fetch_file("o/r", "main", "data/a b#1.txt");
+                if blob.len() != 5 {
+                    throw `fetched ${blob.len()} bytes`;
+                }
+            "#,
+        ),
+        (
+            ".github/test/escape.rhai",
+            r#"Git.fetch_file("o/r", "main", "data/../data/a b#1.txt");"#,
+        ),
+    ]);
+    let result = fixture.run(".github/test/fetch.rhai");
+    assert!(matches!(result, Ok(None)), "{:?}", result);
+    let err = fixture.run(".github/test/escape.rhai").unwrap_err().to_string();
+    assert!(err.contains("Invalid file path"), "{}", err);
+}